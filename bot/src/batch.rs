@@ -0,0 +1,166 @@
+use teloxide::utils::markdown::{escape, link};
+
+/// State of a job in a batch.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum BatchState {
+    Queued,
+    Syncing,
+    /// Synced to the telegraph url.
+    Finished(String),
+    Failed,
+    Cancelled,
+    /// The queue is full.
+    Rejected,
+}
+
+impl BatchState {
+    pub fn is_pending(&self) -> bool {
+        matches!(self, BatchState::Queued | BatchState::Syncing)
+    }
+}
+
+#[derive(Debug)]
+struct BatchEntry {
+    // None if the job is rejected
+    id: Option<u64>,
+    url: String,
+    state: BatchState,
+}
+
+/// Jobs of urls from one message, which share a status message.
+#[derive(Debug, Default)]
+pub struct Batch {
+    entries: Vec<BatchEntry>,
+    // urls beyond the limit of a message
+    skipped: usize,
+}
+
+impl Batch {
+    pub fn new(skipped: usize) -> Self {
+        Self {
+            entries: Vec::new(),
+            skipped,
+        }
+    }
+
+    pub fn add(&mut self, id: Option<u64>, url: String, state: BatchState) {
+        self.entries.push(BatchEntry { id, url, state });
+    }
+
+    /// Returns false if the job is not in the batch.
+    pub fn update(&mut self, id: u64, state: BatchState) -> bool {
+        match self.entries.iter_mut().find(|e| e.id == Some(id)) {
+            Some(entry) => {
+                entry.state = state;
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Ids of queued or running jobs.
+    pub fn pending(&self) -> impl Iterator<Item = u64> + '_ {
+        self.entries
+            .iter()
+            .filter(|e| e.state.is_pending())
+            .filter_map(|e| e.id)
+    }
+
+    pub fn is_done(&self) -> bool {
+        self.pending().next().is_none()
+    }
+
+    /// Status text in MarkdownV2.
+    pub fn render(&self) -> String {
+        let finished = self
+            .entries
+            .iter()
+            .filter(|e| matches!(e.state, BatchState::Finished(_)))
+            .count();
+        let unsynced = self
+            .entries
+            .iter()
+            .filter(|e| !e.state.is_pending())
+            .count()
+            - finished;
+        let mut header = format!(
+            "{} {} urls, {finished} finished",
+            if self.is_done() { "Synced" } else { "Syncing" },
+            self.entries.len()
+        );
+        if unsynced > 0 {
+            header.push_str(&format!(", {unsynced} not synced"));
+        }
+
+        let mut lines = vec![escape(&header)];
+        for (i, entry) in self.entries.iter().enumerate() {
+            let state = match &entry.state {
+                BatchState::Finished(url) => link(url, "finished"),
+                BatchState::Queued => escape("queued"),
+                BatchState::Syncing => escape("syncing"),
+                BatchState::Failed => escape("failed"),
+                BatchState::Cancelled => escape("cancelled"),
+                BatchState::Rejected => escape("too many sync requests"),
+            };
+            lines.push(format!(
+                "{}{state}",
+                escape(&format!("{}. {}: ", i + 1, entry.url))
+            ));
+        }
+        if self.skipped > 0 {
+            lines.push(escape(&format!(
+                "{} more urls are ignored, at most {} urls are synced per message.",
+                self.skipped,
+                self.entries.len()
+            )));
+        }
+        lines.join("\n")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Batch, BatchState};
+
+    #[test]
+    fn update_batch() {
+        let mut batch = Batch::new(2);
+        batch.add(
+            Some(1),
+            "https://e-hentai.org/g/1/a".to_string(),
+            BatchState::Queued,
+        );
+        batch.add(
+            Some(2),
+            "https://nhentai.net/g/2".to_string(),
+            BatchState::Queued,
+        );
+        batch.add(
+            None,
+            "https://nhentai.net/g/3".to_string(),
+            BatchState::Rejected,
+        );
+        assert_eq!(batch.pending().collect::<Vec<_>>(), [1, 2]);
+        assert_eq!(
+            batch.render().lines().next().unwrap(),
+            r"Syncing 3 urls, 0 finished, 1 not synced"
+        );
+
+        let finished = BatchState::Finished("https://telegra.ph/A-01-01".to_string());
+        assert!(batch.update(1, finished));
+        assert!(!batch.update(3, BatchState::Failed));
+        assert_eq!(batch.pending().collect::<Vec<_>>(), [2]);
+        assert!(!batch.is_done());
+        assert!(batch.update(2, BatchState::Cancelled));
+        assert!(batch.is_done());
+
+        let expected = [
+            r"Synced 3 urls, 1 finished, 2 not synced",
+            r"1\. https://e\-hentai\.org/g/1/a: [finished](https://telegra.ph/A-01-01)",
+            r"2\. https://nhentai\.net/g/2: cancelled",
+            r"3\. https://nhentai\.net/g/3: too many sync requests",
+            r"2 more urls are ignored, at most 3 urls are synced per message\.",
+        ];
+        assert_eq!(batch.render(), expected.join("\n"));
+    }
+}
@@ -1,31 +1,64 @@
-use std::{borrow::Cow, collections::HashSet};
+use std::{
+    collections::{HashMap, HashSet},
+    sync::{Arc, Mutex},
+    time::Duration,
+};
 
 use eh2telegraph::{
+    circuit_breaker,
     collector::{e_hentai::EHCollector, exhentai::EXCollector, nhentai::NHCollector},
+    indexer::{e_hentai::EHIndexer, Filter, Indexer},
+    queue::{Job, JobQueue, JobStore, QueueConfig, QueueFull},
     searcher::{
         f_hash::FHashConvertor,
         saucenao::{SaucenaoOutput, SaucenaoParsed, SaucenaoSearcher},
         ImageSearcher,
     },
     storage::KVStorage,
-    sync::Synchronizer,
+    sync::{SyncCancelled, SyncOptions, SyncProgress, SyncRecord, Synchronizer},
 };
 
 use reqwest::Url;
 use teloxide::{
     adaptors::DefaultParseMode,
     prelude::*,
+    types::{
+        InlineKeyboardButton, InlineKeyboardMarkup, InlineQueryResult, InlineQueryResultArticle,
+        InputFile, InputMessageContent, InputMessageContentText, MessageEntity, MessageEntityKind,
+        MessageId, UserId,
+    },
     utils::{
         command::BotCommands,
         markdown::{code_inline, escape, link},
     },
 };
+use tokio::sync::watch;
+use tokio_util::sync::CancellationToken;
 use tracing::{info, trace};
 
-use crate::{ok_or_break, util::PrettyChat};
+use crate::{
+    batch::{Batch, BatchState},
+    ok_or_break,
+    progress::ProgressText,
+    result::{self, ResultConfig},
+    shared::SharedRuns,
+    util::PrettyChat,
+};
 
 const MIN_SIMILARITY: u8 = 70;
 const MIN_SIMILARITY_PRIVATE: u8 = 50;
+const PROGRESS_INTERVAL: Duration = Duration::from_secs(5);
+const CANCEL_PREFIX: &str = "cancel:";
+const CANCEL_BATCH_DATA: &str = "cancel_batch";
+const SYNC_PREFIX: &str = "sync:";
+const RESYNC_PREFIX: &str = "resync:";
+const REPORT_PREFIX: &str = "report:";
+const SIMILAR_PREFIX: &str = "similar:";
+const SIMILAR_TAGS: usize = 3;
+const SIMILAR_RESULTS: usize = 5;
+const MAX_URLS_PER_MESSAGE: usize = 10;
+// limit of telegram callback data
+const MAX_CALLBACK_DATA_LEN: usize = 64;
 
 #[derive(BotCommands, Clone)]
 #[command(
@@ -58,6 +91,75 @@ pub enum Command {
 pub enum AdminCommand {
     #[command(description = "Delete cache with given key.")]
     Delete(String),
+    #[command(description = "Show circuit breaker status of sites.")]
+    Status,
+}
+
+/// A queued sync request, the status message is edited with the result.
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+pub struct SyncJob {
+    pub url: String,
+    #[serde(flatten)]
+    pub status: StatusMessage,
+    /// User who requested the sync.
+    #[serde(default)]
+    pub user: Option<u64>,
+    /// Message to reply with the rich result.
+    #[serde(default)]
+    pub request: Option<MessageId>,
+    /// The job is in a batch, whose status message is shared.
+    #[serde(default)]
+    pub batched: bool,
+}
+
+/// Message to show the sync status.
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+#[serde(untagged)]
+pub enum StatusMessage {
+    Chat {
+        chat: ChatId,
+        message: MessageId,
+    },
+    /// Message sent via the bot in inline mode.
+    Inline {
+        inline_message_id: String,
+    },
+}
+
+impl StatusMessage {
+    /// Edit the message, the inline keyboard is removed if markup is None.
+    async fn edit(
+        &self,
+        bot: &DefaultParseMode<Bot>,
+        text: String,
+        markup: Option<InlineKeyboardMarkup>,
+    ) {
+        let result = match self {
+            StatusMessage::Chat { chat, message } => {
+                let mut req = bot.edit_message_text(*chat, *message, text);
+                req.reply_markup = markup;
+                req.await.map(|_| ())
+            }
+            StatusMessage::Inline { inline_message_id } => {
+                let mut req = bot.edit_message_text_inline(inline_message_id, text);
+                req.reply_markup = markup;
+                req.await.map(|_| ())
+            }
+        };
+        if let Err(e) = result {
+            trace!("[status] unable to edit status message: {e}");
+        }
+    }
+}
+
+// Status message of a batch.
+type BatchKey = (ChatId, MessageId);
+
+// Used to cancel a queued or running job.
+#[derive(Debug, Clone)]
+struct JobControl {
+    user: Option<u64>,
+    token: CancellationToken,
 }
 
 pub struct Handler<C> {
@@ -65,22 +167,641 @@ pub struct Handler<C> {
     pub searcher: SaucenaoSearcher,
     pub convertor: FHashConvertor,
     pub admins: HashSet<i64>,
+    pub queue: JobQueue<SyncJob>,
 
-    single_flight: singleflight_async::SingleFlight<String>,
+    store: Option<JobStore>,
+    controls: Mutex<HashMap<u64, JobControl>>,
+    // the batch is locked when editing its status message, so the latest state is shown
+    batches: Mutex<HashMap<BatchKey, Arc<tokio::sync::Mutex<Batch>>>>,
+    workers: usize,
+    indexer: EHIndexer,
+    result_config: ResultConfig,
+    // reported urls, admins are notified only once for each
+    reported: Mutex<HashSet<String>>,
+    // the error is formatted as response text
+    syncs: SharedRuns<Result<SyncRecord, String>>,
 }
 
 impl<C> Handler<C>
 where
-    C: KVStorage<String> + Send + Sync + 'static,
+    C: KVStorage<SyncRecord> + Send + Sync + 'static,
 {
     pub fn new(synchronizer: Synchronizer<C>, admins: HashSet<i64>) -> Self {
+        let queue_config = QueueConfig::from_config().expect("unable to parse queue config");
         Self {
             synchronizer,
             searcher: SaucenaoSearcher::new_from_config(),
             convertor: FHashConvertor::new_from_config(),
             admins,
+            queue: JobQueue::new(queue_config.max_len),
+
+            store: JobStore::new_from_config().expect("unable to open job store"),
+            controls: Default::default(),
+            batches: Default::default(),
+            workers: queue_config.workers.max(1),
+            indexer: EHIndexer::new_from_config().expect("unable to build e-hentai indexer"),
+            result_config: ResultConfig::from_config().expect("unable to parse result config"),
+            reported: Default::default(),
+            syncs: Default::default(),
+        }
+    }
+
+    /// Restore persisted jobs, and spawn workers to run queued sync jobs.
+    pub async fn start_workers(&'static self, bot: DefaultParseMode<Bot>) {
+        if let Some(store) = &self.store {
+            match store.load::<SyncJob>().await {
+                Ok(jobs) => {
+                    info!("[queue] restored {} jobs", jobs.len());
+                    for job in jobs {
+                        self.add_control(job.id, job.data.user);
+                        if job.data.batched {
+                            self.restore_batch(job.id, &job.data);
+                        }
+                        self.queue.restore(job);
+                    }
+                }
+                Err(e) => tracing::error!("[queue] unable to restore jobs: {e:?}"),
+            }
+        }
+        for _ in 0..self.workers {
+            let bot = bot.clone();
+            tokio::spawn(async move {
+                loop {
+                    let Job { id, data: job, .. } = self.queue.next().await;
+                    let token = self
+                        .controls
+                        .lock()
+                        .unwrap()
+                        .get(&id)
+                        .map(|c| c.token.clone())
+                        .unwrap_or_default();
+                    if job.batched {
+                        self.update_batch(&bot, &job, id, BatchState::Syncing).await;
+                        let opts = SyncOptions {
+                            progress: None,
+                            cancel: Some(&token),
+                        };
+                        let state = match self.sync_response(&job.url, opts).await {
+                            Ok(record) => BatchState::Finished(record.url),
+                            Err(_) if token.is_cancelled() => BatchState::Cancelled,
+                            Err(_) => BatchState::Failed,
+                        };
+                        self.update_batch(&bot, &job, id, state).await;
+                        self.finish_job(id).await;
+                        continue;
+                    }
+
+                    job.status
+                        .edit(
+                            &bot,
+                            escape(&format!("Syncing url {}", job.url)),
+                            Some(cancel_markup(id)),
+                        )
+                        .await;
+                    let (tx, rx) = watch::channel(SyncProgress::default());
+                    let opts = SyncOptions {
+                        progress: Some(&tx),
+                        cancel: Some(&token),
+                    };
+                    let result = tokio::select! {
+                        r = self.sync_response(&job.url, opts) => r,
+                        _ = Self::report_progress(&bot, id, &job, rx) => unreachable!("progress reporting never ends"),
+                    };
+                    // replace the cancel button with result actions
+                    match result {
+                        Ok(record) => {
+                            let markup = result_markup(&record, &job);
+                            if !self
+                                .send_rich_result(&bot, &record, &job, markup.clone())
+                                .await
+                            {
+                                job.status
+                                    .edit(&bot, finished_text(&record), Some(markup))
+                                    .await;
+                            }
+                        }
+                        Err(text) => job.status.edit(&bot, text, None).await,
+                    }
+                    self.finish_job(id).await;
+                }
+            });
+        }
+    }
+
+    /// Update the state of a batched job, and show it in the shared status message.
+    async fn update_batch(
+        &self,
+        bot: &DefaultParseMode<Bot>,
+        job: &SyncJob,
+        id: u64,
+        state: BatchState,
+    ) {
+        let StatusMessage::Chat { chat, message } = job.status else {
+            return;
+        };
+        let batch = self.batches.lock().unwrap().get(&(chat, message)).cloned();
+        let Some(batch) = batch else {
+            return;
+        };
+        let mut batch = batch.lock().await;
+        if batch.update(id, state) {
+            self.show_batch(bot, (chat, message), &batch).await;
+        }
+    }
+
+    async fn show_batch(&self, bot: &DefaultParseMode<Bot>, key: BatchKey, batch: &Batch) {
+        let status = StatusMessage::Chat {
+            chat: key.0,
+            message: key.1,
+        };
+        let markup = (!batch.is_done()).then(|| {
+            InlineKeyboardMarkup::new([[InlineKeyboardButton::callback(
+                "Cancel all",
+                CANCEL_BATCH_DATA,
+            )]])
+        });
+        status.edit(bot, batch.render(), markup).await;
+        if batch.is_done() {
+            self.batches.lock().unwrap().remove(&key);
+        }
+    }
+
+    // Finished jobs are not persisted, so only the pending ones are shown after restart.
+    fn restore_batch(&self, id: u64, job: &SyncJob) {
+        let StatusMessage::Chat { chat, message } = job.status else {
+            return;
+        };
+        let batch = self
+            .batches
+            .lock()
+            .unwrap()
+            .entry((chat, message))
+            .or_default()
+            .clone();
+        batch
+            .try_lock()
+            .expect("batch is locked before workers start")
+            .add(Some(id), job.url.clone(), BatchState::Queued);
+    }
+
+    /// Send the cover with album info if enabled for the chat, and delete the status message.
+    /// Returns false if not sent, then the status message should show the result.
+    async fn send_rich_result(
+        &self,
+        bot: &DefaultParseMode<Bot>,
+        record: &SyncRecord,
+        job: &SyncJob,
+        markup: InlineKeyboardMarkup,
+    ) -> bool {
+        // inline messages can not be replaced with a photo
+        let StatusMessage::Chat { chat, message } = job.status else {
+            return false;
+        };
+        let Some(spoiler) = self.result_config.rich_spoiler(chat.0) else {
+            return false;
+        };
+        let Some(cover) = record.cover.as_deref().and_then(|c| Url::parse(c).ok()) else {
+            return false;
+        };
+        let mut req = bot
+            .send_photo(chat, InputFile::url(cover))
+            .caption(result::caption(record, &job.url))
+            .has_spoiler(spoiler)
+            .reply_markup(markup);
+        if let Some(request) = job.request {
+            req = req
+                .reply_to_message_id(request)
+                .allow_sending_without_reply(true);
+        }
+        if let Err(e) = req.await {
+            info!("[result] unable to send cover of {}: {e}", job.url);
+            return false;
+        }
+        if let Err(e) = bot.delete_message(chat, message).await {
+            trace!("[result] unable to delete status message: {e}");
+        }
+        true
+    }
+
+    /// Edit the status message with the latest progress periodically.
+    async fn report_progress(
+        bot: &DefaultParseMode<Bot>,
+        id: u64,
+        job: &SyncJob,
+        mut rx: watch::Receiver<SyncProgress>,
+    ) {
+        let mut text = ProgressText::new(job.url.clone());
+        loop {
+            tokio::time::sleep(PROGRESS_INTERVAL).await;
+            let progress = *rx.borrow_and_update();
+            if let Some(text) = text.render(progress) {
+                job.status
+                    .edit(bot, escape(&text), Some(cancel_markup(id)))
+                    .await;
+            }
+        }
+    }
+
+    fn add_control(&self, id: u64, user: Option<u64>) {
+        self.controls.lock().unwrap().insert(
+            id,
+            JobControl {
+                user,
+                token: CancellationToken::new(),
+            },
+        );
+    }
+
+    /// Forget a finished or cancelled job.
+    async fn finish_job(&self, id: u64) {
+        self.controls.lock().unwrap().remove(&id);
+        if let Some(store) = &self.store {
+            if let Err(e) = store.remove(id).await {
+                tracing::error!("[queue] unable to remove job {id}: {e:?}");
+            }
+        }
+    }
+
+    /// Cancel a job, only the requester or admins are allowed.
+    /// Returns the text to answer.
+    async fn cancel_job(&self, bot: &DefaultParseMode<Bot>, id: u64, user: UserId) -> &'static str {
+        let control = self.controls.lock().unwrap().get(&id).cloned();
+        let control = match control {
+            Some(c) if c.user == Some(user.0) || self.admins.contains(&(user.0 as i64)) => c,
+            Some(_) => return "Only the requester or admins can cancel it.",
+            None => return "The sync is already finished.",
+        };
+        info!("[queue] job {id} is cancelled by user {user}");
+        control.token.cancel();
+        // the job is not started yet, so no worker will finish it
+        if let Some(job) = self.queue.remove(id) {
+            self.finish_job(id).await;
+            let job = job.data;
+            if job.batched {
+                self.update_batch(bot, &job, id, BatchState::Cancelled)
+                    .await;
+            } else {
+                job.status
+                    .edit(bot, escape(&format!("Sync cancelled: {}", job.url)), None)
+                    .await;
+            }
+        }
+        "Sync cancelled."
+    }
+
+    /// Cancel pending jobs of the batch shown in the pressed message.
+    async fn cancel_batch(
+        &self,
+        bot: &DefaultParseMode<Bot>,
+        query: &CallbackQuery,
+    ) -> &'static str {
+        let Some(message) = &query.message else {
+            return "Invalid cancel request.";
+        };
+        let batch = self
+            .batches
+            .lock()
+            .unwrap()
+            .get(&(message.chat.id, message.id))
+            .cloned();
+        let ids = match batch {
+            Some(batch) => batch.lock().await.pending().collect::<Vec<_>>(),
+            None => Vec::new(),
+        };
+        let mut text = "The sync is already finished.";
+        for id in ids {
+            text = self.cancel_job(bot, id, query.from.id).await;
+        }
+        text
+    }
+
+    /// Sync the url in callback data, the pressed message shows the status.
+    /// If resync is set, the cache is purged first and only admins are allowed.
+    async fn sync_callback(
+        &self,
+        bot: &DefaultParseMode<Bot>,
+        target: &str,
+        query: &CallbackQuery,
+        resync: bool,
+    ) -> &'static str {
+        let user = query.from.id.0;
+        let is_admin = self.admins.contains(&(user as i64));
+        if resync && !is_admin {
+            return "Only admins can re-sync.";
+        }
+        let (url, mut status) = match (callback_url(target), callback_status(query)) {
+            (Some(url), Some(status)) => (url, status),
+            _ => return "Invalid sync request.",
+        };
+        info!(
+            "[callback handler] receive {} request from user {user} for {url}",
+            if resync { "re-sync" } else { "sync" }
+        );
+        if resync {
+            if let Err(e) = self.route_delete_cached(&url).await {
+                tracing::error!("[callback handler] unable to purge cache of {url}: {e:?}");
+                return "Unable to purge the cache, please retry later.";
+            }
+            self.reported.lock().unwrap().remove(&url);
+        }
+        // a rich result can not be edited to text, so the status is sent as a reply
+        let mut request = None;
+        if let Some(message) = query.message.as_ref().filter(|m| m.photo().is_some()) {
+            let sent = bot
+                .send_message(message.chat.id, escape(&format!("Syncing url {url}")))
+                .reply_to_message_id(message.id)
+                .await;
+            let Ok(sent) = sent else {
+                return "Unable to send the status message, please retry later.";
+            };
+            status = StatusMessage::Chat {
+                chat: sent.chat.id,
+                message: sent.id,
+            };
+            request = Some(message.id);
+        }
+        let owner = match &status {
+            StatusMessage::Chat { chat, .. } => chat.0,
+            StatusMessage::Inline { .. } => user as i64,
+        };
+        let job = SyncJob {
+            url,
+            status,
+            user: Some(user),
+            request,
+            batched: false,
+        };
+        self.push_job(bot, owner, is_admin, job).await;
+        "Sync started."
+    }
+
+    /// Notify admins that a synced gallery is broken.
+    async fn report_broken(
+        &self,
+        bot: &DefaultParseMode<Bot>,
+        target: &str,
+        user: UserId,
+    ) -> &'static str {
+        let url = match callback_url(target) {
+            Some(url) => url,
+            None => return "Invalid report.",
+        };
+        if !self.reported.lock().unwrap().insert(url.clone()) {
+            return "It is already reported, thanks.";
+        }
+        info!("[callback handler] user {user} reported broken sync of {url}");
+        let synced = match self.route_cached(&url).await {
+            Ok(Some(record)) => record.url,
+            _ => "not cached".to_string(),
+        };
+        let text = format!("User {user} reported broken sync of {url} ({synced})");
+        for admin in self.admins.iter() {
+            let _ = bot.send_message(ChatId(*admin), escape(&text)).await;
+        }
+        "Reported, thanks."
+    }
+
+    /// Search galleries with the same tags, and reply with the results.
+    async fn more_like_this(
+        &self,
+        bot: &DefaultParseMode<Bot>,
+        target: &str,
+        query: &CallbackQuery,
+    ) -> &'static str {
+        // results can not be sent for messages in inline mode
+        let (url, message) = match (callback_url(target), &query.message) {
+            (Some(url), Some(message)) => (url, message),
+            _ => return "Invalid search request.",
+        };
+        let tags = match self.route_cached(&url).await {
+            Ok(Some(record)) => record.tags,
+            _ => Vec::new(),
+        };
+        let filters = tags
+            .into_iter()
+            .filter(|tag| result::is_key_tag(tag))
+            .take(SIMILAR_TAGS)
+            .map(Filter::Tag)
+            .collect::<Vec<_>>();
+        if filters.is_empty() {
+            return "No tags to search.";
+        }
+
+        let entries = match self.indexer.index(&filters).await {
+            Ok(entries) => entries,
+            Err(e) => {
+                tracing::error!("[callback handler] search similar of {url} failed: {e:?}");
+                return "Search failed, please retry later.";
+            }
+        };
+        let gallery = url
+            .trim_start_matches("https://")
+            .replace("exhentai", "e-hentai");
+        let lines = entries
+            .iter()
+            .filter(|e| !e.link.trim_end_matches('/').ends_with(&gallery))
+            .take(SIMILAR_RESULTS)
+            .map(|e| link(&e.link, &escape(&e.title)))
+            .collect::<Vec<_>>();
+        if lines.is_empty() {
+            return "No similar galleries found.";
+        }
+        let _ = bot
+            .send_message(
+                message.chat.id,
+                format!("{}\n{}", escape("More like this:"), lines.join("\n")),
+            )
+            .reply_to_message_id(message.id)
+            .disable_web_page_preview(true)
+            .await;
+        "Search finished."
+    }
+
+    /// Executed when an inline button is pressed.
+    pub async fn respond_callback(
+        &'static self,
+        bot: DefaultParseMode<Bot>,
+        query: CallbackQuery,
+    ) -> ControlFlow<()> {
+        let data = query.data.as_deref().unwrap_or_default();
+        let text = if data == CANCEL_BATCH_DATA {
+            self.cancel_batch(&bot, &query).await
+        } else if let Some(id) = data.strip_prefix(CANCEL_PREFIX) {
+            match id.parse() {
+                Ok(id) => self.cancel_job(&bot, id, query.from.id).await,
+                Err(_) => return ControlFlow::Continue(()),
+            }
+        } else if let Some(target) = data.strip_prefix(SYNC_PREFIX) {
+            self.sync_callback(&bot, target, &query, false).await
+        } else if let Some(target) = data.strip_prefix(RESYNC_PREFIX) {
+            self.sync_callback(&bot, target, &query, true).await
+        } else if let Some(target) = data.strip_prefix(REPORT_PREFIX) {
+            self.report_broken(&bot, target, query.from.id).await
+        } else if let Some(target) = data.strip_prefix(SIMILAR_PREFIX) {
+            self.more_like_this(&bot, target, &query).await
+        } else {
+            return ControlFlow::Continue(());
+        };
+        let _ = bot.answer_callback_query(query.id).text(text).await;
+        ControlFlow::Break(())
+    }
+
+    /// Executed when an inline query comes in. Only the cache is looked up,
+    /// if the gallery is not synced, the result offers a button to sync it.
+    pub async fn respond_inline(
+        &'static self,
+        bot: DefaultParseMode<Bot>,
+        query: InlineQuery,
+    ) -> ControlFlow<()> {
+        let url = match Synchronizer::match_url_from_text(&query.query) {
+            Some(url) => url.to_string(),
+            None => {
+                let _ = bot
+                    .answer_inline_query(query.id, Vec::<InlineQueryResult>::new())
+                    .await;
+                return ControlFlow::Break(());
+            }
+        };
+        trace!(
+            "[inline handler] receive inline query from user {} for {url}",
+            query.from.id
+        );
+
+        let request = match self.route_cached(&url).await {
+            Ok(Some(record)) => bot.answer_inline_query(query.id, [synced_article(&record)]),
+            Ok(None) => bot
+                .answer_inline_query(query.id, [unsynced_article(&url)])
+                // the result changes once synced
+                .cache_time(0),
+            Err(e) => {
+                info!("[inline handler] unable to look up cache for {url}: {e}");
+                bot.answer_inline_query(query.id, [unsynced_article(&url)])
+                    .cache_time(0)
+            }
+        };
+        let _ = request.await;
+        ControlFlow::Break(())
+    }
+
+    /// Queue a sync job, `status` is the message to show the progress.
+    /// Admin chats have priority.
+    async fn enqueue(&self, bot: &DefaultParseMode<Bot>, status: &Message, url: String) {
+        let chat = status.chat.id;
+        // status message is a reply to the request
+        let request = status.reply_to_message();
+        let user = request.and_then(|m| m.from()).map(|u| u.id.0);
+        let priority = self.admins.contains(&chat.0)
+            || user.is_some_and(|u| self.admins.contains(&(u as i64)));
+        let job = SyncJob {
+            url,
+            status: StatusMessage::Chat {
+                chat,
+                message: status.id,
+            },
+            user,
+            request: request.map(|m| m.id),
+            batched: false,
+        };
+        self.push_job(bot, chat.0, priority, job).await;
+    }
+
+    /// Queue sync jobs of urls from one message, `status` is the message to show
+    /// states of all jobs.
+    async fn enqueue_batch(
+        &self,
+        bot: &DefaultParseMode<Bot>,
+        status: &Message,
+        urls: Vec<String>,
+        skipped: usize,
+    ) {
+        let chat = status.chat.id;
+        let request = status.reply_to_message();
+        let user = request.and_then(|m| m.from()).map(|u| u.id.0);
+        let priority = self.admins.contains(&chat.0)
+            || user.is_some_and(|u| self.admins.contains(&(u as i64)));
+        let key = (chat, status.id);
+        let batch = Arc::new(tokio::sync::Mutex::new(Batch::new(skipped)));
+        self.batches.lock().unwrap().insert(key, batch.clone());
+        // workers wait for the lock, so they always find their jobs in the batch
+        let mut batch = batch.lock().await;
+        for url in urls {
+            let job = SyncJob {
+                url: url.clone(),
+                status: StatusMessage::Chat {
+                    chat,
+                    message: status.id,
+                },
+                user,
+                request: request.map(|m| m.id),
+                batched: true,
+            };
+            match self.try_push(chat.0, priority, job).await {
+                Ok((id, _)) => batch.add(Some(id), url, BatchState::Queued),
+                Err(e) => {
+                    info!("[queue] reject sync {url}: {e}");
+                    batch.add(None, url, BatchState::Rejected);
+                }
+            }
+        }
+        self.show_batch(bot, key, &batch).await;
+    }
+
+    /// Queue a job for the owner, and persist it if the store is configured.
+    /// Returns the job id and its position.
+    async fn try_push(
+        &self,
+        owner: i64,
+        priority: bool,
+        job: SyncJob,
+    ) -> Result<(u64, usize), QueueFull> {
+        let id = self.queue.reserve_id();
+        let job = Job {
+            id,
+            owner,
+            priority,
+            data: job,
+        };
+        // save and add control before workers can take the job, or a fast
+        // job may be removed from the store before it is saved.
+        self.add_control(id, job.data.user);
+        if let Some(store) = &self.store {
+            if let Err(e) = store.save(&job).await {
+                tracing::error!("[queue] unable to save job {id}: {e:?}");
+            }
+        }
+        match self.queue.push_job(job) {
+            Ok(position) => Ok((id, position)),
+            Err(e) => {
+                self.finish_job(id).await;
+                Err(e)
+            }
+        }
+    }
 
-            single_flight: Default::default(),
+    /// Queue a job for the owner, and show the position if it has to wait.
+    async fn push_job(
+        &self,
+        bot: &DefaultParseMode<Bot>,
+        owner: i64,
+        priority: bool,
+        job: SyncJob,
+    ) {
+        let url = job.url.clone();
+        let status = job.status.clone();
+        match self.try_push(owner, priority, job).await {
+            Ok((id, position)) if position > self.queue.idle() => {
+                info!("[queue] sync {url} is queued at position {position}");
+                let text = format!("Queued url {url}, position {position}");
+                status
+                    .edit(bot, escape(&text), Some(cancel_markup(id)))
+                    .await;
+            }
+            Ok(_) => (),
+            Err(e) => {
+                info!("[queue] reject sync {url}: {e}");
+                let text = format!("Too many sync requests now, please retry later. ({e})");
+                status.edit(bot, escape(&text), None).await;
+            }
         }
     }
 
@@ -134,11 +855,7 @@ where
                         .reply_to_message_id(msg.id)
                         .await
                 );
-                tokio::spawn(async move {
-                    let _ = bot
-                        .edit_message_text(msg.chat.id, msg.id, self.sync_response(&url).await)
-                        .await;
-                });
+                self.enqueue(&bot, &msg, url).await;
             }
         };
 
@@ -162,6 +879,23 @@ where
                 });
                 ControlFlow::Break(())
             }
+            AdminCommand::Status => {
+                let status = circuit_breaker::status();
+                let text = if status.is_empty() {
+                    "No site has been requested.".to_string()
+                } else {
+                    status
+                        .iter()
+                        .map(|(site, status)| format!("{site}: {status}"))
+                        .collect::<Vec<_>>()
+                        .join("\n")
+                };
+                let _ = bot
+                    .send_message(msg.chat.id, escape(&text))
+                    .reply_to_message_id(msg.id)
+                    .await;
+                ControlFlow::Break(())
+            }
         }
     }
 
@@ -170,49 +904,7 @@ where
         bot: DefaultParseMode<Bot>,
         msg: Message,
     ) -> ControlFlow<()> {
-        let maybe_link = {
-            let entries = msg
-                .entities()
-                .map(|es| {
-                    es.iter().filter_map(|e| {
-                        if let teloxide::types::MessageEntityKind::TextLink { url } = &e.kind {
-                            Synchronizer::match_url_from_text(url.as_ref()).map(ToOwned::to_owned)
-                        } else {
-                            None
-                        }
-                    })
-                })
-                .into_iter()
-                .flatten();
-            msg.text()
-                .and_then(|content| {
-                    Synchronizer::match_url_from_text(content).map(ToOwned::to_owned)
-                })
-                .into_iter()
-                .chain(entries)
-                .next()
-        };
-
-        if let Some(url) = maybe_link {
-            info!(
-                "[text handler] receive sync request from {:?} for {url}",
-                PrettyChat(&msg.chat)
-            );
-            let msg: Message = ok_or_break!(
-                bot.send_message(msg.chat.id, escape(&format!("Syncing url {url}")))
-                    .reply_to_message_id(msg.id)
-                    .await
-            );
-            tokio::spawn(async move {
-                let _ = bot
-                    .edit_message_text(msg.chat.id, msg.id, self.sync_response(&url).await)
-                    .await;
-            });
-            return ControlFlow::Break(());
-        }
-
-        // fallback to the next branch
-        ControlFlow::Continue(())
+        self.respond_urls(bot, msg, "text handler").await
     }
 
     pub async fn respond_caption(
@@ -220,57 +912,45 @@ where
         bot: DefaultParseMode<Bot>,
         msg: Message,
     ) -> ControlFlow<()> {
-        let caption_entities = msg.caption_entities();
-        let mut final_url = None;
-        for entry in caption_entities.map(|x| x.iter()).into_iter().flatten() {
-            let url = match &entry.kind {
-                teloxide::types::MessageEntityKind::Url => {
-                    let raw = msg
-                        .caption()
-                        .expect("Url MessageEntry found but caption is None");
-                    let encoded: Vec<_> = raw
-                        .encode_utf16()
-                        .skip(entry.offset)
-                        .take(entry.length)
-                        .collect();
-                    let content = ok_or_break!(String::from_utf16(&encoded));
-                    Cow::from(content)
-                }
-                teloxide::types::MessageEntityKind::TextLink { url } => Cow::from(url.as_ref()),
-                _ => {
-                    continue;
-                }
-            };
-            let url = if let Some(c) = Synchronizer::match_url_from_url(&url) {
-                c
-            } else {
-                continue;
-            };
-            final_url = Some(url.to_string());
-            break;
+        self.respond_urls(bot, msg, "caption handler").await
+    }
+
+    /// Sync all urls in the message, multiple urls share one status message.
+    async fn respond_urls(
+        &'static self,
+        bot: DefaultParseMode<Bot>,
+        msg: Message,
+        handler: &str,
+    ) -> ControlFlow<()> {
+        let mut urls = message_urls(&msg);
+        if urls.is_empty() {
+            // fallback to the next branch
+            return ControlFlow::Continue(());
         }
+        info!(
+            "[{handler}] receive sync request from {:?} for {}",
+            PrettyChat(&msg.chat),
+            urls.join(", ")
+        );
 
-        match final_url {
-            Some(url) => {
-                info!(
-                    "[caption handler] receive sync request from {:?} for {url}",
-                    PrettyChat(&msg.chat)
-                );
-                let msg: Message = ok_or_break!(
-                    bot.send_message(msg.chat.id, escape(&format!("Syncing url {url}")))
-                        .reply_to_message_id(msg.id)
-                        .await
-                );
-                let url = url.to_string();
-                tokio::spawn(async move {
-                    let _ = bot
-                        .edit_message_text(msg.chat.id, msg.id, self.sync_response(&url).await)
-                        .await;
-                });
-                ControlFlow::Break(())
-            }
-            None => ControlFlow::Continue(()),
+        if let [url] = urls.as_slice() {
+            let msg: Message = ok_or_break!(
+                bot.send_message(msg.chat.id, escape(&format!("Syncing url {url}")))
+                    .reply_to_message_id(msg.id)
+                    .await
+            );
+            self.enqueue(&bot, &msg, url.clone()).await;
+            return ControlFlow::Break(());
         }
+
+        let skipped = limit_urls(&mut urls);
+        let msg: Message = ok_or_break!(
+            bot.send_message(msg.chat.id, escape(&format!("Syncing {} urls", urls.len())))
+                .reply_to_message_id(msg.id)
+                .await
+        );
+        self.enqueue_batch(&bot, &msg, urls, skipped).await;
+        ControlFlow::Break(())
     }
 
     pub async fn respond_photo(
@@ -335,11 +1015,7 @@ where
             .reply_to_message_id(msg.id)
             .await
         {
-            tokio::spawn(async move {
-                let _ = bot
-                    .edit_message_text(msg.chat.id, msg.id, self.sync_response(&url).await)
-                    .await;
-            });
+            self.enqueue(&bot, &msg, url).await;
         }
 
         ControlFlow::Break(())
@@ -362,22 +1038,34 @@ where
         ControlFlow::Break(())
     }
 
-    async fn sync_response(&self, url: &str) -> String {
-        self.single_flight
-            .work(url, || async {
-                match self.route_sync(url).await {
-                    Ok(url) => {
-                        format!("Sync to telegraph finished: {}", link(&url, &escape(&url)))
-                    }
-                    Err(e) => {
+    /// Syncs of the same url share one run, whose progress is sent to every caller.
+    /// The run is cancelled only when all callers are cancelled.
+    async fn sync_response(
+        &'static self,
+        url: &str,
+        opts: SyncOptions<'_>,
+    ) -> Result<SyncRecord, String> {
+        let owned = url.to_string();
+        let result = self
+            .syncs
+            .run(url, opts, move |progress, cancel| async move {
+                let opts = SyncOptions {
+                    progress: Some(&progress),
+                    cancel: Some(&cancel),
+                };
+                self.route_sync(&owned, opts).await.map_err(|e| {
+                    if e.is::<SyncCancelled>() {
+                        format!("Sync cancelled: {}", escape(&owned))
+                    } else {
                         format!("Sync to telegraph failed: {}", escape(&e.to_string()))
                     }
-                }
+                })
             })
-            .await
+            .await;
+        result.unwrap_or_else(|| Err(format!("Sync cancelled: {}", escape(url))))
     }
 
-    async fn route_sync(&self, url: &str) -> anyhow::Result<String> {
+    async fn route_sync(&self, url: &str, opts: SyncOptions<'_>) -> anyhow::Result<SyncRecord> {
         let u = Url::parse(url).map_err(|_| anyhow::anyhow!("Invalid url"))?;
         let host = u.host_str().unwrap_or_default();
         let path = u.path().to_string();
@@ -387,26 +1075,274 @@ where
         match host {
             "e-hentai.org" => {
                 info!("[registry] sync e-hentai for path {}", path);
-                self.synchronizer
-                    .sync::<EHCollector>(path)
-                    .await
-                    .map_err(anyhow::Error::from)
+                self.synchronizer.sync_with::<EHCollector>(path, opts).await
             }
             "nhentai.to" | "nhentai.net" => {
                 info!("[registry] sync nhentai for path {}", path);
-                self.synchronizer
-                    .sync::<NHCollector>(path)
-                    .await
-                    .map_err(anyhow::Error::from)
+                self.synchronizer.sync_with::<NHCollector>(path, opts).await
             }
             "exhentai.org" => {
                 info!("[registry] sync exhentai for path {}", path);
-                self.synchronizer
-                    .sync::<EXCollector>(path)
-                    .await
-                    .map_err(anyhow::Error::from)
+                self.synchronizer.sync_with::<EXCollector>(path, opts).await
             }
             _ => Err(anyhow::anyhow!("no matching collector")),
         }
     }
+
+    async fn route_delete_cached(&self, url: &str) -> anyhow::Result<()> {
+        let u = Url::parse(url).map_err(|_| anyhow::anyhow!("Invalid url"))?;
+        let host = u.host_str().unwrap_or_default();
+        let path = u.path();
+
+        match host {
+            "e-hentai.org" => self.synchronizer.delete_cached::<EHCollector>(path).await,
+            "nhentai.to" | "nhentai.net" => {
+                self.synchronizer.delete_cached::<NHCollector>(path).await
+            }
+            "exhentai.org" => self.synchronizer.delete_cached::<EXCollector>(path).await,
+            _ => Err(anyhow::anyhow!("no matching collector")),
+        }
+    }
+
+    async fn route_cached(&self, url: &str) -> anyhow::Result<Option<SyncRecord>> {
+        let u = Url::parse(url).map_err(|_| anyhow::anyhow!("Invalid url"))?;
+        let host = u.host_str().unwrap_or_default();
+        let path = u.path();
+
+        match host {
+            "e-hentai.org" => self.synchronizer.cached::<EHCollector>(path).await,
+            "nhentai.to" | "nhentai.net" => self.synchronizer.cached::<NHCollector>(path).await,
+            "exhentai.org" => self.synchronizer.cached::<EXCollector>(path).await,
+            _ => Err(anyhow::anyhow!("no matching collector")),
+        }
+    }
+}
+
+fn cancel_markup(id: u64) -> InlineKeyboardMarkup {
+    InlineKeyboardMarkup::new([[InlineKeyboardButton::callback(
+        "Cancel",
+        format!("{CANCEL_PREFIX}{id}"),
+    )]])
+}
+
+fn finished_text(record: &SyncRecord) -> String {
+    format!(
+        "Sync to telegraph finished: {}",
+        link(&record.url, &escape(&record.url))
+    )
+}
+
+/// Distinct supported urls in the text or caption and its links.
+fn message_urls(msg: &Message) -> Vec<String> {
+    let content = msg.text().or_else(|| msg.caption()).unwrap_or_default();
+    let entities = msg
+        .entities()
+        .or_else(|| msg.caption_entities())
+        .unwrap_or_default();
+    text_urls(content, entities)
+}
+
+/// Distinct supported urls in the text and its links.
+fn text_urls(content: &str, entities: &[MessageEntity]) -> Vec<String> {
+    let links = entities.iter().filter_map(|e| match &e.kind {
+        MessageEntityKind::TextLink { url } => Synchronizer::match_url_from_text(url.as_str()),
+        _ => None,
+    });
+    let mut urls: Vec<String> = Vec::new();
+    for url in Synchronizer::match_urls_from_text(content).chain(links) {
+        if !urls.iter().any(|u| u == url) {
+            urls.push(url.to_string());
+        }
+    }
+    urls
+}
+
+/// Keep urls within the limit of a message, returns the number of skipped ones.
+fn limit_urls(urls: &mut Vec<String>) -> usize {
+    let skipped = urls.len().saturating_sub(MAX_URLS_PER_MESSAGE);
+    urls.truncate(MAX_URLS_PER_MESSAGE);
+    skipped
+}
+
+/// Gallery url in callback data, which is saved without scheme.
+fn callback_url(target: &str) -> Option<String> {
+    Synchronizer::match_url_from_url(&format!("https://{target}")).map(ToOwned::to_owned)
+}
+
+/// Message of the pressed button.
+fn callback_status(query: &CallbackQuery) -> Option<StatusMessage> {
+    match (&query.message, &query.inline_message_id) {
+        (Some(message), _) => Some(StatusMessage::Chat {
+            chat: message.chat.id,
+            message: message.id,
+        }),
+        (None, Some(id)) => Some(StatusMessage::Inline {
+            inline_message_id: id.clone(),
+        }),
+        _ => None,
+    }
+}
+
+/// Buttons of a finished sync, actions with too long data are omitted.
+fn result_markup(record: &SyncRecord, job: &SyncJob) -> InlineKeyboardMarkup {
+    let links = [("Open", &record.url), ("Original", &job.url)]
+        .into_iter()
+        .filter_map(|(text, url)| Some(InlineKeyboardButton::url(text, Url::parse(url).ok()?)))
+        .collect::<Vec<_>>();
+
+    let target = job.url.trim_start_matches("https://");
+    let mut actions = vec![(RESYNC_PREFIX, "Re-sync"), (REPORT_PREFIX, "Report broken")];
+    // search results are replied to the chat
+    if matches!(job.status, StatusMessage::Chat { .. }) {
+        actions.push((SIMILAR_PREFIX, "More like this"));
+    }
+    let actions = actions
+        .into_iter()
+        .map(|(prefix, text)| (text, format!("{prefix}{target}")))
+        .filter(|(_, data)| data.len() <= MAX_CALLBACK_DATA_LEN)
+        .map(|(text, data)| InlineKeyboardButton::callback(text, data))
+        .collect::<Vec<_>>();
+
+    InlineKeyboardMarkup::new([links, actions].into_iter().filter(|row| !row.is_empty()))
+}
+
+fn synced_article(record: &SyncRecord) -> InlineQueryResult {
+    let title = if record.title.is_empty() {
+        record.url.clone()
+    } else {
+        record.title.clone()
+    };
+    let content = InputMessageContentText::new(format!("{title}\n{}", record.url));
+    let mut article =
+        InlineQueryResultArticle::new("synced", title, InputMessageContent::Text(content))
+            .description(record.url.clone());
+    if let Ok(url) = Url::parse(&record.url) {
+        article = article.url(url);
+    }
+    if let Some(cover) = record.cover.as_deref().and_then(|c| Url::parse(c).ok()) {
+        article = article.thumb_url(cover);
+    }
+    InlineQueryResult::Article(article)
+}
+
+fn unsynced_article(url: &str) -> InlineQueryResult {
+    let content = InputMessageContentText::new(url);
+    let mut article = InlineQueryResultArticle::new(
+        "unsynced",
+        "Not synced yet",
+        InputMessageContent::Text(content),
+    )
+    .description(format!("Send it and tap Sync to sync {url}"));
+    let data = format!("{SYNC_PREFIX}{}", url.trim_start_matches("https://"));
+    if data.len() <= MAX_CALLBACK_DATA_LEN {
+        article = article.reply_markup(InlineKeyboardMarkup::new([[
+            InlineKeyboardButton::callback("Sync", data),
+        ]]));
+    }
+    InlineQueryResult::Article(article)
+}
+
+#[cfg(test)]
+mod tests {
+    use eh2telegraph::sync::SyncRecord;
+    use reqwest::Url;
+    use teloxide::types::{
+        ChatId, InlineKeyboardButtonKind, InlineKeyboardMarkup, MessageEntity, MessageId,
+    };
+
+    use super::{
+        limit_urls, result_markup, text_urls, StatusMessage, SyncJob, MAX_CALLBACK_DATA_LEN,
+        MAX_URLS_PER_MESSAGE,
+    };
+
+    fn job(url: String, status: StatusMessage) -> SyncJob {
+        SyncJob {
+            url,
+            status,
+            user: None,
+            request: None,
+            batched: false,
+        }
+    }
+
+    fn callbacks(markup: &InlineKeyboardMarkup) -> Vec<String> {
+        markup
+            .inline_keyboard
+            .iter()
+            .flatten()
+            .filter_map(|button| match &button.kind {
+                InlineKeyboardButtonKind::CallbackData(data) => Some(data.clone()),
+                _ => None,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn long_callback_data() {
+        let record = SyncRecord::from_url("https://telegra.ph/A-01-01".to_string());
+        let chat = StatusMessage::Chat {
+            chat: ChatId(1),
+            message: MessageId(2),
+        };
+        // resync and report data are just within the limit, but similar is not
+        let target = format!(
+            "e-hentai.org/g/1/{}",
+            "a".repeat(MAX_CALLBACK_DATA_LEN - 24)
+        );
+        let markup = result_markup(&record, &job(format!("https://{target}"), chat.clone()));
+        assert_eq!(markup.inline_keyboard.len(), 2);
+        assert_eq!(
+            callbacks(&markup),
+            [format!("resync:{target}"), format!("report:{target}")]
+        );
+
+        let markup = result_markup(&record, &job(format!("https://{target}a"), chat.clone()));
+        assert_eq!(markup.inline_keyboard.len(), 1);
+        assert!(callbacks(&markup).is_empty());
+
+        let url = "https://e-hentai.org/g/1/a".to_string();
+        let markup = result_markup(&record, &job(url.clone(), chat));
+        assert_eq!(callbacks(&markup).len(), 3);
+        let inline = StatusMessage::Inline {
+            inline_message_id: "inline".to_string(),
+        };
+        let markup = result_markup(&record, &job(url, inline));
+        assert_eq!(
+            callbacks(&markup),
+            ["resync:e-hentai.org/g/1/a", "report:e-hentai.org/g/1/a"]
+        );
+    }
+
+    #[test]
+    fn distinct_urls() {
+        let text = "https://e-hentai.org/g/1/a/ and here https://nhentai.net/g/2 https://e-hentai.org/g/1/a";
+        let here = text.find("here").unwrap();
+        let entities = [
+            MessageEntity::text_link(Url::parse("https://nhentai.net/g/2").unwrap(), here, 4),
+            MessageEntity::text_link(Url::parse("https://exhentai.org/g/3/c").unwrap(), 0, 4),
+            MessageEntity::text_link(Url::parse("https://example.com/").unwrap(), 0, 4),
+            MessageEntity::bold(0, 4),
+        ];
+        assert_eq!(
+            text_urls(text, &entities),
+            [
+                "https://e-hentai.org/g/1/a",
+                "https://nhentai.net/g/2",
+                "https://exhentai.org/g/3/c"
+            ]
+        );
+        assert!(text_urls("no urls", &[]).is_empty());
+    }
+
+    #[test]
+    fn limit_per_message() {
+        let mut urls = (0..MAX_URLS_PER_MESSAGE + 3)
+            .map(|i| format!("https://nhentai.net/g/{i}"))
+            .collect::<Vec<_>>();
+        assert_eq!(limit_urls(&mut urls), 3);
+        assert_eq!(urls.len(), MAX_URLS_PER_MESSAGE);
+        assert_eq!(urls.last().unwrap(), "https://nhentai.net/g/9");
+        assert_eq!(limit_urls(&mut urls), 0);
+        assert_eq!(urls.len(), MAX_URLS_PER_MESSAGE);
+    }
 }
@@ -1,9 +1,9 @@
-#![feature(control_flow_enum)]
-
 use eh2telegraph::{
+    buffer::MemoryBudget,
     collector::Registry,
     config::{self},
     http_proxy::ProxiedClient,
+    rate_limit::HostLimiter,
     storage,
     sync::Synchronizer,
     telegraph::Telegraph,
@@ -29,7 +29,11 @@ use crate::{
     util::{wrap_endpoint, PrettyChat},
 };
 
+mod batch;
 mod handler;
+mod progress;
+mod result;
+mod shared;
 mod util;
 mod version;
 
@@ -83,6 +87,8 @@ async fn main() {
     tracing::info!("initializing...");
 
     config::init(args.config);
+    HostLimiter::init_from_config().expect("unable to parse http limit config");
+    MemoryBudget::init_from_config().expect("unable to parse sync memory budget config");
     let base_config: BaseConfig = config::parse("base")
         .expect("unable to parse base config")
         .expect("base config can not be empty");
@@ -121,6 +127,12 @@ async fn main() {
     let photo_handler = move |bot: DefaultParseMode<Bot>, message: Message| async move {
         handler.respond_photo(bot, message).await
     };
+    let callback_handler = move |bot: DefaultParseMode<Bot>, query: CallbackQuery| async move {
+        handler.respond_callback(bot, query).await
+    };
+    let inline_handler = move |bot: DefaultParseMode<Bot>, query: InlineQuery| async move {
+        handler.respond_inline(bot, query).await
+    };
     let default_handler = move |bot: DefaultParseMode<Bot>, message: Message| async move {
         handler.respond_default(bot, message).await
     };
@@ -154,67 +166,91 @@ async fn main() {
     };
 
     let bot = Bot::new(base_config.bot_token).parse_mode(ParseMode::MarkdownV2);
+    handler.start_workers(bot.clone()).await;
     let mut bot_dispatcher = Dispatcher::builder(
         bot.clone(),
         dptree::entry()
-            .chain(dptree::filter_map(move |update: Update| {
-                match update.kind {
-                    UpdateKind::Message(x) | UpdateKind::EditedMessage(x) => Some(x),
-                    _ => None,
-                }
-            }))
-            .chain(dptree::filter_map_async(time_filter))
-            .chain(dptree::filter_map_async(permission_filter))
             .branch(
                 dptree::entry()
-                    .chain(dptree::filter(move |message: Message| {
-                        handler.admins.contains(&message.chat.id.0)
-                    }))
-                    .filter_command::<AdminCommand>()
-                    .branch(wrap_endpoint(admin_command_handler)),
-            )
-            .branch(
-                dptree::entry()
-                    .filter_command::<Command>()
-                    .branch(wrap_endpoint(command_handler)),
-            )
-            .branch(
-                dptree::entry()
-                    .chain(dptree::filter_map(move |message: Message| {
-                        // Ownership mechanism does not allow using map.
-                        #[allow(clippy::manual_map)]
-                        match message.text() {
-                            Some(v) if !v.is_empty() => Some(message),
+                    .chain(dptree::filter_map(move |update: Update| {
+                        match update.kind {
+                            UpdateKind::CallbackQuery(x) => Some(x),
                             _ => None,
                         }
                     }))
-                    .branch(wrap_endpoint(text_handler)),
+                    .branch(wrap_endpoint(callback_handler)),
             )
             .branch(
                 dptree::entry()
-                    .chain(dptree::filter_map(move |message: Message| {
-                        // Ownership mechanism does not allow using map.
-                        #[allow(clippy::manual_map)]
-                        match message.caption_entities() {
-                            Some(v) if !v.is_empty() => Some(message),
+                    .chain(dptree::filter_map(move |update: Update| {
+                        match update.kind {
+                            UpdateKind::InlineQuery(x) => Some(x),
                             _ => None,
                         }
                     }))
-                    .branch(wrap_endpoint(caption_handler)),
+                    .branch(wrap_endpoint(inline_handler)),
             )
             .branch(
                 dptree::entry()
-                    .chain(dptree::filter_map(move |message: Message| {
-                        // Ownership mechanism does not allow using map.
-                        #[allow(clippy::manual_map)]
-                        match message.photo() {
-                            Some(v) if !v.is_empty() => Some(message),
+                    .chain(dptree::filter_map(move |update: Update| {
+                        match update.kind {
+                            UpdateKind::Message(x) | UpdateKind::EditedMessage(x) => Some(x),
                             _ => None,
                         }
                     }))
-                    .branch(wrap_endpoint(photo_handler)),
-            )
-            .branch(wrap_endpoint(default_handler)),
+                    .chain(dptree::filter_map_async(time_filter))
+                    .chain(dptree::filter_map_async(permission_filter))
+                    .branch(
+                        dptree::entry()
+                            .chain(dptree::filter(move |message: Message| {
+                                handler.admins.contains(&message.chat.id.0)
+                            }))
+                            .filter_command::<AdminCommand>()
+                            .branch(wrap_endpoint(admin_command_handler)),
+                    )
+                    .branch(
+                        dptree::entry()
+                            .filter_command::<Command>()
+                            .branch(wrap_endpoint(command_handler)),
+                    )
+                    .branch(
+                        dptree::entry()
+                            .chain(dptree::filter_map(move |message: Message| {
+                                // Ownership mechanism does not allow using map.
+                                #[allow(clippy::manual_map)]
+                                match message.text() {
+                                    Some(v) if !v.is_empty() => Some(message),
+                                    _ => None,
+                                }
+                            }))
+                            .branch(wrap_endpoint(text_handler)),
+                    )
+                    .branch(
+                        dptree::entry()
+                            .chain(dptree::filter_map(move |message: Message| {
+                                // Ownership mechanism does not allow using map.
+                                #[allow(clippy::manual_map)]
+                                match message.caption_entities() {
+                                    Some(v) if !v.is_empty() => Some(message),
+                                    _ => None,
+                                }
+                            }))
+                            .branch(wrap_endpoint(caption_handler)),
+                    )
+                    .branch(
+                        dptree::entry()
+                            .chain(dptree::filter_map(move |message: Message| {
+                                // Ownership mechanism does not allow using map.
+                                #[allow(clippy::manual_map)]
+                                match message.photo() {
+                                    Some(v) if !v.is_empty() => Some(message),
+                                    _ => None,
+                                }
+                            }))
+                            .branch(wrap_endpoint(photo_handler)),
+                    )
+                    .branch(wrap_endpoint(default_handler)),
+            ),
     )
     .default_handler(Box::new(|_upd| {
         #[cfg(debug_assertions)]
@@ -225,7 +261,11 @@ async fn main() {
     .enable_ctrlc_handler()
     .build();
     let bot_listener = update_listeners::Polling::builder(bot)
-        .allowed_updates(vec![AllowedUpdate::Message])
+        .allowed_updates(vec![
+            AllowedUpdate::Message,
+            AllowedUpdate::CallbackQuery,
+            AllowedUpdate::InlineQuery,
+        ])
         .timeout(std::time::Duration::from_secs(10))
         .build();
 
@@ -0,0 +1,150 @@
+use std::time::{Duration, Instant};
+
+use eh2telegraph::sync::SyncProgress;
+
+const BAR_LEN: usize = 10;
+
+/// Render sync progress as status text, with a progress bar and ETA.
+pub struct ProgressText {
+    url: String,
+    // when the first image is downloaded
+    download_started: Option<Instant>,
+    last: Option<SyncProgress>,
+}
+
+impl ProgressText {
+    pub fn new(url: String) -> Self {
+        Self {
+            url,
+            download_started: None,
+            last: None,
+        }
+    }
+
+    /// Returns None if progress is not changed since the last render.
+    pub fn render(&mut self, progress: SyncProgress) -> Option<String> {
+        if self.last == Some(progress) {
+            return None;
+        }
+        self.last = Some(progress);
+
+        let phase = match progress {
+            SyncProgress::FetchingIndex => "Fetching gallery index".to_string(),
+            SyncProgress::Downloading { done, total } => {
+                let started = *self.download_started.get_or_insert_with(Instant::now);
+                match total {
+                    Some(total) => {
+                        let mut text = format!("Downloading {}", bar(done, total));
+                        if let Some(eta) = eta(started.elapsed(), done, total) {
+                            text.push_str(&format!(", ETA {}", format_duration(eta)));
+                        }
+                        text
+                    }
+                    None => format!("Downloading {done} images"),
+                }
+            }
+            SyncProgress::Uploading { done, total } => match total {
+                Some(total) => format!("Uploading {}", bar(done, total)),
+                None => format!("Uploading, {done} images uploaded"),
+            },
+            SyncProgress::CreatingPages { done, total } => {
+                format!("Creating pages {}", bar(done, total))
+            }
+        };
+        Some(format!("Syncing url {}\n{phase}", self.url))
+    }
+}
+
+fn bar(done: usize, total: usize) -> String {
+    let filled = (done * BAR_LEN)
+        .checked_div(total)
+        .unwrap_or(0)
+        .min(BAR_LEN);
+    format!(
+        "[{}{}] {done}/{total}",
+        "#".repeat(filled),
+        "-".repeat(BAR_LEN - filled)
+    )
+}
+
+fn eta(elapsed: Duration, done: usize, total: usize) -> Option<Duration> {
+    if done == 0 || done >= total {
+        return None;
+    }
+    Some(elapsed.mul_f64((total - done) as f64 / done as f64))
+}
+
+fn format_duration(d: Duration) -> String {
+    let secs = d.as_secs();
+    match secs {
+        0..=59 => format!("{secs}s"),
+        60..=3599 => format!("{}m{}s", secs / 60, secs % 60),
+        _ => format!("{}h{}m", secs / 3600, secs % 3600 / 60),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use eh2telegraph::sync::SyncProgress;
+
+    use super::{bar, eta, format_duration, ProgressText};
+
+    #[test]
+    fn render_bar() {
+        assert_eq!(bar(0, 4), "[----------] 0/4");
+        assert_eq!(bar(1, 4), "[##--------] 1/4");
+        assert_eq!(bar(4, 4), "[##########] 4/4");
+        // more done than expected, and no total at all
+        assert_eq!(bar(5, 4), "[##########] 5/4");
+        assert_eq!(bar(3, 0), "[----------] 3/0");
+    }
+
+    #[test]
+    fn estimate() {
+        let elapsed = Duration::from_secs(10);
+        assert_eq!(eta(elapsed, 0, 4), None);
+        assert_eq!(eta(elapsed, 4, 4), None);
+        assert_eq!(eta(elapsed, 1, 4), Some(Duration::from_secs(30)));
+        assert_eq!(eta(elapsed, 2, 4), Some(Duration::from_secs(10)));
+    }
+
+    #[test]
+    fn durations() {
+        assert_eq!(format_duration(Duration::from_secs(0)), "0s");
+        assert_eq!(format_duration(Duration::from_millis(59_900)), "59s");
+        assert_eq!(format_duration(Duration::from_secs(61)), "1m1s");
+        assert_eq!(format_duration(Duration::from_secs(3599)), "59m59s");
+        assert_eq!(format_duration(Duration::from_secs(3600 * 2 + 90)), "2h1m");
+    }
+
+    #[test]
+    fn render_changes_only() {
+        let mut text = ProgressText::new("https://e-hentai.org/g/1/a/".to_string());
+        assert_eq!(
+            text.render(SyncProgress::FetchingIndex).unwrap(),
+            "Syncing url https://e-hentai.org/g/1/a/\nFetching gallery index"
+        );
+        assert_eq!(text.render(SyncProgress::FetchingIndex), None);
+
+        let downloading = SyncProgress::Downloading {
+            done: 0,
+            total: Some(4),
+        };
+        assert!(text
+            .render(downloading)
+            .unwrap()
+            .ends_with("\nDownloading [----------] 0/4"));
+        assert_eq!(text.render(downloading), None);
+        let uploading = SyncProgress::Uploading {
+            done: 3,
+            total: None,
+        };
+        assert!(text
+            .render(uploading)
+            .unwrap()
+            .ends_with("\nUploading, 3 images uploaded"));
+        assert_eq!(text.render(uploading), None);
+    }
+}
@@ -0,0 +1,148 @@
+use std::collections::HashMap;
+
+use eh2telegraph::{config, sync::SyncRecord};
+use teloxide::utils::markdown::{bold, escape, link};
+
+const CONFIG_KEY: &str = "result";
+// caption is limited to 1024 characters, so title and tags are cut
+const MAX_TITLE_CHARS: usize = 256;
+const MAX_CAPTION_TAGS: usize = 10;
+// tags of these namespaces say little about the content
+const MINOR_NAMESPACES: &[&str] = &["language", "other", "reclass"];
+
+/// How sync results are sent.
+#[derive(Debug, Default, serde::Deserialize)]
+pub struct ResultConfig {
+    /// Send the cover as a photo with album info, instead of editing the status message.
+    #[serde(default)]
+    pub rich: bool,
+    /// Send the cover with spoiler animation.
+    #[serde(default)]
+    pub spoiler: bool,
+    /// Overrides for chats.
+    #[serde(default)]
+    pub chats: HashMap<i64, ChatResultConfig>,
+}
+
+#[derive(Debug, Default, serde::Deserialize)]
+pub struct ChatResultConfig {
+    pub rich: Option<bool>,
+    pub spoiler: Option<bool>,
+}
+
+impl ResultConfig {
+    pub fn from_config() -> anyhow::Result<Self> {
+        Ok(config::parse(CONFIG_KEY)?.unwrap_or_default())
+    }
+
+    /// Returns the spoiler flag if rich result is enabled for the chat.
+    pub fn rich_spoiler(&self, chat: i64) -> Option<bool> {
+        let overrides = self.chats.get(&chat);
+        let rich = overrides.and_then(|c| c.rich).unwrap_or(self.rich);
+        rich.then(|| overrides.and_then(|c| c.spoiler).unwrap_or(self.spoiler))
+    }
+}
+
+/// Whether the tag tells something about the content.
+pub fn is_key_tag(tag: &str) -> bool {
+    !matches!(tag.split_once(':'), Some((ns, _)) if MINOR_NAMESPACES.contains(&ns))
+}
+
+/// Caption of the rich result, in MarkdownV2.
+pub fn caption(record: &SyncRecord, original: &str) -> String {
+    let title = if record.title.is_empty() {
+        record.url.clone()
+    } else {
+        record.title.chars().take(MAX_TITLE_CHARS).collect()
+    };
+    let mut lines = vec![
+        bold(&escape(&title)),
+        escape(&format!("Pages: {}", record.image_count)),
+    ];
+    if let Some(category) = &record.category {
+        lines.push(escape(&format!("Category: {category}")));
+    }
+    let tags = record
+        .tags
+        .iter()
+        .filter(|tag| is_key_tag(tag))
+        .take(MAX_CAPTION_TAGS)
+        .map(String::as_str)
+        .collect::<Vec<_>>();
+    if !tags.is_empty() {
+        lines.push(escape(&format!("Tags: {}", tags.join(", "))));
+    }
+    lines.push(format!(
+        "Telegraph: {}",
+        link(&record.url, &escape(&record.url))
+    ));
+    lines.push(format!("Original: {}", link(original, &escape(original))));
+    lines.join("\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use eh2telegraph::sync::SyncRecord;
+
+    use super::{caption, ChatResultConfig, ResultConfig, MAX_TITLE_CHARS};
+
+    #[test]
+    fn chat_overrides() {
+        let mut config = ResultConfig {
+            rich: false,
+            spoiler: true,
+            ..Default::default()
+        };
+        let chat = |rich, spoiler| ChatResultConfig { rich, spoiler };
+        config.chats.insert(1, chat(Some(true), None));
+        config.chats.insert(2, chat(None, Some(false)));
+        config.chats.insert(3, chat(Some(true), Some(false)));
+        assert_eq!(config.rich_spoiler(0), None);
+        assert_eq!(config.rich_spoiler(1), Some(true));
+        assert_eq!(config.rich_spoiler(2), None);
+        assert_eq!(config.rich_spoiler(3), Some(false));
+
+        config.rich = true;
+        config.chats.insert(1, chat(Some(false), None));
+        assert_eq!(config.rich_spoiler(0), Some(true));
+        assert_eq!(config.rich_spoiler(1), None);
+        assert_eq!(config.rich_spoiler(2), Some(false));
+    }
+
+    #[test]
+    fn render_caption() {
+        let mut record = SyncRecord::from_url("https://telegra.ph/A-01-01".to_string());
+        record.title = "Tom & Jerry [Eng]".to_string();
+        record.image_count = 12;
+        record.category = Some("Doujinshi".to_string());
+        record.tags = ["language:english", "other:full color"]
+            .into_iter()
+            .map(String::from)
+            .chain((0..12).map(|i| format!("female:tag{i}")))
+            .collect();
+        let tags = (0..10)
+            .map(|i| format!("female:tag{i}"))
+            .collect::<Vec<_>>()
+            .join(", ");
+        let expected = [
+            r"*Tom & Jerry \[Eng\]*".to_string(),
+            "Pages: 12".to_string(),
+            "Category: Doujinshi".to_string(),
+            format!("Tags: {tags}"),
+            r"Telegraph: [https://telegra\.ph/A\-01\-01](https://telegra.ph/A-01-01)".to_string(),
+            r"Original: [https://e\-hentai\.org/g/1/a/](https://e-hentai.org/g/1/a/)".to_string(),
+        ];
+        assert_eq!(
+            caption(&record, "https://e-hentai.org/g/1/a/"),
+            expected.join("\n")
+        );
+
+        // the title falls back to the url, and long titles are cut
+        record.title = String::new();
+        let text = caption(&record, "https://e-hentai.org/g/1/a/");
+        assert!(text.starts_with(r"*https://telegra\.ph/A\-01\-01*"));
+        record.title = "a".repeat(MAX_TITLE_CHARS + 10);
+        let text = caption(&record, "https://e-hentai.org/g/1/a/");
+        assert!(text.starts_with(&format!("*{}*\n", "a".repeat(MAX_TITLE_CHARS))));
+    }
+}
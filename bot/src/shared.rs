@@ -0,0 +1,279 @@
+use std::{
+    collections::HashMap,
+    future::Future,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+};
+
+use eh2telegraph::sync::{ProgressSender, SyncOptions, SyncProgress};
+use tokio::sync::watch;
+use tokio_util::sync::CancellationToken;
+
+type Runs<T> = Arc<Mutex<HashMap<String, Arc<Run<T>>>>>;
+
+struct Run<T> {
+    result: watch::Receiver<Option<T>>,
+    progress: watch::Receiver<SyncProgress>,
+    cancel: CancellationToken,
+    // only changed with the runs locked
+    waiters: AtomicUsize,
+}
+
+/// Runs of the same key are shared by all callers waiting for it.
+/// Every waiter gets the progress of the run, and the run is cancelled only
+/// when all waiters are cancelled.
+pub struct SharedRuns<T> {
+    runs: Runs<T>,
+}
+
+impl<T> Default for SharedRuns<T> {
+    fn default() -> Self {
+        Self {
+            runs: Default::default(),
+        }
+    }
+}
+
+impl<T> SharedRuns<T>
+where
+    T: Clone + Send + Sync + 'static,
+{
+    /// Join the run of the key, or spawn one with `start`.
+    /// Returns None if the caller is cancelled or the run is aborted.
+    pub async fn run<F, Fut>(&self, key: &str, opts: SyncOptions<'_>, start: F) -> Option<T>
+    where
+        F: FnOnce(ProgressSender, CancellationToken) -> Fut,
+        Fut: Future<Output = T> + Send + 'static,
+    {
+        let waiter = self.join(key, start);
+        let mut result = waiter.run.result.clone();
+        let mut progress = waiter.run.progress.clone();
+        let forward = |progress: &mut watch::Receiver<SyncProgress>| {
+            if let Some(tx) = opts.progress {
+                tx.send_replace(*progress.borrow_and_update());
+            }
+        };
+        forward(&mut progress);
+        loop {
+            tokio::select! {
+                r = result.wait_for(Option::is_some) => return r.ok().and_then(|r| r.clone()),
+                Ok(()) = progress.changed() => forward(&mut progress),
+                _ = cancelled(opts.cancel) => return None,
+            }
+        }
+    }
+
+    fn join<F, Fut>(&self, key: &str, start: F) -> Waiter<T>
+    where
+        F: FnOnce(ProgressSender, CancellationToken) -> Fut,
+        Fut: Future<Output = T> + Send + 'static,
+    {
+        let mut runs = self.runs.lock().unwrap();
+        let run = match runs.get(key) {
+            Some(run) => run.clone(),
+            None => {
+                let (result_tx, result) = watch::channel(None);
+                let (progress_tx, progress) = watch::channel(SyncProgress::default());
+                let cancel = CancellationToken::new();
+                let run = Arc::new(Run {
+                    result,
+                    progress,
+                    cancel: cancel.clone(),
+                    waiters: Default::default(),
+                });
+                runs.insert(key.to_string(), run.clone());
+
+                let fut = start(progress_tx, cancel);
+                let (all, key, this) = (self.runs.clone(), key.to_string(), run.clone());
+                tokio::spawn(async move {
+                    let output = fut.await;
+                    remove_run(&mut all.lock().unwrap(), &key, &this);
+                    result_tx.send_replace(Some(output));
+                });
+                run
+            }
+        };
+        run.waiters.fetch_add(1, Ordering::Relaxed);
+        Waiter {
+            runs: self.runs.clone(),
+            key: key.to_string(),
+            run,
+        }
+    }
+}
+
+/// Leave the run on drop, and cancel it if no one is waiting.
+struct Waiter<T> {
+    runs: Runs<T>,
+    key: String,
+    run: Arc<Run<T>>,
+}
+
+impl<T> Drop for Waiter<T> {
+    fn drop(&mut self) {
+        let mut runs = self.runs.lock().unwrap();
+        if self.run.waiters.fetch_sub(1, Ordering::Relaxed) == 1 {
+            // later callers should start a new run instead of joining the cancelled one
+            remove_run(&mut runs, &self.key, &self.run);
+            self.run.cancel.cancel();
+        }
+    }
+}
+
+fn remove_run<T>(runs: &mut HashMap<String, Arc<Run<T>>>, key: &str, run: &Arc<Run<T>>) {
+    if runs.get(key).is_some_and(|r| Arc::ptr_eq(r, run)) {
+        runs.remove(key);
+    }
+}
+
+async fn cancelled(token: Option<&CancellationToken>) {
+    match token {
+        Some(token) => token.cancelled().await,
+        None => std::future::pending().await,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        Arc,
+    };
+
+    use eh2telegraph::sync::{SyncOptions, SyncProgress};
+    use tokio::sync::{oneshot, watch};
+    use tokio_util::sync::CancellationToken;
+
+    use super::SharedRuns;
+
+    const URL: &str = "https://e-hentai.org/g/1/a/";
+
+    fn waiters<T>(runs: &SharedRuns<T>, key: &str) -> usize {
+        let runs = runs.runs.lock().unwrap();
+        runs.get(key)
+            .map_or(0, |r| r.waiters.load(Ordering::Relaxed))
+    }
+
+    async fn wait_waiters<T>(runs: &SharedRuns<T>, key: &str, n: usize) {
+        while waiters(runs, key) != n {
+            tokio::task::yield_now().await;
+        }
+    }
+
+    #[tokio::test]
+    async fn share_result() {
+        let runs: &'static SharedRuns<&str> = Box::leak(Box::default());
+        let started = Arc::new(AtomicUsize::new(0));
+        let (finish, finished) = oneshot::channel();
+        let mut finished = Some(finished);
+        let mut handles = Vec::new();
+        for _ in 0..2 {
+            let (started, finished) = (started.clone(), finished.take());
+            handles.push(tokio::spawn(async move {
+                runs.run(URL, SyncOptions::default(), move |_, _| {
+                    started.fetch_add(1, Ordering::Relaxed);
+                    async move {
+                        finished.unwrap().await.unwrap();
+                        "done"
+                    }
+                })
+                .await
+            }));
+            wait_waiters(runs, URL, handles.len()).await;
+        }
+        finish.send(()).unwrap();
+        for handle in handles {
+            assert_eq!(handle.await.unwrap(), Some("done"));
+        }
+        assert_eq!(started.load(Ordering::Relaxed), 1);
+        assert_eq!(waiters(runs, URL), 0);
+    }
+
+    #[tokio::test]
+    async fn broadcast_progress() {
+        let runs: &'static SharedRuns<&str> = Box::leak(Box::default());
+        let progress = SyncProgress::Downloading {
+            done: 1,
+            total: Some(2),
+        };
+        let (go, ready) = oneshot::channel();
+        let (finish, finished) = oneshot::channel();
+        let mut start = Some((ready, finished));
+        let mut handles = Vec::new();
+        let mut receivers = Vec::new();
+        for _ in 0..2 {
+            let (tx, rx) = watch::channel(SyncProgress::default());
+            let start = start.take();
+            handles.push(tokio::spawn(async move {
+                let opts = SyncOptions {
+                    progress: Some(&tx),
+                    cancel: None,
+                };
+                runs.run(URL, opts, move |sender, _| async move {
+                    let (ready, finished) = start.unwrap();
+                    ready.await.unwrap();
+                    sender.send_replace(progress);
+                    finished.await.unwrap();
+                    "done"
+                })
+                .await
+            }));
+            receivers.push(rx);
+            wait_waiters(runs, URL, handles.len()).await;
+        }
+        go.send(()).unwrap();
+        // the job joining the run gets progress too
+        for rx in receivers.iter_mut() {
+            rx.wait_for(|p| *p == progress).await.unwrap();
+        }
+        finish.send(()).unwrap();
+        for handle in handles {
+            assert_eq!(handle.await.unwrap(), Some("done"));
+        }
+    }
+
+    #[tokio::test]
+    async fn cancel_when_all_cancelled() {
+        let runs: &'static SharedRuns<&str> = Box::leak(Box::default());
+        let aborted = Arc::new(AtomicBool::new(false));
+        let tokens = [CancellationToken::new(), CancellationToken::new()];
+        let mut handles = Vec::new();
+        for token in &tokens {
+            let (token, aborted) = (token.clone(), aborted.clone());
+            handles.push(tokio::spawn(async move {
+                let opts = SyncOptions {
+                    progress: None,
+                    cancel: Some(&token),
+                };
+                runs.run(URL, opts, move |_, cancel| async move {
+                    cancel.cancelled().await;
+                    aborted.store(true, Ordering::Relaxed);
+                    "aborted"
+                })
+                .await
+            }));
+            wait_waiters(runs, URL, handles.len()).await;
+        }
+
+        // the other job is still waiting
+        tokens[0].cancel();
+        let second = handles.pop().unwrap();
+        assert_eq!(handles.pop().unwrap().await.unwrap(), None);
+        assert_eq!(waiters(runs, URL), 1);
+        assert!(!aborted.load(Ordering::Relaxed));
+
+        tokens[1].cancel();
+        assert_eq!(second.await.unwrap(), None);
+        while !aborted.load(Ordering::Relaxed) {
+            tokio::task::yield_now().await;
+        }
+
+        // a later sync starts a new run
+        let output = runs
+            .run(URL, SyncOptions::default(), |_, _| async { "done" })
+            .await;
+        assert_eq!(output, Some("done"));
+    }
+}
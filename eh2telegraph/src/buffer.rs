@@ -1,7 +1,17 @@
+use std::sync::Arc;
+
+use once_cell::sync::OnceCell;
+use tokio::sync::{Semaphore, TryAcquireError};
+
+use crate::config;
+
+const CONFIG_KEY: &str = "sync";
+
 /// ImageBuffer for upload in batch. ceshi
 pub struct ImageBuffer<T> {
     buf: Vec<T>,
     size: usize,
+    permit: MemoryPermit,
 }
 
 impl<T> Default for ImageBuffer<T> {
@@ -10,6 +20,7 @@ impl<T> Default for ImageBuffer<T> {
         Self {
             buf: Vec::new(),
             size: 0,
+            permit: MemoryPermit::default(),
         }
     }
 }
@@ -28,23 +39,27 @@ where
         Self {
             buf: Vec::with_capacity(n),
             size: 0,
+            permit: MemoryPermit::default(),
         }
     }
 
+    /// Push data with its memory permit, which is held until the buffer is swapped.
     #[inline]
-    pub fn push(&mut self, data: T) {
+    pub fn push(&mut self, data: T, permit: MemoryPermit) {
         self.size += data.size();
         self.buf.push(data);
+        self.permit.merge(permit);
     }
 
+    /// Take all data and their memory permit.
     #[inline]
-    pub fn swap(&mut self) -> (Vec<T>, usize) {
+    pub fn swap(&mut self) -> (Vec<T>, usize, MemoryPermit) {
         let mut out = Vec::with_capacity(self.buf.len() * 2);
         std::mem::swap(&mut self.buf, &mut out);
 
         let mut size = 0;
         std::mem::swap(&mut self.size, &mut size);
-        (out, size)
+        (out, size, std::mem::take(&mut self.permit))
     }
 
     #[inline]
@@ -66,6 +81,7 @@ where
     pub fn clear(&mut self) {
         self.size = 0;
         self.buf.clear();
+        self.permit = MemoryPermit::default();
     }
 }
 
@@ -93,3 +109,160 @@ impl<const N: usize> DataSized for Box<[u8; N]> {
         N
     }
 }
+
+#[derive(serde::Deserialize, Default)]
+struct SyncConfig {
+    /// Max bytes of images held by all syncs, in MiB.
+    memory_budget_mb: Option<usize>,
+}
+
+static GLOBAL_BUDGET: OnceCell<MemoryBudget> = OnceCell::new();
+
+/// Byte semaphore bounding the image data held in memory.
+#[derive(Debug, Clone)]
+pub struct MemoryBudget {
+    sem: Arc<Semaphore>,
+    total: usize,
+}
+
+impl MemoryBudget {
+    pub fn new(total: usize) -> Self {
+        let total = total.clamp(1, Semaphore::MAX_PERMITS);
+        Self {
+            sem: Arc::new(Semaphore::new(total)),
+            total,
+        }
+    }
+
+    /// Set the global budget if `sync.memory_budget_mb` is configured.
+    pub fn init_from_config() -> anyhow::Result<()> {
+        let config: SyncConfig = config::parse(CONFIG_KEY)?.unwrap_or_default();
+        if let Some(mb) = config.memory_budget_mb {
+            let _ = GLOBAL_BUDGET.set(Self::new(mb.saturating_mul(1024 * 1024)));
+        }
+        Ok(())
+    }
+
+    pub fn global() -> Option<&'static Self> {
+        GLOBAL_BUDGET.get()
+    }
+
+    // a single item larger than the budget takes the whole budget
+    fn permits(&self, bytes: usize) -> u32 {
+        bytes.min(self.total).min(u32::MAX as usize) as u32
+    }
+
+    /// Wait until bytes are available.
+    pub async fn acquire(&self, bytes: usize) -> MemoryPermit {
+        let permits = self.permits(bytes);
+        self.sem
+            .acquire_many(permits)
+            .await
+            .expect("memory budget semaphore closed")
+            .forget();
+        self.permit(permits)
+    }
+
+    /// Take bytes if they are available now.
+    pub fn try_acquire(&self, bytes: usize) -> Option<MemoryPermit> {
+        let permits = self.permits(bytes);
+        match self.sem.try_acquire_many(permits) {
+            Ok(permit) => permit.forget(),
+            Err(TryAcquireError::NoPermits) => return None,
+            Err(TryAcquireError::Closed) => panic!("memory budget semaphore closed"),
+        }
+        Some(self.permit(permits))
+    }
+
+    fn permit(&self, permits: u32) -> MemoryPermit {
+        MemoryPermit {
+            sem: Some(self.sem.clone()),
+            bytes: permits as usize,
+        }
+    }
+
+    /// Bytes available now.
+    pub fn available(&self) -> usize {
+        self.sem.available_permits()
+    }
+
+    pub fn total(&self) -> usize {
+        self.total
+    }
+}
+
+/// Bytes taken from a memory budget, released on drop.
+/// The default permit takes nothing.
+#[derive(Debug, Default)]
+pub struct MemoryPermit {
+    sem: Option<Arc<Semaphore>>,
+    bytes: usize,
+}
+
+impl MemoryPermit {
+    /// Merge a permit of the same budget.
+    pub fn merge(&mut self, mut other: Self) {
+        if self.sem.is_none() {
+            self.sem = other.sem.take();
+        }
+        self.bytes += std::mem::take(&mut other.bytes);
+    }
+
+    /// Keep at most bytes and release the rest, so a permit reserved before
+    /// loading can be cut to the real size.
+    pub fn shrink(&mut self, bytes: usize) {
+        if let Some(sem) = self.sem.as_ref().filter(|_| bytes < self.bytes) {
+            sem.add_permits(self.bytes - bytes);
+            self.bytes = bytes;
+        }
+    }
+
+    pub fn bytes(&self) -> usize {
+        self.bytes
+    }
+}
+
+impl Drop for MemoryPermit {
+    fn drop(&mut self) {
+        if let Some(sem) = self.sem.as_ref() {
+            sem.add_permits(self.bytes);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{ImageBuffer, MemoryBudget};
+
+    #[tokio::test]
+    async fn memory_budget() {
+        let budget = MemoryBudget::new(10);
+        let mut buffer = ImageBuffer::new();
+        buffer.push(vec![0u8; 6], budget.acquire(6).await);
+        assert!(budget.try_acquire(6).is_none());
+        buffer.push(vec![0u8; 4], budget.try_acquire(4).unwrap());
+        assert_eq!(budget.available(), 0);
+
+        // too large item takes the whole budget
+        let waiting = tokio::spawn(async move {
+            let permit = budget.acquire(100).await;
+            drop(permit);
+            budget.available()
+        });
+        let (data, size, permit) = buffer.swap();
+        assert_eq!((data.len(), size), (2, 10));
+        assert_eq!(permit.bytes(), 10);
+        drop(permit);
+        assert_eq!(waiting.await.unwrap(), 10);
+
+        // a reserved permit is cut to the real size
+        let budget = MemoryBudget::new(10);
+        let mut permit = budget.acquire(8).await;
+        permit.shrink(3);
+        assert_eq!((permit.bytes(), budget.available()), (3, 7));
+        permit.shrink(5);
+        assert_eq!(budget.available(), 7);
+        drop(permit);
+        assert_eq!(budget.available(), 10);
+    }
+}
@@ -0,0 +1,353 @@
+//! Per-upstream circuit breaker.
+//! A breaker opens when too many recent requests to the upstream failed, and
+//! new requests fail fast until OPEN_DURATION passes. Then it is half-open and
+//! a single probe request is allowed, which closes or reopens the breaker.
+use std::{
+    collections::{HashMap, VecDeque},
+    fmt,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use once_cell::sync::Lazy;
+use parking_lot::Mutex;
+
+use crate::stream::AsyncStream;
+
+// Keep WINDOW recent results, and open when at least MIN_REQUESTS are recorded
+// and the error ratio reaches ERROR_RATIO.
+const WINDOW: usize = 40;
+const MIN_REQUESTS: usize = 10;
+const ERROR_RATIO: f64 = 0.6;
+const OPEN_DURATION: Duration = Duration::from_secs(120);
+
+static BREAKERS: Lazy<Mutex<HashMap<String, Arc<CircuitBreaker>>>> = Lazy::new(Default::default);
+
+/// Whether the error means the site is unavailable, which are network errors
+/// and 5xx responses. Errors like removed galleries do not count.
+pub fn is_site_failure(e: &anyhow::Error) -> bool {
+    e.chain()
+        .filter_map(|e| e.downcast_ref::<reqwest::Error>())
+        .any(|e| match e.status() {
+            Some(status) => status.is_server_error(),
+            None => e.is_connect() || e.is_timeout() || e.is_request() || e.is_body(),
+        })
+}
+
+/// Get the global breaker of the upstream.
+pub fn get(upstream: &str) -> Arc<CircuitBreaker> {
+    BREAKERS
+        .lock()
+        .entry(upstream.to_string())
+        .or_insert_with(|| Arc::new(CircuitBreaker::new(upstream)))
+        .clone()
+}
+
+/// Status of all global breakers, sorted by upstream.
+pub fn status() -> Vec<(String, BreakerStatus)> {
+    let mut status = BREAKERS
+        .lock()
+        .iter()
+        .map(|(name, b)| (name.clone(), b.status()))
+        .collect::<Vec<_>>();
+    status.sort_unstable_by(|a, b| a.0.cmp(&b.0));
+    status
+}
+
+#[derive(thiserror::Error, Debug)]
+#[error("site {upstream} is unavailable now, please retry after {} seconds", retry_after.as_secs() + 1)]
+pub struct SiteUnavailable {
+    pub upstream: String,
+    pub retry_after: Duration,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum State {
+    Closed,
+    Open { until: Instant },
+    HalfOpen { probing: bool },
+}
+
+#[derive(Debug)]
+struct Inner {
+    state: State,
+    // true means failure
+    results: VecDeque<bool>,
+}
+
+#[derive(Debug)]
+pub struct CircuitBreaker {
+    upstream: String,
+    inner: Mutex<Inner>,
+}
+
+/// Status of a breaker for display.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BreakerStatus {
+    Closed { failed: usize, total: usize },
+    Open { retry_after: Duration },
+    HalfOpen,
+}
+
+impl fmt::Display for BreakerStatus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Closed { failed, total } => write!(f, "closed, {failed}/{total} failed"),
+            Self::Open { retry_after } => write!(f, "open, retry in {}s", retry_after.as_secs()),
+            Self::HalfOpen => write!(f, "half-open"),
+        }
+    }
+}
+
+impl CircuitBreaker {
+    pub fn new(upstream: &str) -> Self {
+        Self {
+            upstream: upstream.to_string(),
+            inner: Mutex::new(Inner {
+                state: State::Closed,
+                results: VecDeque::with_capacity(WINDOW),
+            }),
+        }
+    }
+
+    /// Check if a request is allowed. The result should be reported with the permit.
+    pub fn acquire(self: &Arc<Self>) -> Result<BreakerPermit, SiteUnavailable> {
+        let mut inner = self.inner.lock();
+        let probe = match inner.state {
+            State::Closed => false,
+            State::Open { until } => {
+                let now = Instant::now();
+                if until > now {
+                    return Err(self.unavailable(until - now));
+                }
+                tracing::info!("[breaker] {} is half-open, probing", self.upstream);
+                inner.state = State::HalfOpen { probing: true };
+                true
+            }
+            State::HalfOpen { probing: false } => {
+                inner.state = State::HalfOpen { probing: true };
+                true
+            }
+            State::HalfOpen { probing: true } => return Err(self.unavailable(Duration::ZERO)),
+        };
+        Ok(BreakerPermit {
+            breaker: self.clone(),
+            probe,
+            reported: false,
+        })
+    }
+
+    pub fn status(&self) -> BreakerStatus {
+        let inner = self.inner.lock();
+        match inner.state {
+            State::Closed => BreakerStatus::Closed {
+                failed: inner.results.iter().filter(|f| **f).count(),
+                total: inner.results.len(),
+            },
+            State::Open { until } => BreakerStatus::Open {
+                retry_after: until.saturating_duration_since(Instant::now()),
+            },
+            State::HalfOpen { .. } => BreakerStatus::HalfOpen,
+        }
+    }
+
+    fn unavailable(&self, retry_after: Duration) -> SiteUnavailable {
+        SiteUnavailable {
+            upstream: self.upstream.clone(),
+            retry_after,
+        }
+    }
+
+    fn report(&self, probe: bool, success: bool) {
+        let mut inner = self.inner.lock();
+        match inner.state {
+            State::HalfOpen { .. } if probe => {
+                if success {
+                    tracing::info!("[breaker] {} is closed", self.upstream);
+                    inner.state = State::Closed;
+                    inner.results.clear();
+                } else {
+                    tracing::warn!("[breaker] {} probe failed, reopen", self.upstream);
+                    inner.state = State::Open {
+                        until: Instant::now() + OPEN_DURATION,
+                    };
+                }
+            }
+            State::Closed => {
+                if inner.results.len() >= WINDOW {
+                    inner.results.pop_front();
+                }
+                inner.results.push_back(!success);
+                let failed = inner.results.iter().filter(|f| **f).count();
+                let total = inner.results.len();
+                if total >= MIN_REQUESTS && failed as f64 >= total as f64 * ERROR_RATIO {
+                    tracing::warn!(
+                        "[breaker] {} is open, {failed}/{total} requests failed",
+                        self.upstream
+                    );
+                    inner.state = State::Open {
+                        until: Instant::now() + OPEN_DURATION,
+                    };
+                    inner.results.clear();
+                }
+            }
+            // results of requests started before opening are ignored
+            _ => (),
+        }
+    }
+
+    fn release_probe(&self) {
+        let mut inner = self.inner.lock();
+        if inner.state == (State::HalfOpen { probing: true }) {
+            inner.state = State::HalfOpen { probing: false };
+        }
+    }
+}
+
+/// A request allowed by the breaker.
+/// If it is dropped without reporting, the result is not counted.
+#[derive(Debug)]
+pub struct BreakerPermit {
+    breaker: Arc<CircuitBreaker>,
+    probe: bool,
+    reported: bool,
+}
+
+impl BreakerPermit {
+    pub fn report(mut self, success: bool) {
+        self.reported = true;
+        self.breaker.report(self.probe, success);
+    }
+
+    /// Report the result, errors not caused by the site are not counted.
+    pub fn report_result<T>(self, result: &anyhow::Result<T>) {
+        match result {
+            Ok(_) => self.report(true),
+            Err(e) if is_site_failure(e) => self.report(false),
+            Err(_) => (),
+        }
+    }
+}
+
+impl Drop for BreakerPermit {
+    fn drop(&mut self) {
+        if self.probe && !self.reported {
+            self.breaker.release_probe();
+        }
+    }
+}
+
+/// Stream wrapper that reports item results to the breaker, and fails items
+/// fast when the breaker is open.
+pub struct BreakerStream<S> {
+    inner: S,
+    breaker: Arc<CircuitBreaker>,
+}
+
+impl<S> BreakerStream<S> {
+    pub fn new(inner: S, breaker: Arc<CircuitBreaker>) -> Self {
+        Self { inner, breaker }
+    }
+}
+
+impl<S, T, E> AsyncStream for BreakerStream<S>
+where
+    S: AsyncStream<Item = Result<T, E>>,
+    E: Into<anyhow::Error>,
+{
+    type Item = anyhow::Result<T>;
+
+    type Future = impl std::future::Future<Output = Self::Item>;
+
+    fn next(&mut self) -> Option<Self::Future> {
+        let fut = self.inner.next()?;
+        let breaker = self.breaker.clone();
+        Some(async move {
+            let permit = breaker.acquire()?;
+            let result = fut.await.map_err(Into::into);
+            permit.report_result(&result);
+            result
+        })
+    }
+
+    #[inline]
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.inner.size_hint()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{sync::Arc, time::Instant};
+
+    use super::{is_site_failure, BreakerStatus, CircuitBreaker, State, MIN_REQUESTS};
+
+    #[test]
+    fn open_and_probe() {
+        let breaker = Arc::new(CircuitBreaker::new("test"));
+        for _ in 0..MIN_REQUESTS - 1 {
+            breaker.acquire().unwrap().report(false);
+        }
+        assert!(matches!(breaker.status(), BreakerStatus::Closed { .. }));
+        breaker.acquire().unwrap().report(false);
+        assert!(matches!(breaker.status(), BreakerStatus::Open { .. }));
+        assert!(breaker.acquire().is_err());
+
+        // expire the open state
+        breaker.inner.lock().state = State::Open {
+            until: Instant::now(),
+        };
+        let probe = breaker.acquire().unwrap();
+        assert!(breaker.acquire().is_err());
+        // dropped probe does not count
+        drop(probe);
+        let probe = breaker.acquire().unwrap();
+        probe.report(false);
+        assert!(matches!(breaker.status(), BreakerStatus::Open { .. }));
+
+        breaker.inner.lock().state = State::Open {
+            until: Instant::now(),
+        };
+        breaker.acquire().unwrap().report(true);
+        assert_eq!(
+            breaker.status(),
+            BreakerStatus::Closed {
+                failed: 0,
+                total: 0
+            }
+        );
+    }
+
+    #[tokio::test]
+    async fn site_failure() {
+        let status_error = |status: u16| {
+            let response = http::Response::builder().status(status).body("").unwrap();
+            let e = reqwest::Response::from(response)
+                .error_for_status()
+                .unwrap_err();
+            anyhow::Error::from(e).context("fetch gallery")
+        };
+        assert!(is_site_failure(&status_error(503)));
+        assert!(!is_site_failure(&status_error(404)));
+        assert!(!is_site_failure(&anyhow::anyhow!("gallery removed")));
+
+        let e = reqwest::get("http://127.0.0.1:1/").await.unwrap_err();
+        assert!(is_site_failure(&e.into()));
+
+        // user errors are not counted
+        let breaker = Arc::new(CircuitBreaker::new("test"));
+        for _ in 0..MIN_REQUESTS {
+            breaker
+                .acquire()
+                .unwrap()
+                .report_result::<()>(&Err(anyhow::anyhow!("invalid path")));
+        }
+        assert_eq!(
+            breaker.status(),
+            BreakerStatus::Closed {
+                failed: 0,
+                total: 0
+            }
+        );
+    }
+}
@@ -1,38 +1,41 @@
 /// nhentai collector.
 /// Host matching: e-hentai.org
 use crate::{
-    http_client::{GhostClient, GhostClientBuilder},
+    http_client::GhostClientBuilder,
+    outbound::{OutboundClient, OutboundPolicy},
     stream::AsyncStream,
-    util::match_first_group,
     util::{get_bytes, get_string},
 };
 use again::RetryPolicy;
 use ipnet::Ipv6Net;
-use regex::Regex;
 use reqwest::header;
 
 use std::time::Duration;
 
 use super::{
-    utils::paged::{PageFormatter, PageIndicator, Paged},
+    utils::{
+        gallery::{GalleryLayout, GalleryPageIndicator},
+        paged::{Paged, PagedLinks},
+    },
     AlbumMeta, Collector, ImageData, ImageMeta,
 };
 
 lazy_static::lazy_static! {
-    static ref PAGE_RE: Regex = Regex::new(r#"<a href="(https://e-hentai\.org/s/\w+/[\w-]+)">"#).unwrap();
-    static ref IMG_RE: Regex = Regex::new(r#"<img id="img" src="(.*?)""#).unwrap();
-    static ref TITLE_RE: Regex = Regex::new(r#"<h1 id="gn">(.*?)</h1>"#).unwrap();
-
     static ref RETRY_POLICY: RetryPolicy = RetryPolicy::fixed(Duration::from_millis(200))
         .with_max_retries(5)
         .with_jitter(true);
 }
 const TIMEOUT: Duration = Duration::from_secs(30);
+const SITE: &str = "e-hentai";
+const LAYOUT: GalleryLayout = GalleryLayout {
+    gallery: "e-hentai gallery page",
+    image: "e-hentai image page",
+};
 
 #[derive(Debug, Clone, Default)]
 pub struct EHCollector {
-    client: GhostClient,
-    raw_client: reqwest::Client,
+    client: OutboundClient,
+    raw_client: OutboundClient,
 }
 
 impl EHCollector {
@@ -47,8 +50,13 @@ impl EHCollector {
             client: GhostClientBuilder::default()
                 .with_default_headers(request_headers)
                 .with_cf_resolve(&["e-hentai.org"])
-                .build(prefix),
-            raw_client: reqwest::Client::builder().timeout(TIMEOUT).build().unwrap(),
+                .build(prefix)
+                .into(),
+            raw_client: reqwest::Client::builder()
+                .timeout(TIMEOUT)
+                .build()
+                .unwrap()
+                .into(),
         }
     }
 
@@ -59,16 +67,30 @@ impl EHCollector {
             header::HeaderValue::from_str("nw=1").unwrap(),
         );
 
-        Ok(Self {
-            client: GhostClientBuilder::default()
+        let policy = OutboundPolicy::from_config(SITE, OutboundPolicy::Ghost)?;
+        let client = policy.build(
+            GhostClientBuilder::default()
                 .with_default_headers(request_headers)
-                .with_cf_resolve(&["e-hentai.org"])
-                .build_from_config()?,
-            raw_client: reqwest::Client::builder().timeout(TIMEOUT).build().unwrap(),
+                .with_cf_resolve(&["e-hentai.org"]),
+        )?;
+        Ok(Self {
+            raw_client: policy.build_image(&client)?,
+            client,
         })
     }
 }
 
+#[cfg(test)]
+impl EHCollector {
+    pub(crate) fn from_fixture(fixture: &crate::http_replay::Fixture) -> Self {
+        let live = || EHCollector::new(None);
+        Self {
+            client: fixture.client(|| live().client),
+            raw_client: fixture.client(|| live().raw_client),
+        }
+    }
+}
+
 impl Collector for EHCollector {
     type FetchError = anyhow::Error;
     type StreamError = anyhow::Error;
@@ -99,41 +121,39 @@ impl Collector for EHCollector {
 
         // clone client to force changing ip
         let client = self.client.clone();
-        let mut paged = Paged::new(0, EHPageIndicator { base: url.clone() });
-        let gallery_pages = paged.pages(&client).await?;
-
-        // Since paged returns at least one page, we can safely get it.
-        let title = match_first_group(&TITLE_RE, &gallery_pages[0])
-            .map(|s| s.to_string())
-            .unwrap_or_else(|| format!("e-hentai-{album_id}"));
-
-        let mut image_page_links = Vec::new();
-        for gallery_page in gallery_pages.iter() {
-            PAGE_RE.captures_iter(gallery_page).for_each(|c| {
-                let matching = c.get(1).expect("regexp is matched but no group 1 found");
-                image_page_links.push(matching.as_str().to_string());
-            });
-        }
-
-        if image_page_links.is_empty() {
-            return Err(anyhow::anyhow!(
-                "invalid url, maybe resource has been deleted."
-            ));
-        }
+        let paged = Paged::new(
+            0,
+            GalleryPageIndicator {
+                base: url.clone(),
+                layout: LAYOUT,
+            },
+        );
+        let (first_page, links) = paged
+            .links(client.clone(), |c| LAYOUT.parse_links(c))
+            .await?;
+        let (title, total) = LAYOUT.parse_info(&first_page);
+        let title = title.unwrap_or_else(|| format!("e-hentai-{album_id}"));
+        let total = match total {
+            Some(n) => n,
+            None => links.all().await?.len(),
+        };
 
         Ok((
             AlbumMeta {
                 link: url,
                 name: title,
-                class: None,
+                class: LAYOUT.parse_category(&first_page),
                 description: None,
                 authors: None,
-                tags: None,
+                tags: Some(LAYOUT.parse_tags(&first_page)),
+                version: Some(format!("{album_id}/{album_token}")),
             },
             EHImageStream {
                 client,
                 raw_client: self.raw_client.clone(),
-                image_page_links: image_page_links.into_iter(),
+                links,
+                next: 0,
+                total,
             },
         ))
     }
@@ -141,24 +161,25 @@ impl Collector for EHCollector {
 
 #[derive(Debug)]
 pub struct EHImageStream {
-    client: GhostClient,
-    raw_client: reqwest::Client,
-    image_page_links: std::vec::IntoIter<String>,
+    client: OutboundClient,
+    raw_client: OutboundClient,
+    links: PagedLinks,
+    next: usize,
+    total: usize,
 }
 
 impl EHImageStream {
     async fn load_image(
-        client: &GhostClient,
-        raw_client: &reqwest::Client,
+        client: &OutboundClient,
+        raw_client: &OutboundClient,
         link: String,
     ) -> anyhow::Result<(ImageMeta, ImageData)> {
         let content = RETRY_POLICY
             .retry(|| async { get_string(client, &link).await })
             .await?;
-        let img_url = match_first_group(&IMG_RE, &content)
-            .ok_or_else(|| anyhow::anyhow!("unable to find image in page"))?;
+        let img_url = LAYOUT.parse_image(&content)?;
         let image_data = RETRY_POLICY
-            .retry(|| async { get_bytes(raw_client, img_url).await })
+            .retry(|| async { get_bytes(raw_client, &img_url).await })
             .await?;
 
         tracing::trace!(
@@ -167,7 +188,7 @@ impl EHImageStream {
         );
         let meta = ImageMeta {
             id: link,
-            url: img_url.to_string(),
+            url: img_url,
             description: None,
         };
         Ok((meta, image_data))
@@ -180,74 +201,51 @@ impl AsyncStream for EHImageStream {
     type Future = impl std::future::Future<Output = Self::Item>;
 
     fn next(&mut self) -> Option<Self::Future> {
-        let link = self.image_page_links.next()?;
+        if self.next >= self.total {
+            return None;
+        }
+        let idx = self.next;
+        self.next += 1;
+        let links = self.links.clone();
         let client = self.client.clone();
         let raw_client = self.raw_client.clone();
-        Some(async move { Self::load_image(&client, &raw_client, link).await })
+        Some(async move {
+            let link = links
+                .get(idx)
+                .await?
+                .ok_or_else(|| anyhow::anyhow!("image page {idx} not found"))?;
+            Self::load_image(&client, &raw_client, link).await
+        })
     }
 
     #[inline]
     fn size_hint(&self) -> (usize, Option<usize>) {
-        self.image_page_links.size_hint()
-    }
-}
-
-struct EHPageIndicator {
-    base: String,
-}
-
-impl PageFormatter for EHPageIndicator {
-    fn format_n(&self, n: usize) -> String {
-        format!("{}/?p={}", self.base, n)
-    }
-}
-
-impl PageIndicator for EHPageIndicator {
-    fn is_last_page(&self, content: &str, next_page: usize) -> bool {
-        let html = format!(
-            "<a href=\"{}/?p={}\" onclick=\"return false\">",
-            self.base, next_page
-        );
-        !content.contains(&html)
+        let n = self.total - self.next;
+        (n, Some(n))
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::http_replay::Fixture;
 
-    #[ignore]
     #[tokio::test]
-    async fn demo() {
-        let collector = EHCollector {
-            raw_client: Default::default(),
-            client: Default::default(),
-        };
+    async fn replay_fetch() {
+        let fixture = Fixture::open("e_hentai");
+        let collector = EHCollector::from_fixture(&fixture);
         let (album, mut image_stream) = collector
             .fetch("/g/2122174/fd2525031e".to_string())
             .await
             .unwrap();
-        println!("album: {album:?}");
+        assert_eq!(album.name, "Fixture Album");
+        assert_eq!(image_stream.size_hint().0, 2);
 
-        let maybe_first_image = image_stream.next().unwrap().await;
-        if let Ok((meta, data)) = maybe_first_image {
-            println!("first image meta: {meta:?}");
-            println!("first image data length: {}", data.len());
+        while let Some(fut) = image_stream.next() {
+            let (meta, data) = fut.await.unwrap();
+            assert!(meta.url.starts_with("https://ehgt.org/fixture/"));
+            assert_eq!(data.as_ref(), &[0xff, 0xd8, 0xff]);
         }
-    }
-
-    #[ignore]
-    #[test]
-    fn regex_match() {
-        // test page: https://e-hentai.org/g/2122174/fd2525031e
-        let r = Regex::new(r#"<a href="(https://e-hentai\.org/s/\w+/[\w-]+)">"#).unwrap();
-        let h = r#"<div class="gdtm" style="height:170px"><div style="margin:1px auto 0; width:100px; height:140px; background:transparent url(https://ehgt.org/m/002122/2122174-00.jpg) -600px 0 no-repeat"><a href="https://e-hentai.org/s/bd2b37d829/2122174-7"><img alt="007" title="Page 7: 2.png" src="https://ehgt.org/g/blank.gif" style="width:100px; height:139px; margin:-1px 0 0 -1px" /></a></div></div><div class="gdtm" style="height:170px"><div style="margin:1px auto 0; width:100px; height:100px; background:transparent url(https://ehgt.org/m/002122/2122174-00.jpg) -700px 0 no-repeat"><a href="https://e-hentai.org/s/4ca72f757d/2122174-8"><img alt="008" title="Page 8: 3.png" src="https://ehgt.org/g/blank.gif" style="width:100px; height:99px; margin:-1px 0 0 -1px" />"#;
-
-        let mut iter = r.captures_iter(h);
-        let first = iter.next().unwrap();
-        println!("{}", first.get(1).unwrap().as_str());
-
-        let second = iter.next().unwrap();
-        println!("{}", second.get(1).unwrap().as_str());
+        assert_eq!(fixture.missed(), 0);
     }
 }
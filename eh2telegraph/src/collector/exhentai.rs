@@ -2,38 +2,42 @@ use std::time::Duration;
 
 use again::RetryPolicy;
 use ipnet::Ipv6Net;
-use regex::Regex;
 use reqwest::header::{self, HeaderMap};
 use serde::Deserialize;
 
 use crate::{
     config,
-    http_client::{GhostClient, GhostClientBuilder},
+    http_client::GhostClientBuilder,
+    outbound::{OutboundClient, OutboundPolicy},
     stream::AsyncStream,
-    util::{get_bytes, get_string, match_first_group},
+    util::{get_bytes, get_string},
 };
 
 use super::{
-    utils::paged::{PageFormatter, PageIndicator, Paged},
+    utils::{
+        gallery::{GalleryLayout, GalleryPageIndicator},
+        paged::{Paged, PagedLinks},
+    },
     AlbumMeta, Collector, ImageData, ImageMeta,
 };
 
 lazy_static::lazy_static! {
-    static ref PAGE_RE: Regex = Regex::new(r#"<a href="(https://exhentai\.org/s/\w+/[\w-]+)">"#).unwrap();
-    static ref IMG_RE: Regex = Regex::new(r#"<img id="img" src="(.*?)""#).unwrap();
-    static ref TITLE_RE: Regex = Regex::new(r#"<h1 id="gn">(.*?)</h1>"#).unwrap();
-
     static ref RETRY_POLICY: RetryPolicy = RetryPolicy::fixed(Duration::from_millis(200))
         .with_max_retries(5)
         .with_jitter(true);
 }
 const CONFIG_KEY: &str = "exhentai";
 const TIMEOUT: Duration = Duration::from_secs(30);
+const SITE: &str = "exhentai";
+const LAYOUT: GalleryLayout = GalleryLayout {
+    gallery: "exhentai gallery page",
+    image: "exhentai image page",
+};
 
 #[derive(Debug, Clone)]
 pub struct EXCollector {
-    ghost_client: GhostClient,
-    raw_client: reqwest::Client,
+    ghost_client: OutboundClient,
+    raw_client: OutboundClient,
 }
 
 #[derive(Debug, Deserialize)]
@@ -67,28 +71,47 @@ impl EXCollector {
             ghost_client: GhostClientBuilder::default()
                 .with_default_headers(config.build_header())
                 .with_cf_resolve(&["exhentai.org"])
-                .build(prefix),
-            raw_client: reqwest::Client::builder().timeout(TIMEOUT).build().unwrap(),
+                .build(prefix)
+                .into(),
+            raw_client: reqwest::Client::builder()
+                .timeout(TIMEOUT)
+                .build()
+                .unwrap()
+                .into(),
         })
     }
 
     pub fn new_from_config() -> anyhow::Result<Self> {
         let config: ExConfig = config::parse(CONFIG_KEY)?
             .ok_or_else(|| anyhow::anyhow!("exhentai config(key: exhentai) not found"))?;
-        Ok(Self {
-            ghost_client: GhostClientBuilder::default()
+        let policy = OutboundPolicy::from_config(SITE, OutboundPolicy::Ghost)?;
+        let ghost_client = policy.build(
+            GhostClientBuilder::default()
                 .with_default_headers(config.build_header())
-                .with_cf_resolve(&["exhentai.org"])
-                .build_from_config()?,
-            raw_client: reqwest::Client::builder().timeout(TIMEOUT).build().unwrap(),
+                .with_cf_resolve(&["exhentai.org"]),
+        )?;
+        Ok(Self {
+            raw_client: policy.build_image(&ghost_client)?,
+            ghost_client,
         })
     }
 
-    pub fn get_client(&self) -> reqwest::Client {
+    pub fn get_client(&self) -> OutboundClient {
         self.raw_client.clone()
     }
 }
 
+#[cfg(test)]
+impl EXCollector {
+    pub(crate) fn from_fixture(fixture: &crate::http_replay::Fixture) -> Self {
+        let live = || EXCollector::new_from_config().expect("exhentai config is required");
+        Self {
+            ghost_client: fixture.client(|| live().ghost_client),
+            raw_client: fixture.client(|| live().raw_client),
+        }
+    }
+}
+
 impl Collector for EXCollector {
     type FetchError = anyhow::Error;
     type StreamError = anyhow::Error;
@@ -117,45 +140,44 @@ impl Collector for EXCollector {
         let url = format!("https://exhentai.org/g/{album_id}/{album_token}");
         tracing::info!("[exhentai] process {url}");
 
-        let mut paged = Paged::new(0, EXPageIndicator { base: url.clone() });
-        let gallery_pages = paged.pages(&self.ghost_client).await.map_err(|e| {
-            tracing::error!("[exhentai] load page failed: {e:?}");
-            e
-        })?;
-        tracing::info!("[exhentai] pages loaded for {album_id}/{album_token}");
-
-        // Since paged returns at least one page, we can safely get it.
-        let title = match_first_group(&TITLE_RE, &gallery_pages[0])
-            .map(|s| s.to_string())
-            .unwrap_or_else(|| format!("exhentai-{album_id}"));
-
-        let mut image_page_links = Vec::new();
-        for gallery_page in gallery_pages.iter() {
-            PAGE_RE.captures_iter(gallery_page).for_each(|c| {
-                let matching = c.get(1).expect("regexp is matched but no group 1 found");
-                image_page_links.push(matching.as_str().to_string());
-            });
-        }
-
-        if image_page_links.is_empty() {
-            return Err(anyhow::anyhow!(
-                "invalid url, maybe resource has been deleted, or our ip is blocked."
-            ));
-        }
+        let paged = Paged::new(
+            0,
+            GalleryPageIndicator {
+                base: url.clone(),
+                layout: LAYOUT,
+            },
+        );
+        let (first_page, links) = paged
+            .links(self.ghost_client.clone(), |c| LAYOUT.parse_links(c))
+            .await
+            .map_err(|e| {
+                tracing::error!("[exhentai] load page failed: {e:?}");
+                e
+            })?;
+        let (title, total) = LAYOUT.parse_info(&first_page);
+        let title = title.unwrap_or_else(|| format!("exhentai-{album_id}"));
+        let total = match total {
+            Some(n) => n,
+            None => links.all().await?.len(),
+        };
+        tracing::info!("[exhentai] {total} images found for {album_id}/{album_token}");
 
         Ok((
             AlbumMeta {
                 link: url,
                 name: title,
-                class: None,
+                class: LAYOUT.parse_category(&first_page),
                 description: None,
                 authors: None,
-                tags: None,
+                tags: Some(LAYOUT.parse_tags(&first_page)),
+                version: Some(format!("{album_id}/{album_token}")),
             },
             EXImageStream {
                 raw_client: self.raw_client.clone(),
                 ghost_client: self.ghost_client.clone(),
-                image_page_links: image_page_links.into_iter(),
+                links,
+                next: 0,
+                total,
             },
         ))
     }
@@ -163,24 +185,25 @@ impl Collector for EXCollector {
 
 #[derive(Debug)]
 pub struct EXImageStream {
-    raw_client: reqwest::Client,
-    ghost_client: GhostClient,
-    image_page_links: std::vec::IntoIter<String>,
+    raw_client: OutboundClient,
+    ghost_client: OutboundClient,
+    links: PagedLinks,
+    next: usize,
+    total: usize,
 }
 
 impl EXImageStream {
     async fn load_image(
-        ghost_client: GhostClient,
-        raw_client: reqwest::Client,
+        ghost_client: OutboundClient,
+        raw_client: OutboundClient,
         link: String,
     ) -> anyhow::Result<(ImageMeta, ImageData)> {
         let content = RETRY_POLICY
             .retry(|| async { get_string(&ghost_client, &link).await })
             .await?;
-        let img_url = match_first_group(&IMG_RE, &content)
-            .ok_or_else(|| anyhow::anyhow!("unable to find image in page"))?;
+        let img_url = LAYOUT.parse_image(&content)?;
         let image_data = RETRY_POLICY
-            .retry(|| async { get_bytes(&raw_client, img_url).await })
+            .retry(|| async { get_bytes(&raw_client, &img_url).await })
             .await?;
 
         tracing::trace!(
@@ -189,7 +212,7 @@ impl EXImageStream {
         );
         let meta = ImageMeta {
             id: link,
-            url: img_url.to_string(),
+            url: img_url,
             description: None,
         };
         Ok((meta, image_data))
@@ -202,92 +225,59 @@ impl AsyncStream for EXImageStream {
     type Future = impl std::future::Future<Output = Self::Item>;
 
     fn next(&mut self) -> Option<Self::Future> {
-        let link = self.image_page_links.next()?;
+        if self.next >= self.total {
+            return None;
+        }
+        let idx = self.next;
+        self.next += 1;
+        let links = self.links.clone();
         let ghost_client = self.ghost_client.clone();
         let raw_client = self.raw_client.clone();
-        Some(async move { Self::load_image(ghost_client, raw_client, link).await })
+        Some(async move {
+            let link = links
+                .get(idx)
+                .await?
+                .ok_or_else(|| anyhow::anyhow!("image page {idx} not found"))?;
+            Self::load_image(ghost_client, raw_client, link).await
+        })
     }
 
     #[inline]
     fn size_hint(&self) -> (usize, Option<usize>) {
-        self.image_page_links.size_hint()
-    }
-}
-
-struct EXPageIndicator {
-    base: String,
-}
-
-impl PageFormatter for EXPageIndicator {
-    fn format_n(&self, n: usize) -> String {
-        format!("{}/?p={}", self.base, n)
-    }
-}
-
-impl PageIndicator for EXPageIndicator {
-    fn is_last_page(&self, content: &str, next_page: usize) -> bool {
-        let html = format!(
-            "<a href=\"{}/?p={}\" onclick=\"return false\">",
-            self.base, next_page
-        );
-        !content.contains(&html)
+        let n = self.total - self.next;
+        (n, Some(n))
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::http_replay::Fixture;
 
-    #[ignore]
     #[tokio::test]
-    async fn demo() {
-        let config = ExConfig {
-            ipb_pass_hash: "balabala".to_string(),
-            ipb_member_id: "balabala".to_string(),
-            igneous: "balabala".to_string(),
-        };
-        println!("config {config:#?}");
-        let collector = EXCollector::new(&config, None).unwrap();
+    async fn replay_fetch() {
+        let fixture = Fixture::open("exhentai");
+        let collector = EXCollector::from_fixture(&fixture);
         let (album, mut image_stream) = collector
             .fetch("/g/2129939/01a6e086b9".to_string())
             .await
             .unwrap();
-        println!("album: {album:?}");
-
-        let maybe_first_image = image_stream.next().unwrap().await;
-        if let Ok((meta, data)) = maybe_first_image {
-            println!("first image meta: {meta:?}");
-            println!("first image data length: {}", data.len());
-        }
-    }
-
-    #[ignore]
-    #[tokio::test]
-    async fn invalid_url() {
-        let config = ExConfig {
-            ipb_pass_hash: "balabala".to_string(),
-            ipb_member_id: "balabala".to_string(),
-            igneous: "balabala".to_string(),
-        };
-        println!("config {config:#?}");
-        let collector = EXCollector::new(&config, None).unwrap();
-        let output = collector.fetch("/g/2129939/00000".to_string()).await;
-        assert!(output.is_err());
-        println!("output err {output:?}");
-    }
-
-    #[ignore]
-    #[test]
-    fn regex_match() {
-        // test page: https://exhentai.org/g/2122174/fd2525031e
-        let r = Regex::new(r#"<a href="(https://exhentai\.org/s/\w+/[\w-]+)">"#).unwrap();
-        let h = r#"<div class="gdtm" style="height:170px"><div style="margin:1px auto 0; width:100px; height:140px; background:transparent url(https://ehgt.org/m/002122/2122174-00.jpg) -600px 0 no-repeat"><a href="https://exhentai.org/s/bd2b37d829/2122174-7"><img alt="007" title="Page 7: 2.png" src="https://ehgt.org/g/blank.gif" style="width:100px; height:139px; margin:-1px 0 0 -1px" /></a></div></div><div class="gdtm" style="height:170px"><div style="margin:1px auto 0; width:100px; height:100px; background:transparent url(https://ehgt.org/m/002122/2122174-00.jpg) -700px 0 no-repeat"><a href="https://exhentai.org/s/4ca72f757d/2122174-8"><img alt="008" title="Page 8: 3.png" src="https://ehgt.org/g/blank.gif" style="width:100px; height:99px; margin:-1px 0 0 -1px" />"#;
-
-        let mut iter = r.captures_iter(h);
-        let first = iter.next().unwrap();
-        println!("{}", first.get(1).unwrap().as_str());
-
-        let second = iter.next().unwrap();
-        println!("{}", second.get(1).unwrap().as_str());
+        assert_eq!(album.name, "EX Fixture Album");
+        assert_eq!(album.class.as_deref(), Some("Manga"));
+        assert_eq!(album.tags.unwrap(), ["female:glasses"]);
+        assert_eq!(image_stream.size_hint().0, 1);
+
+        let (meta, data) = image_stream.next().unwrap().await.unwrap();
+        assert_eq!(meta.url, "https://ehgt.org/fixture/ex1.jpg");
+        assert_eq!(data.as_ref(), &[0xff, 0xd8, 0xff]);
+        assert!(image_stream.next().is_none());
+
+        // exhentai returns an empty page for invalid galleries
+        let err = collector
+            .fetch("/g/2129939/00000".to_string())
+            .await
+            .unwrap_err();
+        assert!(err.to_string().contains("empty gallery page"));
+        assert_eq!(fixture.missed(), 0);
     }
 }
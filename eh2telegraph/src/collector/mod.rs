@@ -32,6 +32,8 @@ pub struct AlbumMeta {
     pub description: Option<String>,
     pub authors: Option<Vec<String>>,
     pub tags: Option<Vec<String>>,
+    /// Source side version of the album, it changes when the gallery is updated.
+    pub version: Option<String>,
 }
 
 /// Generic collector.
@@ -87,6 +89,10 @@ impl Param<EXCollector> for Registry {
 }
 
 impl Registry {
+    pub fn new(eh: EHCollector, nh: NHCollector, ex: EXCollector) -> Self {
+        Self { eh, nh, ex }
+    }
+
     pub fn new_from_config() -> Self {
         Self {
             eh: EHCollector::new_from_config().expect("unable to build e-hentai collector"),
@@ -10,7 +10,8 @@ use serde::Deserialize;
 use std::time::Duration;
 
 use crate::{
-    http_client::{GhostClient, GhostClientBuilder},
+    http_client::{GhostClientBuilder, HttpRequestBuilder},
+    outbound::{OutboundClient, OutboundPolicy},
     stream::AsyncStream,
     util::get_bytes,
 };
@@ -18,6 +19,7 @@ use crate::{
 use super::{AlbumMeta, Collector, ImageData, ImageMeta};
 
 const NHAPI: &str = "https://nhapi.cat42.uk/gallery/";
+const SITE: &str = "nhentai";
 
 lazy_static::lazy_static! {
     static ref RETRY_POLICY: RetryPolicy = RetryPolicy::fixed(Duration::from_millis(200))
@@ -36,7 +38,7 @@ const NH_CDN_LIST: [&str; 5] = [
 
 #[derive(Debug, Clone, Default)]
 pub struct NHCollector {
-    client: GhostClient,
+    client: OutboundClient,
 }
 
 impl NHCollector {
@@ -44,12 +46,17 @@ impl NHCollector {
         Self {
             client: GhostClientBuilder::default()
                 .with_cf_resolve(&DOMAIN_LIST)
-                .build(None),
+                .build(None)
+                .into(),
         }
     }
 
     pub fn new_from_config() -> anyhow::Result<Self> {
-        Ok(Self::new())
+        // nhapi is not behind CloudFlare, so ipv6 prefix is not used by default.
+        let policy = OutboundPolicy::from_config(SITE, OutboundPolicy::Direct)?;
+        Ok(Self {
+            client: policy.build(GhostClientBuilder::default().with_cf_resolve(&DOMAIN_LIST))?,
+        })
     }
 }
 
@@ -122,6 +129,15 @@ impl ImageType {
 //     name: String,
 // }
 
+#[cfg(test)]
+impl NHCollector {
+    pub(crate) fn from_fixture(fixture: &crate::http_replay::Fixture) -> Self {
+        Self {
+            client: fixture.client(|| NHCollector::new().client),
+        }
+    }
+}
+
 impl Collector for NHCollector {
     type FetchError = anyhow::Error;
     type StreamError = anyhow::Error;
@@ -154,8 +170,7 @@ impl Collector for NHCollector {
         // clone client to force changing ip
         let client = self.client.clone();
         let album: NhAlbum = client
-            .get(&api_url)
-            .send()
+            .execute(client.get_builder(&api_url))
             .await
             .and_then(Response::error_for_status)?
             .json()
@@ -178,6 +193,7 @@ impl Collector for NHCollector {
                 description: None,
                 authors: None,
                 tags: None,
+                version: Some(album.media_id.clone()),
             },
             NHImageStream { client, image_urls },
         ))
@@ -220,12 +236,15 @@ impl ImageURL {
 
 #[derive(Debug)]
 pub struct NHImageStream {
-    client: GhostClient,
+    client: OutboundClient,
     image_urls: std::vec::IntoIter<ImageURL>,
 }
 
 impl NHImageStream {
-    async fn load_image(client: GhostClient, link: &str) -> anyhow::Result<(ImageMeta, ImageData)> {
+    async fn load_image(
+        client: OutboundClient,
+        link: &str,
+    ) -> anyhow::Result<(ImageMeta, ImageData)> {
         let image_data = RETRY_POLICY
             .retry(|| async { get_bytes(&client, link).await })
             .await?;
@@ -267,3 +286,34 @@ impl AsyncStream for NHImageStream {
         self.image_urls.size_hint()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::http_replay::Fixture;
+
+    #[tokio::test]
+    async fn replay_fetch() {
+        // images are loaded from a random CDN, so the fixture serves all of them
+        let fixture = Fixture::open("nhentai");
+        let collector = NHCollector::from_fixture(&fixture);
+        let (album, mut image_stream) = collector.fetch("/g/333678".to_string()).await.unwrap();
+        assert_eq!(album.name, "NH Fixture Album");
+        assert_eq!(album.link, "https://nhentai.net/g/333678");
+        assert_eq!(album.version.as_deref(), Some("1781962"));
+        assert_eq!(image_stream.size_hint().0, 2);
+
+        let mut urls = Vec::new();
+        while let Some(fut) = image_stream.next() {
+            let (meta, data) = fut.await.unwrap();
+            assert_eq!(data.as_ref(), &[0xff, 0xd8, 0xff]);
+            urls.push(meta.url);
+        }
+        assert!(urls[0].ends_with("/galleries/1781962/1.jpg"));
+        assert!(urls[1].ends_with("/galleries/1781962/2.png"));
+        assert_eq!(fixture.missed(), 0);
+
+        let err = collector.fetch("/g/".to_string()).await.unwrap_err();
+        assert!(err.to_string().contains("invalid input path"));
+    }
+}
@@ -0,0 +1,177 @@
+/// Parsers of gallery and image pages shared by e-hentai and exhentai, whose
+/// pages have the same layout.
+use crate::html::{Css, Document};
+
+use super::paged::{PageFormatter, PageIndicator};
+
+lazy_static::lazy_static! {
+    static ref TITLE: Css = Css::new("h1#gn");
+    static ref THUMBNAILS: Css = Css::new("#gdt");
+    static ref PAGE_LINKS: Css = Css::new(r#"#gdt a[href*="hentai.org/s/"]"#);
+    static ref REMOVED_NOTICE: Css = Css::new("div.d");
+    static ref IMAGE_COUNT: Css = Css::new("p.gpc");
+    static ref TAGS: Css = Css::new(r#"#taglist a[id^="ta_"]"#);
+    static ref CATEGORY: Css = Css::new("#gdc div");
+    static ref PAGINATION: Css = Css::new("table.ptt td");
+    static ref IMG: Css = Css::new("img#img");
+}
+
+/// Page names of a site, which are shown in layout changed errors.
+#[derive(Debug, Clone, Copy)]
+pub struct GalleryLayout {
+    pub gallery: &'static str,
+    pub image: &'static str,
+}
+
+impl GalleryLayout {
+    /// Parse image page links of a gallery page.
+    pub fn parse_links(&self, content: &str) -> anyhow::Result<Vec<String>> {
+        let doc = Document::parse(self.gallery, content);
+        if !doc.exists(&THUMBNAILS) {
+            if doc.exists(&REMOVED_NOTICE) {
+                return Err(anyhow::anyhow!(
+                    "invalid url, maybe resource has been deleted."
+                ));
+            }
+            // an empty page is returned if our ip or cookie is blocked
+            if content.trim().is_empty() {
+                return Err(anyhow::anyhow!(
+                    "empty gallery page, maybe our ip or cookie is blocked."
+                ));
+            }
+            return Err(doc.layout_changed(&THUMBNAILS).into());
+        }
+        let links = doc.attrs(&PAGE_LINKS, "href");
+        if links.is_empty() {
+            return Err(anyhow::anyhow!(
+                "invalid url, maybe resource has been deleted, or our ip is blocked."
+            ));
+        }
+        Ok(links)
+    }
+
+    /// Parse title and image count of the first gallery page.
+    pub fn parse_info(&self, content: &str) -> (Option<String>, Option<usize>) {
+        let doc = Document::parse(self.gallery, content);
+        // like "Showing 1 - 40 of 1,000 images"
+        let total = doc.text(&IMAGE_COUNT).and_then(|s| {
+            let (_, n) = s.rsplit_once(" of ")?;
+            n.trim_end_matches(" images").replace(',', "").parse().ok()
+        });
+        (doc.text(&TITLE), total)
+    }
+
+    /// Category like `Doujinshi`.
+    pub fn parse_category(&self, content: &str) -> Option<String> {
+        Document::parse(self.gallery, content)
+            .text(&CATEGORY)
+            .filter(|c| !c.is_empty())
+    }
+
+    /// Namespaced tags like `female:big breasts`.
+    pub fn parse_tags(&self, content: &str) -> Vec<String> {
+        Document::parse(self.gallery, content)
+            .attrs(&TAGS, "id")
+            .into_iter()
+            .filter_map(|id| id.strip_prefix("ta_").map(|tag| tag.replace('_', " ")))
+            .collect()
+    }
+
+    /// Parse page count from the pagination of a gallery page.
+    pub fn parse_page_count(&self, content: &str) -> Option<usize> {
+        Document::parse(self.gallery, content)
+            .select(&PAGINATION)
+            .filter_map(|n| n.text().replace(',', "").parse().ok())
+            .max()
+    }
+
+    /// Parse image url of an image page.
+    pub fn parse_image(&self, content: &str) -> anyhow::Result<String> {
+        Ok(Document::parse(self.image, content).require_attr(&IMG, "src")?)
+    }
+}
+
+/// Gallery pages are `{base}/?p={n}`, and the pagination shows the page count.
+pub struct GalleryPageIndicator {
+    pub base: String,
+    pub layout: GalleryLayout,
+}
+
+impl PageFormatter for GalleryPageIndicator {
+    fn format_n(&self, n: usize) -> String {
+        format!("{}/?p={}", self.base, n)
+    }
+}
+
+impl PageIndicator for GalleryPageIndicator {
+    // the pagination numbers pages from 1, while `next_page` counts from 0
+    fn is_last_page(&self, content: &str, next_page: usize) -> bool {
+        self.layout
+            .parse_page_count(content)
+            .is_none_or(|count| next_page >= count)
+    }
+
+    fn page_count(&self, first_page: &str) -> Option<usize> {
+        self.layout.parse_page_count(first_page)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{GalleryLayout, GalleryPageIndicator};
+    use crate::collector::utils::paged::PageIndicator;
+
+    const LAYOUT: GalleryLayout = GalleryLayout {
+        gallery: "test gallery page",
+        image: "test image page",
+    };
+
+    #[test]
+    fn parse_gallery() {
+        // test page: https://e-hentai.org/g/2122174/fd2525031e
+        let h = r#"<h1 id="gn">A &amp; B</h1><div id="gdt"><div class="gdtm" style="height:170px"><div style="margin:1px auto 0; width:100px; height:140px; background:transparent url(https://ehgt.org/m/002122/2122174-00.jpg) -600px 0 no-repeat"><a href="https://e-hentai.org/s/bd2b37d829/2122174-7"><img alt="007" title="Page 7: 2.png" src="https://ehgt.org/g/blank.gif" style="width:100px; height:139px; margin:-1px 0 0 -1px" /></a></div></div><div class="gdtm" style="height:170px"><div style="margin:1px auto 0; width:100px; height:100px; background:transparent url(https://ehgt.org/m/002122/2122174-00.jpg) -700px 0 no-repeat"><a href="https://exhentai.org/s/4ca72f757d/2122174-8"><img alt="008" title="Page 8: 3.png" src="https://ehgt.org/g/blank.gif" style="width:100px; height:99px; margin:-1px 0 0 -1px" />"#;
+        let links = LAYOUT.parse_links(h).unwrap();
+        assert_eq!(LAYOUT.parse_info(h).0.unwrap(), "A & B");
+        assert_eq!(
+            links,
+            [
+                "https://e-hentai.org/s/bd2b37d829/2122174-7",
+                "https://exhentai.org/s/4ca72f757d/2122174-8"
+            ]
+        );
+
+        let err = LAYOUT.parse_links("<html></html>").unwrap_err();
+        assert!(err.is::<crate::html::LayoutChanged>());
+        assert!(LAYOUT.parse_links("").is_err());
+
+        let h = r#"<p class="gpc">Showing 1 - 40 of 1,000 images</p><table class="ptt"><tr><td>&lt;</td><td><a>1</a></td><td><a>2</a></td><td><a>25</a></td><td>&gt;</td></tr></table>"#;
+        assert_eq!(LAYOUT.parse_info(h).1, Some(1000));
+        assert_eq!(LAYOUT.parse_page_count(h), Some(25));
+
+        let h = r#"<div id="taglist"><table><tr><td class="tc">female:</td><td><div id="td_female:big_breasts" class="gt"><a id="ta_female:big_breasts">big breasts</a></div><div id="td_female:glasses" class="gtl"><a id="ta_female:glasses">glasses</a></div></td></tr></table></div>"#;
+        assert_eq!(
+            LAYOUT.parse_tags(h),
+            ["female:big breasts", "female:glasses"]
+        );
+        let h = r#"<div id="gdc"><div class="cs ct2" onclick="">Doujinshi</div></div>"#;
+        assert_eq!(LAYOUT.parse_category(h).unwrap(), "Doujinshi");
+
+        let h = r#"<div id="i3"><a onclick="return load_image(2, 'a')" href="https://e-hentai.org/s/a/1-2"><img id="img" src="https://ehgt.org/1.jpg" style="" /></a></div>"#;
+        assert_eq!(LAYOUT.parse_image(h).unwrap(), "https://ehgt.org/1.jpg");
+        let err = LAYOUT.parse_image("<html></html>").unwrap_err();
+        assert!(err.is::<crate::html::LayoutChanged>());
+    }
+
+    #[test]
+    fn last_page() {
+        let indicator = GalleryPageIndicator {
+            base: "https://e-hentai.org/g/2122174/fd2525031e".to_string(),
+            layout: LAYOUT,
+        };
+        // attributes of pagination links do not matter
+        let h = r#"<table class="ptt"><tr><td>&lt;</td><td class="ptds"><a href="https://e-hentai.org/g/2122174/fd2525031e/">1</a></td><td><a onclick="return false" href="https://e-hentai.org/g/2122174/fd2525031e/?p=1">2</a></td><td>&gt;</td></tr></table>"#;
+        assert!(!indicator.is_last_page(h, 1));
+        assert!(indicator.is_last_page(h, 2));
+        assert!(indicator.is_last_page("<p>single page</p>", 1));
+    }
+}
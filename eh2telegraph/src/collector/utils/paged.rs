@@ -1,6 +1,10 @@
-use reqwest::Response;
+use futures::StreamExt;
+use tokio::sync::watch;
 
-use crate::http_client::HttpRequestBuilder;
+use crate::{http_client::HttpRequestBuilder, util::get_string};
+
+// max concurrent page requests after the first page
+const PAGE_CONCURRENCY: usize = 4;
 
 pub trait PageFormatter {
     fn format_n(&self, n: usize) -> String;
@@ -8,6 +12,12 @@ pub trait PageFormatter {
 
 pub trait PageIndicator {
     fn is_last_page(&self, content: &str, next_page: usize) -> bool;
+
+    /// Total page count read from the first page.
+    /// If it is known, the rest pages are loaded concurrently.
+    fn page_count(&self, _first_page: &str) -> Option<usize> {
+        None
+    }
 }
 
 #[derive(thiserror::Error, Debug)]
@@ -40,13 +50,7 @@ where
     {
         let url = self.page_indicator.format_n(self.next_page);
 
-        let content = client
-            .get_builder(&url)
-            .send()
-            .await
-            .and_then(Response::error_for_status)?
-            .text()
-            .await?;
+        let content = get_string(client, &url).await?;
         self.next_page += 1;
         Ok(content)
     }
@@ -61,14 +65,195 @@ where
     where
         C: HttpRequestBuilder,
     {
+        let first_page = self.next_page;
+        let first = self.next(client).await?;
+        if let Some(count) = self.page_indicator.page_count(&first) {
+            let end = first_page + count;
+            let mut results = vec![first];
+            let mut rest = futures::stream::iter(self.next_page..end)
+                .map(|n| {
+                    let url = self.page_indicator.format_n(n);
+                    async move { get_string(client, &url).await }
+                })
+                .buffered(PAGE_CONCURRENCY);
+            while let Some(content) = rest.next().await {
+                results.push(content?);
+            }
+            self.next_page = end.max(self.next_page);
+            return Ok(results);
+        }
+
         let mut results = Vec::new();
+        let mut content = first;
         loop {
-            let content = self.next(client).await?;
             let terminated = self.page_indicator.is_last_page(&content, self.next_page);
             results.push(content);
             if terminated {
                 return Ok(results);
             }
+            content = self.next(client).await?;
         }
     }
 }
+
+impl<T> Paged<T>
+where
+    T: PageFormatter + PageIndicator + Send + Sync + 'static,
+{
+    /// Load the first page, and parse links of the rest pages in background.
+    /// Returns the first page and the links of all pages in page order. Links
+    /// of the first page are available at once.
+    pub async fn links<C, F>(mut self, client: C, parse: F) -> anyhow::Result<(String, PagedLinks)>
+    where
+        C: HttpRequestBuilder + Clone + Send + 'static,
+        F: Fn(&str) -> anyhow::Result<Vec<String>> + Send + Sync + 'static,
+    {
+        let first_page = self.next_page;
+        let first = self.next(&client).await?;
+        let (tx, rx) = watch::channel(LinksState {
+            links: parse(&first)?,
+            error: None,
+            done: false,
+        });
+        let page_count = self.page_indicator.page_count(&first);
+        let is_last = self.page_indicator.is_last_page(&first, self.next_page);
+
+        tokio::spawn(async move {
+            let load = async {
+                match page_count {
+                    Some(count) => {
+                        let mut rest = futures::stream::iter(self.next_page..first_page + count)
+                            .map(|n| {
+                                let url = self.page_indicator.format_n(n);
+                                let client = client.clone();
+                                async move { get_string(&client, &url).await }
+                            })
+                            .buffered(PAGE_CONCURRENCY);
+                        while let Some(content) = rest.next().await {
+                            let links = parse(&content?)?;
+                            tx.send_modify(|s| s.links.extend(links));
+                        }
+                    }
+                    None if !is_last => loop {
+                        let content = self.next(&client).await?;
+                        let links = parse(&content)?;
+                        tx.send_modify(|s| s.links.extend(links));
+                        if self.page_indicator.is_last_page(&content, self.next_page) {
+                            break;
+                        }
+                    },
+                    None => (),
+                }
+                anyhow::Ok(())
+            };
+            let result = tokio::select! {
+                r = load => r,
+                // all receivers are dropped
+                _ = tx.closed() => return,
+            };
+            if let Err(e) = &result {
+                tracing::error!("[paged] load page failed: {e:?}");
+            }
+            tx.send_modify(|s| {
+                s.error = result.err().map(|e| e.to_string());
+                s.done = true;
+            });
+        });
+        Ok((first, PagedLinks { rx }))
+    }
+}
+
+#[derive(Debug)]
+struct LinksState {
+    links: Vec<String>,
+    error: Option<String>,
+    done: bool,
+}
+
+/// Links parsed from pages, which may be still loading.
+/// The loading is stopped when all clones are dropped.
+#[derive(Debug, Clone)]
+pub struct PagedLinks {
+    rx: watch::Receiver<LinksState>,
+}
+
+impl PagedLinks {
+    /// Wait for the idx-th link. Returns None if there are no more links.
+    pub async fn get(&self, idx: usize) -> anyhow::Result<Option<String>> {
+        let mut rx = self.rx.clone();
+        // the sender is dropped if loading is aborted, check the state anyway
+        let _ = rx.wait_for(|s| s.links.len() > idx || s.done).await;
+        let state = rx.borrow();
+        if let Some(link) = state.links.get(idx) {
+            return Ok(Some(link.clone()));
+        }
+        match &state.error {
+            Some(e) => Err(anyhow::anyhow!("load page failed: {e}")),
+            None if !state.done => Err(anyhow::anyhow!("load page aborted")),
+            None => Ok(None),
+        }
+    }
+
+    /// Wait for all links.
+    pub async fn all(&self) -> anyhow::Result<Vec<String>> {
+        let mut rx = self.rx.clone();
+        // the sender is dropped if loading is aborted, check the state anyway
+        let _ = rx.wait_for(|s| s.done).await;
+        let state = rx.borrow();
+        match &state.error {
+            Some(e) => Err(anyhow::anyhow!("load page failed: {e}")),
+            None if !state.done => Err(anyhow::anyhow!("load page aborted")),
+            None => Ok(state.links.clone()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{PageFormatter, PageIndicator, Paged};
+    use crate::http_replay::{Exchange, ReplayClient};
+
+    struct Indicator;
+
+    impl PageFormatter for Indicator {
+        fn format_n(&self, n: usize) -> String {
+            format!("https://example.com/?p={n}")
+        }
+    }
+
+    impl PageIndicator for Indicator {
+        fn is_last_page(&self, _content: &str, next_page: usize) -> bool {
+            next_page >= 3
+        }
+
+        fn page_count(&self, first_page: &str) -> Option<usize> {
+            first_page.split(';').next()?.parse().ok()
+        }
+    }
+
+    #[tokio::test]
+    async fn concurrent_links() {
+        let client = ReplayClient::new(vec![
+            Exchange::new("GET", "https://example.com/?p=0", 200, b"3;a0 a1"),
+            Exchange::new("GET", "https://example.com/?p=1", 200, b"b0 b1"),
+            Exchange::new("GET", "https://example.com/?p=2", 200, b"c0"),
+        ]);
+        let parse = |content: &str| {
+            let links = content.rsplit(';').next().unwrap_or_default();
+            Ok(links.split(' ').map(ToString::to_string).collect())
+        };
+
+        let (first, links) = Paged::new(0, Indicator)
+            .links(client.clone(), parse)
+            .await
+            .unwrap();
+        assert_eq!(first, "3;a0 a1");
+        assert_eq!(links.get(4).await.unwrap().unwrap(), "c0");
+        assert!(links.get(5).await.unwrap().is_none());
+        assert_eq!(links.all().await.unwrap(), ["a0", "a1", "b0", "b1", "c0"]);
+
+        let pages = Paged::new(0, Indicator).pages(&client).await.unwrap();
+        assert_eq!(pages.len(), 3);
+        assert_eq!(client.missed(), 0);
+    }
+}
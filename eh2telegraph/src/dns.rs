@@ -0,0 +1,228 @@
+//! DNS overrides and DNS-over-HTTPS resolver.
+//!
+//! Config example:
+//! ```yaml
+//! http:
+//!   dns:
+//!     prefer: ipv6
+//!     doh: https://cloudflare-dns.com/dns-query
+//!     overrides:
+//!       e-hentai.org:
+//!         - "[2606:4700:4700::1111]:443"
+//!         - "1.1.1.1:443"
+//! ```
+//! Ghost clients use overrides of their source family. Direct and proxy clients
+//! have no known source, so only overrides of `prefer` are used if it is set.
+use std::{
+    collections::HashMap,
+    net::{IpAddr, SocketAddr},
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use parking_lot::Mutex;
+use reqwest::dns::{Addrs, Name, Resolve, Resolving};
+
+use crate::{config, http_client::SourceFamily};
+
+const CONFIG_KEY: &str = "http";
+const DOH_TIMEOUT: Duration = Duration::from_secs(10);
+const MIN_TTL: u64 = 60;
+
+#[derive(serde::Deserialize, Clone, Debug, Default)]
+pub struct DnsConfig {
+    /// Address family to try first.
+    pub prefer: Option<SourceFamily>,
+    /// DNS-over-HTTPS endpoint with JSON API, system resolver is used if not set.
+    pub doh: Option<String>,
+    /// Domain to socket addresses, they replace the built-in overrides.
+    #[serde(default)]
+    pub overrides: HashMap<String, Vec<SocketAddr>>,
+}
+
+impl DnsConfig {
+    /// Read `http.dns` from config.
+    pub fn from_config() -> anyhow::Result<Self> {
+        #[derive(serde::Deserialize, Default)]
+        struct HttpConfig {
+            #[serde(default)]
+            dns: DnsConfig,
+        }
+        let config: HttpConfig = config::parse(CONFIG_KEY)?.unwrap_or_default();
+        Ok(config.dns)
+    }
+
+    /// Override addresses of each domain, only addresses of the family are kept
+    /// if it is set, and the preferred family comes first.
+    pub fn overrides_of(&self, family: Option<SourceFamily>) -> Vec<(&str, Vec<SocketAddr>)> {
+        self.overrides
+            .iter()
+            .filter_map(|(domain, addrs)| {
+                let mut addrs = addrs
+                    .iter()
+                    .filter(|a| match family {
+                        Some(SourceFamily::Ipv6) => a.is_ipv6(),
+                        Some(SourceFamily::Ipv4) => a.is_ipv4(),
+                        None => true,
+                    })
+                    .copied()
+                    .collect::<Vec<_>>();
+                sort_by_preference(&mut addrs, self.prefer, SocketAddr::ip);
+                (!addrs.is_empty()).then_some((domain.as_str(), addrs))
+            })
+            .collect()
+    }
+
+    /// DNS-over-HTTPS resolver if the endpoint is set.
+    pub fn resolver(&self) -> Option<Arc<DohResolver>> {
+        self.doh
+            .as_ref()
+            .map(|endpoint| Arc::new(DohResolver::new(endpoint, self.prefer)))
+    }
+}
+
+/// Sort addresses so the preferred family comes first.
+pub fn sort_by_preference<T, F>(addrs: &mut [T], prefer: Option<SourceFamily>, ip: F)
+where
+    F: Fn(&T) -> IpAddr,
+{
+    let rank = |addr: &T| match (prefer, ip(addr)) {
+        (Some(SourceFamily::Ipv6), IpAddr::V4(_)) | (Some(SourceFamily::Ipv4), IpAddr::V6(_)) => 1,
+        _ => 0,
+    };
+    addrs.sort_by_key(rank);
+}
+
+type CacheEntry = (Instant, Vec<IpAddr>);
+
+#[derive(serde::Deserialize)]
+struct DohResponse {
+    #[serde(rename = "Answer", default)]
+    answer: Vec<DohAnswer>,
+}
+
+#[derive(serde::Deserialize)]
+struct DohAnswer {
+    #[serde(rename = "type")]
+    typ: u16,
+    #[serde(rename = "TTL", default)]
+    ttl: u64,
+    data: String,
+}
+
+/// Resolver with DNS-over-HTTPS JSON API(supported by cloudflare and google).
+/// Results are cached by their TTL.
+#[derive(Debug, Clone)]
+pub struct DohResolver {
+    endpoint: String,
+    prefer: Option<SourceFamily>,
+    client: reqwest::Client,
+    // name -> (expire time, ips)
+    cache: Arc<Mutex<HashMap<String, CacheEntry>>>,
+}
+
+impl DohResolver {
+    pub fn new(endpoint: &str, prefer: Option<SourceFamily>) -> Self {
+        Self {
+            endpoint: endpoint.to_string(),
+            prefer,
+            client: reqwest::Client::builder()
+                .timeout(DOH_TIMEOUT)
+                .build()
+                .expect("unable to build doh client"),
+            cache: Default::default(),
+        }
+    }
+
+    async fn query(&self, name: &str, typ: &str) -> reqwest::Result<Vec<(IpAddr, u64)>> {
+        let resp: DohResponse = self
+            .client
+            .get(&self.endpoint)
+            .query(&[("name", name), ("type", typ)])
+            .header(reqwest::header::ACCEPT, "application/dns-json")
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?;
+        Ok(resp
+            .answer
+            .into_iter()
+            // only A(1) and AAAA(28) records, CNAME is skipped
+            .filter(|a| a.typ == 1 || a.typ == 28)
+            .filter_map(|a| a.data.parse().ok().map(|ip| (ip, a.ttl)))
+            .collect())
+    }
+
+    pub async fn lookup(&self, name: &str) -> anyhow::Result<Vec<IpAddr>> {
+        if let Some((expire, ips)) = self.cache.lock().get(name) {
+            if *expire > Instant::now() {
+                return Ok(ips.clone());
+            }
+        }
+
+        let (v4, v6) = futures::join!(self.query(name, "A"), self.query(name, "AAAA"));
+        let mut records = Vec::new();
+        for r in [v4, v6] {
+            match r {
+                Ok(r) => records.extend(r),
+                Err(e) => tracing::warn!("[dns] doh query {name} failed: {e}"),
+            }
+        }
+        if records.is_empty() {
+            anyhow::bail!("no record found for {name}");
+        }
+        let ttl = records.iter().map(|r| r.1).min().unwrap_or_default();
+        let mut ips = records.into_iter().map(|r| r.0).collect::<Vec<_>>();
+        sort_by_preference(&mut ips, self.prefer, |ip| *ip);
+        self.cache.lock().insert(
+            name.to_string(),
+            (
+                Instant::now() + Duration::from_secs(ttl.max(MIN_TTL)),
+                ips.clone(),
+            ),
+        );
+        Ok(ips)
+    }
+}
+
+impl Resolve for DohResolver {
+    fn resolve(&self, name: Name) -> Resolving {
+        let this = self.clone();
+        Box::pin(async move {
+            let ips = this.lookup(name.as_str()).await?;
+            // port is replaced by reqwest
+            let addrs: Addrs = Box::new(ips.into_iter().map(|ip| SocketAddr::new(ip, 0)));
+            Ok(addrs)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::SocketAddr;
+
+    use super::{sort_by_preference, DnsConfig};
+    use crate::http_client::SourceFamily;
+
+    #[test]
+    fn parse_and_sort() {
+        let config: DnsConfig = serde_yaml::from_str(
+            "prefer: ipv6\noverrides:\n  e-hentai.org: [\"1.1.1.1:443\", \"[2606:4700:4700::1111]:443\"]",
+        )
+        .unwrap();
+        let mut addrs = config.overrides["e-hentai.org"].clone();
+        sort_by_preference(&mut addrs, config.prefer, SocketAddr::ip);
+        assert!(addrs[0].is_ipv6());
+        sort_by_preference(&mut addrs, Some(SourceFamily::Ipv4), SocketAddr::ip);
+        assert!(addrs[0].is_ipv4());
+    }
+
+    #[ignore]
+    #[tokio::test]
+    async fn doh_lookup() {
+        let resolver = super::DohResolver::new("https://cloudflare-dns.com/dns-query", None);
+        let ips = resolver.lookup("e-hentai.org").await.unwrap();
+        assert!(!ips.is_empty());
+    }
+}
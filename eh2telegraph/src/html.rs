@@ -0,0 +1,139 @@
+//! HTML extraction with CSS selectors.
+//!
+//! Selectors are declared once as `Css` statics, and a parsed `Document` is
+//! queried with them. Texts and attributes are returned with entities decoded.
+//! `Document` is not Send, so parse and extract before any await point.
+use scraper::{ElementRef, Html, Selector};
+
+/// Expected elements are not found, the site layout may have changed.
+#[derive(thiserror::Error, Debug)]
+#[error("{page} layout changed, `{selector}` is not found")]
+pub struct LayoutChanged {
+    pub page: &'static str,
+    pub selector: &'static str,
+}
+
+/// A parsed CSS selector with its source.
+#[derive(Debug)]
+pub struct Css {
+    src: &'static str,
+    selector: Selector,
+}
+
+impl Css {
+    /// Parse selector, panics if it is invalid.
+    pub fn new(src: &'static str) -> Self {
+        Self {
+            src,
+            selector: Selector::parse(src)
+                .unwrap_or_else(|e| panic!("invalid selector {src}: {e}")),
+        }
+    }
+}
+
+pub struct Document {
+    page: &'static str,
+    html: Html,
+}
+
+impl Document {
+    /// Parse html, `page` names the page in errors.
+    pub fn parse(page: &'static str, content: &str) -> Self {
+        Self {
+            page,
+            html: Html::parse_document(content),
+        }
+    }
+
+    pub fn layout_changed(&self, css: &Css) -> LayoutChanged {
+        LayoutChanged {
+            page: self.page,
+            selector: css.src,
+        }
+    }
+
+    pub fn exists(&self, css: &Css) -> bool {
+        self.html.select(&css.selector).next().is_some()
+    }
+
+    pub fn select<'a>(&'a self, css: &'a Css) -> impl Iterator<Item = Node<'a>> + 'a {
+        self.html.select(&css.selector).map(Node)
+    }
+
+    /// Text of the first matched element.
+    pub fn text(&self, css: &Css) -> Option<String> {
+        self.select(css).next().map(|n| n.text())
+    }
+
+    /// Attribute of the first matched element which has it.
+    pub fn attr(&self, css: &Css, name: &str) -> Option<String> {
+        self.select(css)
+            .find_map(|n| n.attr(name).map(ToString::to_string))
+    }
+
+    /// Attribute of all matched elements which have it.
+    pub fn attrs(&self, css: &Css, name: &str) -> Vec<String> {
+        self.select(css)
+            .filter_map(|n| n.attr(name).map(ToString::to_string))
+            .collect()
+    }
+
+    pub fn require_text(&self, css: &Css) -> Result<String, LayoutChanged> {
+        self.text(css).ok_or_else(|| self.layout_changed(css))
+    }
+
+    pub fn require_attr(&self, css: &Css, name: &str) -> Result<String, LayoutChanged> {
+        self.attr(css, name).ok_or_else(|| self.layout_changed(css))
+    }
+}
+
+/// A matched element.
+#[derive(Clone, Copy)]
+pub struct Node<'a>(ElementRef<'a>);
+
+impl<'a> Node<'a> {
+    /// Concatenated and trimmed text.
+    pub fn text(&self) -> String {
+        self.0.text().collect::<String>().trim().to_string()
+    }
+
+    pub fn attr(&self, name: &str) -> Option<&'a str> {
+        self.0.value().attr(name)
+    }
+
+    pub fn select<'b>(&self, css: &'b Css) -> impl Iterator<Item = Node<'a>> + 'b
+    where
+        'a: 'b,
+    {
+        self.0.select(&css.selector).map(Node)
+    }
+
+    pub fn text_of(&self, css: &Css) -> Option<String> {
+        self.select(css).next().map(|n| n.text())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Css, Document};
+
+    #[test]
+    fn extract() {
+        let doc = Document::parse(
+            "test",
+            r#"<h1 id="gn" class="x">Tom &amp; Jerry &#x2665;</h1>
+            <div id="gdt"><a class="a" href="/s/1?a=1&amp;b=2"></a><a href="/s/2"></a></div>"#,
+        );
+        let title = Css::new("h1#gn");
+        let links = Css::new("#gdt a[href]");
+        let missing = Css::new("img#img");
+        assert_eq!(doc.text(&title).unwrap(), "Tom & Jerry \u{2665}");
+        assert_eq!(doc.attrs(&links, "href"), ["/s/1?a=1&b=2", "/s/2"]);
+        let err = doc.require_attr(&missing, "src").unwrap_err();
+        assert_eq!(err.selector, "img#img");
+        assert_eq!(
+            err.to_string(),
+            "test layout changed, `img#img` is not found"
+        );
+    }
+}
@@ -1,5 +1,6 @@
 // A wrapper for reqwest to provide ability to bind to random ip.
-// Since apparently I can not afford a ipv4 subnet, here I assume ipv6.
+// Since apparently I can not afford a ipv4 subnet, here I assume ipv6, and
+// ipv4 addresses can be listed one by one for sites without ipv6.
 // Using he.net tunnel broker works fine.
 // Setup:
 // 1. sudo ip add add local 2001:x:x::/48 dev lo
@@ -56,18 +57,37 @@ pub const UAS: [&str; 45] = [
 const CONFIG_KEY: &str = "http";
 const TIMTOUT: Duration = Duration::from_secs(30);
 
+const DEFAULT_POOL_SIZE: usize = 8;
+const DEFAULT_ROTATE_INTERVAL: Duration = Duration::from_secs(300);
+const DEFAULT_BAN_COOLDOWN: Duration = Duration::from_secs(1800);
+// Consecutive errors before a client is rotated.
+const ERROR_THRESHOLD: usize = 3;
+// expired bans are dropped when there are more than this
+const BAN_SWEEP_LEN: usize = 64;
+
 use std::{
-    net::{IpAddr, Ipv6Addr, SocketAddr},
-    ops::{Deref, DerefMut},
-    sync::Arc,
-    time::Duration,
+    borrow::Cow,
+    collections::HashMap,
+    future::Future,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    sync::{
+        atomic::{AtomicU64, AtomicUsize, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
 };
 
 use ipnet::Ipv6Net;
+use parking_lot::Mutex;
 use reqwest::header;
 use rustls::ClientConfig;
 
-use crate::{config, tls::WhitelistVerifier};
+use crate::{
+    config,
+    dns::{DnsConfig, DohResolver},
+    rate_limit::{self, RequestKind},
+    tls::TlsConfig,
+};
 
 const CF_ADDR: Ipv6Addr = Ipv6Addr::new(0x2606, 0x4700, 0x4700, 0, 0, 0, 0, 0x1111);
 const TG_ADDR: Ipv6Addr = Ipv6Addr::new(0x2001, 0x67c, 0x4e8, 0x1033, 0x1, 0x100, 0, 0xa);
@@ -78,9 +98,38 @@ pub fn rand_ua() -> &'static str {
     UAS.choose(&mut thread_rng()).expect("Empty UA List!")
 }
 
-pub trait HttpRequestBuilder {
+pub trait HttpRequestBuilder: Sync {
     fn get_builder(&self, url: &str) -> reqwest::RequestBuilder;
     fn post_builder(&self, url: &str) -> reqwest::RequestBuilder;
+
+    /// Send the request built by this client without host limit.
+    fn send_raw(
+        &self,
+        builder: reqwest::RequestBuilder,
+    ) -> impl Future<Output = reqwest::Result<reqwest::Response>> + Send {
+        builder.send()
+    }
+
+    /// Send the request built by this client, limited by the host limiter.
+    /// The in-flight permit is released once the response headers are received.
+    fn execute_as(
+        &self,
+        kind: RequestKind,
+        builder: reqwest::RequestBuilder,
+    ) -> impl Future<Output = reqwest::Result<reqwest::Response>> + Send {
+        async move {
+            let (_permit, builder) = rate_limit::limit(kind, builder).await?;
+            self.send_raw(builder).await
+        }
+    }
+
+    /// Send the request as a page request.
+    fn execute(
+        &self,
+        builder: reqwest::RequestBuilder,
+    ) -> impl Future<Output = reqwest::Result<reqwest::Response>> + Send {
+        self.execute_as(RequestKind::Page, builder)
+    }
 }
 
 macro_rules! gen_impl {
@@ -101,8 +150,6 @@ macro_rules! gen_impl {
 }
 
 gen_impl!(reqwest::Client);
-gen_impl!(crate::http_proxy::ProxiedClient);
-gen_impl!(GhostClient);
 
 #[derive(Debug, Default, Clone, Copy, PartialEq, Eq, derive_more::From, derive_more::Into)]
 pub struct Ipv6Net2(Ipv6Net);
@@ -120,15 +167,48 @@ impl<'de> serde::Deserialize<'de> for Ipv6Net2 {
     }
 }
 
+/// Address family of source ip.
+#[derive(serde::Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum SourceFamily {
+    Ipv6,
+    Ipv4,
+}
+
 #[derive(serde::Deserialize, Clone, Debug, Default)]
 struct HTTPConfig {
     ipv6_prefix: Option<Ipv6Net2>,
+    #[serde(default)]
+    ipv6_prefixes: Vec<Ipv6Net2>,
+    #[serde(default)]
+    ipv4_addrs: Vec<Ipv4Addr>,
+    /// Source family of hosts, the domain and its subdomains are matched.
+    #[serde(default)]
+    source_rules: HashMap<String, SourceFamily>,
+    /// Seconds to avoid an address for a host after it gets 403.
+    ban_cooldown: Option<u64>,
+    /// Number of clients with different ip kept in pool.
+    pool_size: Option<usize>,
+    /// Seconds before a client is replaced with one of new ip.
+    rotate_interval: Option<u64>,
+    #[serde(default)]
+    dns: DnsConfig,
+    #[serde(default)]
+    tls: TlsConfig,
 }
 
 #[derive(Debug, Default)]
 pub struct GhostClientBuilder {
     mapping: Vec<(&'static str, SocketAddr)>,
+    dns: DnsConfig,
+    tls: Option<ClientConfig>,
     headers: Option<header::HeaderMap>,
+    ipv6_prefixes: Vec<Ipv6Net>,
+    ipv4_addrs: Vec<Ipv4Addr>,
+    rules: Vec<(String, SourceFamily)>,
+    ban_cooldown: Option<Duration>,
+    pool_size: Option<usize>,
+    rotate_interval: Option<Duration>,
 }
 
 impl GhostClientBuilder {
@@ -139,6 +219,63 @@ impl GhostClientBuilder {
         }
     }
 
+    pub fn headers(&self) -> Option<&header::HeaderMap> {
+        self.headers.as_ref()
+    }
+
+    pub fn with_pool_size(self, size: usize) -> Self {
+        Self {
+            pool_size: Some(size),
+            ..self
+        }
+    }
+
+    pub fn with_rotate_interval(self, interval: Duration) -> Self {
+        Self {
+            rotate_interval: Some(interval),
+            ..self
+        }
+    }
+
+    pub fn with_ipv6_prefixes(mut self, prefixes: impl IntoIterator<Item = Ipv6Net>) -> Self {
+        self.ipv6_prefixes.extend(prefixes);
+        self
+    }
+
+    pub fn with_ipv4_addrs(mut self, addrs: impl IntoIterator<Item = Ipv4Addr>) -> Self {
+        self.ipv4_addrs.extend(addrs);
+        self
+    }
+
+    /// Use source of the family for the domain and its subdomains.
+    pub fn with_source_rule(mut self, domain: &str, family: SourceFamily) -> Self {
+        self.rules.push((domain.to_string(), family));
+        self
+    }
+
+    pub fn with_ban_cooldown(self, cooldown: Duration) -> Self {
+        Self {
+            ban_cooldown: Some(cooldown),
+            ..self
+        }
+    }
+
+    /// Use DNS overrides and resolver. Overridden domains are not affected by
+    /// the built-in resolve of `with_cf_resolve`.
+    pub fn with_dns(self, dns: DnsConfig) -> Self {
+        Self { dns, ..self }
+    }
+
+    /// Use custom TLS config, see `tls::TlsConfig`.
+    pub fn with_tls(self, tls: ClientConfig) -> Self {
+        Self {
+            tls: Some(tls),
+            ..self
+        }
+    }
+
+    /// Resolve domains to cloudflare for clients with ipv6 source, it can be
+    /// replaced by DNS overrides in config.
     pub fn with_cf_resolve(mut self, domains: &[&'static str]) -> Self {
         let cf = SocketAddr::new(IpAddr::V6(CF_ADDR), 443);
         for &domain in domains.iter() {
@@ -147,6 +284,11 @@ impl GhostClientBuilder {
         self
     }
 
+    pub fn with_resolve(mut self, domain: &'static str, addr: SocketAddr) -> Self {
+        self.mapping.push((domain, addr));
+        self
+    }
+
     #[deprecated = "telegra.ph has fixed it and returns 501 when using ipv6"]
     pub fn with_tg_resolve(mut self) -> Self {
         let tg = SocketAddr::new(IpAddr::V6(TG_ADDR), 443);
@@ -155,119 +297,513 @@ impl GhostClientBuilder {
         self
     }
 
-    pub fn build(self, prefix: Option<Ipv6Net>) -> GhostClient {
-        let inner = GhostClient::build_raw(&prefix, &self.mapping, self.headers.clone());
-        GhostClient {
-            prefix,
-            mapping: Arc::new(self.mapping),
-            headers: self.headers,
-            inner,
+    pub fn build(mut self, prefix: Option<Ipv6Net>) -> GhostClient {
+        self.ipv6_prefixes.extend(prefix);
+        let pool_size = self.pool_size.unwrap_or(DEFAULT_POOL_SIZE).max(1);
+        // the first group is the default one
+        let mut families = Vec::new();
+        if !self.ipv6_prefixes.is_empty() {
+            families.push((Some(SourceFamily::Ipv6), pool_size));
+        }
+        if !self.ipv4_addrs.is_empty() {
+            families.push((Some(SourceFamily::Ipv4), self.ipv4_addrs.len()));
         }
+        if families.is_empty() {
+            // without source all clients have the same ip, so one is enough.
+            families.push((None, 1));
+        }
+
+        let mut pool = ClientPool {
+            ipv6_prefixes: self.ipv6_prefixes,
+            ipv4_addrs: self.ipv4_addrs,
+            rules: self.rules,
+            mapping: self.mapping,
+            resolver: self.dns.resolver(),
+            dns: self.dns,
+            tls: self.tls,
+            headers: self.headers,
+            rotate_interval: self.rotate_interval.unwrap_or(DEFAULT_ROTATE_INTERVAL),
+            ban_cooldown: self.ban_cooldown.unwrap_or(DEFAULT_BAN_COOLDOWN),
+            groups: Vec::new(),
+            generation: AtomicU64::new(0),
+            bans: Mutex::new(HashMap::new()),
+        };
+        pool.groups = families
+            .into_iter()
+            .map(|(family, size)| SlotGroup {
+                family,
+                slots: (0..size)
+                    .map(|idx| Mutex::new(pool.new_slot(family, idx)))
+                    .collect(),
+                next: AtomicUsize::new(0),
+            })
+            .collect();
+        GhostClient::pick(Arc::new(pool))
     }
 
     pub fn build_from_config(self) -> anyhow::Result<GhostClient> {
         let config: HTTPConfig = config::parse(CONFIG_KEY)?.unwrap_or_default();
         let prefix = config.ipv6_prefix.map(Into::into);
-        Ok(self.build(prefix))
+        let mut builder = self
+            .with_ipv6_prefixes(config.ipv6_prefixes.into_iter().map(Into::into))
+            .with_ipv4_addrs(config.ipv4_addrs)
+            .with_dns(config.dns);
+        if let Some(tls) = config.tls.client_config()? {
+            builder = builder.with_tls(tls);
+        }
+        for (domain, family) in config.source_rules {
+            builder = builder.with_source_rule(&domain, family);
+        }
+        if let Some(secs) = config.ban_cooldown {
+            builder = builder.with_ban_cooldown(Duration::from_secs(secs));
+        }
+        if let Some(size) = config.pool_size {
+            builder = builder.with_pool_size(size);
+        }
+        if let Some(secs) = config.rotate_interval {
+            builder = builder.with_rotate_interval(Duration::from_secs(secs));
+        }
+        Ok(builder.build(prefix))
     }
 }
 
-#[derive(Debug, Default)]
-pub struct GhostClient {
-    prefix: Option<Ipv6Net>,
-    mapping: Arc<Vec<(&'static str, SocketAddr)>>,
+/// Pre-built clients, each one is bound to a source address.
+/// Clients are grouped by address family, and the group of a request is
+/// selected by source rules of its host.
+/// A client is replaced with one of new address after `rotate_interval`, or
+/// when ERROR_THRESHOLD consecutive requests failed. An address that gets 403
+/// is not used for the host in `ban_cooldown`.
+#[derive(Debug)]
+struct ClientPool {
+    ipv6_prefixes: Vec<Ipv6Net>,
+    ipv4_addrs: Vec<Ipv4Addr>,
+    rules: Vec<(String, SourceFamily)>,
+    mapping: Vec<(&'static str, SocketAddr)>,
+    dns: DnsConfig,
+    resolver: Option<Arc<DohResolver>>,
+    tls: Option<ClientConfig>,
     headers: Option<header::HeaderMap>,
+    rotate_interval: Duration,
+    ban_cooldown: Duration,
 
-    inner: reqwest::Client,
+    groups: Vec<SlotGroup>,
+    generation: AtomicU64,
+    bans: Mutex<HashMap<(IpAddr, String), Instant>>,
 }
 
-impl GhostClient {
-    pub fn builder() -> GhostClientBuilder {
-        GhostClientBuilder::default()
-    }
+#[derive(Debug)]
+struct SlotGroup {
+    family: Option<SourceFamily>,
+    slots: Vec<Mutex<Slot>>,
+    next: AtomicUsize,
 }
 
-impl Clone for GhostClient {
-    fn clone(&self) -> Self {
-        let inner = Self::build_raw(&self.prefix, &self.mapping, self.headers.clone());
-        Self {
-            prefix: self.prefix,
-            mapping: self.mapping.clone(),
-            headers: self.headers.clone(),
-            inner,
-        }
-    }
+#[derive(Debug)]
+struct Slot {
+    client: reqwest::Client,
+    addr: Option<IpAddr>,
+    generation: u64,
+    created: Instant,
+    errors: usize,
 }
 
-impl Deref for GhostClient {
-    type Target = reqwest::Client;
-
-    fn deref(&self) -> &Self::Target {
-        &self.inner
-    }
+/// A client taken from the pool.
+#[derive(Debug, Clone)]
+struct Lease {
+    group: usize,
+    slot: usize,
+    generation: u64,
+    addr: Option<IpAddr>,
+    client: reqwest::Client,
 }
 
-impl DerefMut for GhostClient {
-    fn deref_mut(&mut self) -> &mut Self::Target {
-        &mut self.inner
+impl ClientPool {
+    fn new_slot(&self, family: Option<SourceFamily>, idx: usize) -> Slot {
+        let addr = match family {
+            Some(SourceFamily::Ipv6) => {
+                use rand::seq::SliceRandom;
+                let net = self
+                    .ipv6_prefixes
+                    .choose(&mut rand::thread_rng())
+                    .expect("empty ipv6 prefixes");
+                Some(IpAddr::V6(GhostClient::rand_addr(net)))
+            }
+            // ipv4 addresses are fixed, one slot for each
+            Some(SourceFamily::Ipv4) => Some(IpAddr::V4(self.ipv4_addrs[idx])),
+            None => None,
+        };
+        Slot {
+            client: self.build_client(addr),
+            addr,
+            generation: self.generation.fetch_add(1, Ordering::Relaxed),
+            created: Instant::now(),
+            errors: 0,
+        }
     }
-}
 
-impl GhostClient {
-    fn build_raw(
-        net: &Option<Ipv6Net>,
-        mapping: &[(&'static str, SocketAddr)],
-        headers: Option<header::HeaderMap>,
-    ) -> reqwest::Client {
+    fn build_client(&self, addr: Option<IpAddr>) -> reqwest::Client {
         let mut builder = reqwest::Client::builder().timeout(TIMTOUT);
 
-        if let Some(headers) = headers {
+        if let Some(headers) = self.headers.clone() {
             builder = builder.default_headers(headers);
         }
-
-        if let Some(net) = net {
-            let addr: u128 = net.addr().into();
-            let prefix_len = net.prefix_len();
-            let mask = !u128::MAX
-                .checked_shl((128 - prefix_len) as u32)
-                .unwrap_or(u128::MIN);
-
-            // use random ipv6
-            let rand: u128 = rand::Rng::gen(&mut rand::thread_rng());
-            let addr = IpAddr::V6(Ipv6Addr::from(rand & mask | addr));
+        if let Some(addr) = addr {
             builder = builder.local_address(addr);
+        }
 
-            // apply resolve
-            for (domain, addr) in mapping {
-                builder = builder.resolve(domain, *addr);
+        // apply configured overrides with the same family as source
+        let family = addr.map(|a| match a {
+            IpAddr::V6(_) => SourceFamily::Ipv6,
+            IpAddr::V4(_) => SourceFamily::Ipv4,
+        });
+        for (domain, addrs) in self.dns.overrides_of(family) {
+            builder = builder.resolve_to_addrs(domain, &addrs);
+        }
+        // apply built-in resolve, the mapped addresses are ipv6
+        if addr.is_some_and(|a| a.is_ipv6()) {
+            for (domain, addr) in self.mapping.iter() {
+                if !self.dns.overrides.contains_key(*domain) {
+                    builder = builder.resolve(domain, *addr);
+                }
             }
+        }
+        if let Some(resolver) = self.resolver.clone() {
+            builder = builder.dns_resolver(resolver);
+        }
 
-            // not add preconfigured tls
-            // let tls_config = TLS_CFG.clone();
-            // builder = builder.use_preconfigured_tls(tls_config);
+        if let Some(tls) = self.tls.clone() {
+            builder = builder.use_preconfigured_tls(tls);
         }
 
         builder.build().expect("build reqwest client failed")
     }
 
+    fn group_for(&self, host: &str) -> usize {
+        let family = self.rules.iter().find_map(|(domain, family)| {
+            let matched = host == domain
+                || host
+                    .strip_suffix(domain.as_str())
+                    .map(|h| h.ends_with('.'))
+                    .unwrap_or_default();
+            matched.then_some(*family)
+        });
+        family
+            .and_then(|f| self.groups.iter().position(|g| g.family == Some(f)))
+            .unwrap_or(0)
+    }
+
+    fn is_banned(&self, addr: Option<IpAddr>, host: &str) -> bool {
+        let addr = match addr {
+            Some(a) => a,
+            None => return false,
+        };
+        let mut bans = self.bans.lock();
+        match bans.get(&(addr, host.to_string())) {
+            Some(until) if *until > Instant::now() => true,
+            Some(_) => {
+                bans.remove(&(addr, host.to_string()));
+                false
+            }
+            None => false,
+        }
+    }
+
+    // Rotated ipv6 addresses are rarely checked again, so expired bans are swept
+    // when inserting instead of waiting for the next check.
+    fn ban(&self, addr: IpAddr, host: &str) {
+        let mut bans = self.bans.lock();
+        let now = Instant::now();
+        if bans.len() >= BAN_SWEEP_LEN {
+            bans.retain(|_, until| *until > now);
+        }
+        bans.insert((addr, host.to_string()), now + self.ban_cooldown);
+    }
+
+    /// Select a client in round-robin, expired one is rotated first.
+    /// Clients banned by the host are skipped if possible.
+    fn pick(&self, group: usize, host: Option<&str>) -> Lease {
+        let g = &self.groups[group];
+        let start = g.next.fetch_add(1, Ordering::Relaxed);
+        let mut fallback = None;
+        for n in 0..g.slots.len() {
+            let idx = (start + n) % g.slots.len();
+            let mut slot = g.slots[idx].lock();
+            if slot.addr.is_some() && slot.created.elapsed() >= self.rotate_interval {
+                *slot = self.new_slot(g.family, idx);
+            }
+            if let Some(host) = host {
+                if self.is_banned(slot.addr, host) {
+                    // ipv6 has enough addresses to get a new one
+                    if g.family == Some(SourceFamily::Ipv6) {
+                        *slot = self.new_slot(g.family, idx);
+                    } else {
+                        fallback.get_or_insert(idx);
+                        continue;
+                    }
+                }
+            }
+            return Lease {
+                group,
+                slot: idx,
+                generation: slot.generation,
+                addr: slot.addr,
+                client: slot.client.clone(),
+            };
+        }
+        // all addresses are banned, use one anyway
+        let idx = fallback.unwrap_or(start % g.slots.len());
+        let slot = g.slots[idx].lock();
+        Lease {
+            group,
+            slot: idx,
+            generation: slot.generation,
+            addr: slot.addr,
+            client: slot.client.clone(),
+        }
+    }
+
+    fn report(&self, lease: &Lease, host: &str, status: Option<u16>) {
+        let g = &self.groups[lease.group];
+        let mut slot = g.slots[lease.slot].lock();
+        // the client has been rotated
+        if slot.generation != lease.generation {
+            return;
+        }
+        match status {
+            Some(403) => {
+                if let Some(addr) = slot.addr {
+                    tracing::warn!("[http] {addr} is banned by {host}");
+                    self.ban(addr, host);
+                }
+                self.rotate(g.family, lease.slot, &mut slot);
+            }
+            Some(429) | None => {
+                slot.errors += 1;
+                if slot.errors >= ERROR_THRESHOLD {
+                    self.rotate(g.family, lease.slot, &mut slot);
+                }
+            }
+            Some(_) => slot.errors = 0,
+        }
+    }
+
+    fn rotate(&self, family: Option<SourceFamily>, idx: usize, slot: &mut Slot) {
+        // only ipv6 can get a different address
+        if family == Some(SourceFamily::Ipv6) {
+            tracing::info!("[http] rotate client after {} errors", slot.errors);
+            *slot = self.new_slot(family, idx);
+        }
+    }
+}
+
+/// Client bound to source addresses of the pool.
+/// Clients are shared in a pool, and cloning takes other clients from the
+/// pool, so the ip may change without building a new connection pool.
+/// Use `request` or `HttpRequestBuilder` to create requests, and `send_raw`
+/// to send them, then source rules of the host are applied.
+#[derive(Debug)]
+pub struct GhostClient {
+    pool: Arc<ClientPool>,
+    // one lease for each group
+    leases: Vec<Lease>,
+}
+
+impl GhostClient {
+    pub fn builder() -> GhostClientBuilder {
+        GhostClientBuilder::default()
+    }
+
+    fn pick(pool: Arc<ClientPool>) -> Self {
+        let leases = (0..pool.groups.len()).map(|g| pool.pick(g, None)).collect();
+        Self { pool, leases }
+    }
+
+    /// Get the lease for host, another one is taken if it is banned.
+    fn lease_for(&self, host: &str) -> Cow<'_, Lease> {
+        let lease = &self.leases[self.pool.group_for(host)];
+        if self.pool.is_banned(lease.addr, host) {
+            Cow::Owned(self.pool.pick(lease.group, Some(host)))
+        } else {
+            Cow::Borrowed(lease)
+        }
+    }
+
+    /// Create request with the client selected by host.
+    pub fn request(&self, method: reqwest::Method, url: &str) -> reqwest::RequestBuilder {
+        let host = reqwest::Url::parse(url)
+            .ok()
+            .and_then(|u| u.host_str().map(ToOwned::to_owned))
+            .unwrap_or_default();
+        self.lease_for(&host).client.request(method, url)
+    }
+}
+
+impl Default for GhostClient {
+    fn default() -> Self {
+        Self::builder().build(None)
+    }
+}
+
+impl Clone for GhostClient {
+    fn clone(&self) -> Self {
+        Self::pick(self.pool.clone())
+    }
+}
+
+impl GhostClient {
+    fn rand_addr(net: &Ipv6Net) -> Ipv6Addr {
+        let addr: u128 = net.addr().into();
+        let prefix_len = net.prefix_len();
+        let mask = !u128::MAX
+            .checked_shl((128 - prefix_len) as u32)
+            .unwrap_or(u128::MIN);
+        let rand: u128 = rand::Rng::gen(&mut rand::thread_rng());
+        Ipv6Addr::from(rand & mask | addr)
+    }
+
+    /// Replace clients with ones of new ip.
     pub fn refresh(&mut self) {
-        self.inner = Self::build_raw(&self.prefix, &self.mapping, self.headers.clone());
+        for lease in self.leases.iter() {
+            let g = &self.pool.groups[lease.group];
+            let mut slot = g.slots[lease.slot].lock();
+            if slot.generation == lease.generation {
+                self.pool.rotate(g.family, lease.slot, &mut slot);
+            }
+        }
+        *self = Self::pick(self.pool.clone());
     }
 }
 
-lazy_static::lazy_static! {
-    // here we only meet telegra.ph with wrong tls config, so we write them as fixed values.
-    static ref TLS_CFG: ClientConfig = WhitelistVerifier::new(["telegram.org"]).into();
+impl HttpRequestBuilder for GhostClient {
+    #[inline]
+    fn get_builder(&self, url: &str) -> reqwest::RequestBuilder {
+        self.request(reqwest::Method::GET, url)
+            .header(reqwest::header::USER_AGENT, rand_ua())
+    }
+
+    #[inline]
+    fn post_builder(&self, url: &str) -> reqwest::RequestBuilder {
+        self.request(reqwest::Method::POST, url)
+            .header(reqwest::header::USER_AGENT, rand_ua())
+    }
+
+    /// The request is sent with the lease selected here, which may differ from
+    /// the one that built it, so the result is reported to the lease sent it.
+    async fn send_raw(
+        &self,
+        builder: reqwest::RequestBuilder,
+    ) -> reqwest::Result<reqwest::Response> {
+        let request = builder.build()?;
+        let host = request.url().host_str().unwrap_or_default().to_string();
+        let lease = self.lease_for(&host);
+        let result = lease.client.execute(request).await;
+        let status = match &result {
+            Ok(resp) => Some(resp.status().as_u16()),
+            Err(e) if e.is_connect() || e.is_timeout() => None,
+            // errors not caused by the address
+            Err(_) => Some(0),
+        };
+        self.pool.report(&lease, &host, status);
+        result
+    }
 }
 
 #[cfg(test)]
 mod tests {
-    use super::TLS_CFG;
+    use std::{
+        io::{Read, Write},
+        net::{IpAddr, Ipv4Addr, TcpListener},
+    };
+
+    use std::time::Duration;
+
+    use super::{GhostClient, HttpRequestBuilder, SourceFamily, BAN_SWEEP_LEN, ERROR_THRESHOLD};
+    use crate::tls::WhitelistVerifier;
+
+    #[test]
+    fn client_pool() {
+        let client = GhostClient::builder()
+            .with_pool_size(2)
+            .build(Some("2001:db8::/64".parse().unwrap()));
+        let lease = client.leases[0].clone();
+        assert_ne!(client.clone().leases[0].slot, lease.slot);
+        assert_eq!(client.clone().leases[0].slot, lease.slot);
+
+        // rotate after errors
+        for _ in 0..ERROR_THRESHOLD {
+            client.pool.report(&lease, "e-hentai.org", None);
+        }
+        let slot = &client.pool.groups[0].slots[lease.slot];
+        assert_ne!(slot.lock().generation, lease.generation);
+        // results of the old client are ignored
+        client.pool.report(&lease, "e-hentai.org", None);
+        assert_eq!(slot.lock().errors, 0);
+        // without source there is only one client
+        assert_eq!(GhostClient::default().pool.groups[0].slots.len(), 1);
+    }
+
+    #[test]
+    fn source_rules() {
+        let v4: Vec<Ipv4Addr> = vec!["192.0.2.1".parse().unwrap(), "192.0.2.2".parse().unwrap()];
+        let client = GhostClient::builder()
+            .with_ipv4_addrs(v4.clone())
+            .with_source_rule("exhentai.org", SourceFamily::Ipv4)
+            .build(Some("2001:db8::/64".parse().unwrap()));
+        assert_eq!(client.lease_for("e-hentai.org").group, 0);
+        let lease = client.lease_for("s.exhentai.org").into_owned();
+        assert_eq!(lease.group, 1);
+        assert!(matches!(lease.addr, Some(IpAddr::V4(_))));
+
+        // banned address is avoided for the host only
+        client.pool.report(&lease, "exhentai.org", Some(403));
+        let other = client.lease_for("exhentai.org");
+        assert_ne!(other.addr, lease.addr);
+        assert_eq!(client.lease_for("s.exhentai.org").addr, lease.addr);
+    }
+
+    #[test]
+    fn sweep_bans() {
+        let client = GhostClient::builder()
+            .with_ban_cooldown(Duration::ZERO)
+            .build(Some("2001:db8::/64".parse().unwrap()));
+        for i in 0..BAN_SWEEP_LEN * 2 {
+            let addr = IpAddr::V6(format!("2001:db8::{i:x}").parse().unwrap());
+            client.pool.ban(addr, "e-hentai.org");
+        }
+        assert!(client.pool.bans.lock().len() <= BAN_SWEEP_LEN);
+    }
+
+    #[tokio::test]
+    async fn report_sender() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let server = std::thread::spawn(move || {
+            let (mut stream, peer) = listener.accept().unwrap();
+            let _ = stream.read(&mut [0; 1024]).unwrap();
+            stream
+                .write_all(b"HTTP/1.1 403 Forbidden\r\ncontent-length: 0\r\n\r\n")
+                .unwrap();
+            peer.ip()
+        });
+
+        let v4: Vec<Ipv4Addr> = vec!["127.0.0.1".parse().unwrap(), "127.0.0.2".parse().unwrap()];
+        let client = GhostClient::builder().with_ipv4_addrs(v4).build(None);
+        let banned = client.leases[0].clone();
+        client.pool.report(&banned, "127.0.0.1", Some(403));
+
+        let url = format!("http://127.0.0.1:{port}/");
+        let resp = client.send_raw(client.get_builder(&url)).await.unwrap();
+        assert_eq!(resp.status(), 403);
+        // the 403 is reported to the address that sent the request
+        let sender = server.join().unwrap();
+        assert_ne!(Some(sender), banned.addr);
+        assert!(client.pool.is_banned(Some(sender), "127.0.0.1"));
+    }
 
     #[ignore]
     #[tokio::test]
     async fn test_tls() {
-        let tls_config = TLS_CFG.clone();
+        let tls_config: rustls::ClientConfig = WhitelistVerifier::new(["telegram.org"]).into();
         // use a telegram.org ip address(normally it fails in browser)
         let cli = reqwest::Client::builder()
             .resolve("api.telegra.ph", "149.154.167.99:443".parse().unwrap())
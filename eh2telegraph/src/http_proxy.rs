@@ -1,104 +1,402 @@
-use std::time::Duration;
+use std::{
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
 
-use reqwest::header::HeaderValue;
+use parking_lot::Mutex;
+use reqwest::{
+    header::{HeaderMap, HeaderValue},
+    Method, Request, Response,
+};
+use rustls::ClientConfig;
 
-use crate::config;
+use crate::{
+    config,
+    http_client::{rand_ua, HttpRequestBuilder},
+    tls::TlsConfig,
+};
 
 const CONFIG_KEY: &str = "proxy";
 const TIMEOUT: Duration = Duration::from_secs(30);
+pub(crate) const URL_HEADER: &str = "X-Forwarded-For";
+const AUTH_HEADER: &str = "X-Authorization";
 
-#[derive(serde::Deserialize, Clone, Debug, Default)]
-struct ProxyConfig {
+// An endpoint is marked unhealthy after FAIL_THRESHOLD consecutive failures,
+// and it will be tried again after UNHEALTHY_DURATION.
+const FAIL_THRESHOLD: usize = 3;
+const UNHEALTHY_DURATION: Duration = Duration::from_secs(60);
+
+#[derive(serde::Deserialize, Clone, Debug)]
+#[serde(untagged)]
+enum ProxyConfig {
+    Multi {
+        endpoints: Vec<EndpointConfig>,
+        #[serde(default)]
+        fallback_direct: bool,
+    },
+    Single(EndpointConfig),
+}
+
+#[derive(serde::Deserialize, Clone, Debug)]
+struct EndpointConfig {
     endpoint: String,
     authorization: String,
+    #[serde(default = "default_weight")]
+    weight: usize,
+}
+
+fn default_weight() -> usize {
+    1
+}
+
+/// Original url of the request, it is in the header if sent through proxy.
+pub(crate) fn target_url(request: &Request) -> reqwest::Url {
+    request
+        .headers()
+        .get(URL_HEADER)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| reqwest::Url::parse(v).ok())
+        .unwrap_or_else(|| request.url().clone())
 }
 
 /// RequestBuilder helps create a Request with proxy.
+/// Requests are distributed to healthy endpoints by weight, and failed ones
+/// will be retried on another endpoint when sent with `send` if possible.
 /// Note: Users should not replace headers.
 #[derive(Debug, Clone, Default)]
 pub struct ProxiedClient {
-    proxy: Option<Proxy>,
+    proxy: Option<Arc<ProxyPool>>,
+    tls: Option<Arc<ClientConfig>>,
+    headers: Option<HeaderMap>,
     inner: reqwest::Client,
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug)]
 pub struct Proxy {
     endpoint: reqwest::Url,
     authorization: HeaderValue,
+    failures: AtomicUsize,
+    unhealthy_until: Mutex<Option<Instant>>,
 }
 
-impl ProxiedClient {
-    pub fn new(endpoint: &str, authorization: &str) -> Self {
-        let proxy = Some(Proxy {
+impl Proxy {
+    fn new(endpoint: &str, authorization: &str) -> Self {
+        Self {
             endpoint: endpoint.parse().expect("unable to parse proxy endpoint"),
             authorization: authorization
                 .parse()
                 .expect("unable to parse proxy authorization"),
-        });
+            failures: AtomicUsize::new(0),
+            unhealthy_until: Mutex::new(None),
+        }
+    }
+
+    fn is_healthy(&self) -> bool {
+        match *self.unhealthy_until.lock() {
+            Some(until) => until <= Instant::now(),
+            None => true,
+        }
+    }
+
+    fn report(&self, success: bool) {
+        if success {
+            self.failures.store(0, Ordering::Relaxed);
+            *self.unhealthy_until.lock() = None;
+            return;
+        }
+        if self.failures.fetch_add(1, Ordering::Relaxed) + 1 >= FAIL_THRESHOLD {
+            tracing::warn!("[proxy] endpoint {} is marked unhealthy", self.endpoint);
+            self.failures.store(0, Ordering::Relaxed);
+            *self.unhealthy_until.lock() = Some(Instant::now() + UNHEALTHY_DURATION);
+        }
+    }
+}
+
+#[derive(Debug)]
+struct ProxyPool {
+    proxies: Vec<Proxy>,
+    // Proxy indexes, each one appears `weight` times.
+    schedule: Vec<usize>,
+    next: AtomicUsize,
+    fallback_direct: bool,
+}
+
+impl ProxyPool {
+    fn new(endpoints: Vec<(Proxy, usize)>, fallback_direct: bool) -> Self {
+        assert!(!endpoints.is_empty(), "proxy endpoints can not be empty");
+        let mut proxies = Vec::with_capacity(endpoints.len());
+        let mut schedule = Vec::new();
+        for (idx, (proxy, weight)) in endpoints.into_iter().enumerate() {
+            proxies.push(proxy);
+            schedule.extend(std::iter::repeat_n(idx, weight));
+        }
+        assert!(!schedule.is_empty(), "proxy weights can not be all zero");
         Self {
-            proxy,
-            inner: reqwest::Client::builder()
-                .timeout(TIMEOUT)
-                .build()
-                .expect("unable to build reqwest client"),
+            proxies,
+            schedule,
+            next: AtomicUsize::new(0),
+            fallback_direct,
+        }
+    }
+
+    /// Select a healthy proxy in round-robin, `None` means direct connection.
+    /// If all proxies are unhealthy and direct fallback is not allowed, we
+    /// still return one of them.
+    fn select(&self, exclude: Option<usize>) -> Option<usize> {
+        let start = self.next.fetch_add(1, Ordering::Relaxed);
+        let candidate = (0..self.schedule.len())
+            .map(|n| self.schedule[(start + n) % self.schedule.len()])
+            .filter(|idx| Some(*idx) != exclude)
+            .find(|idx| self.proxies[*idx].is_healthy());
+        match candidate {
+            Some(idx) => Some(idx),
+            None if self.fallback_direct => None,
+            None => Some(self.schedule[start % self.schedule.len()]),
+        }
+    }
+
+    fn position(&self, request: &Request) -> Option<usize> {
+        self.proxies
+            .iter()
+            .position(|p| p.endpoint == *request.url())
+    }
+}
+
+impl ProxiedClient {
+    pub fn new(endpoint: &str, authorization: &str) -> Self {
+        Self::new_multi(vec![(endpoint, authorization, 1)], false)
+    }
+
+    /// Create client with multiple (endpoint, authorization, weight).
+    pub fn new_multi(endpoints: Vec<(&str, &str, usize)>, fallback_direct: bool) -> Self {
+        let endpoints = endpoints
+            .into_iter()
+            .map(|(endpoint, authorization, weight)| (Proxy::new(endpoint, authorization), weight))
+            .collect();
+        Self {
+            proxy: Some(Arc::new(ProxyPool::new(endpoints, fallback_direct))),
+            tls: None,
+            headers: None,
+            inner: Self::build_inner(None, None),
         }
     }
 
     pub fn new_from_config() -> Self {
-        match config::parse::<ProxyConfig>(CONFIG_KEY)
+        let client = match config::parse::<ProxyConfig>(CONFIG_KEY)
             .expect("unable to parse proxy config(key is {CONFIG_KEY})")
         {
-            Some(cfg) => Self::new(&cfg.endpoint, &cfg.authorization),
+            Some(ProxyConfig::Single(cfg)) => Self::new(&cfg.endpoint, &cfg.authorization),
+            Some(ProxyConfig::Multi {
+                endpoints,
+                fallback_direct,
+            }) => Self::new_multi(
+                endpoints
+                    .iter()
+                    .map(|e| (e.endpoint.as_str(), e.authorization.as_str(), e.weight))
+                    .collect(),
+                fallback_direct,
+            ),
             None => {
                 tracing::warn!("initialized ProxiedClient without proxy config");
                 Self::default()
             }
+        };
+        match TlsConfig::from_config()
+            .and_then(|c| c.client_config())
+            .expect("unable to parse tls config")
+        {
+            Some(tls) => client.with_tls(tls),
+            None => client,
         }
     }
 
-    pub fn with_default_headers(self, headers: reqwest::header::HeaderMap) -> Self {
+    pub fn with_default_headers(self, headers: HeaderMap) -> Self {
         Self {
-            inner: reqwest::Client::builder()
-                .timeout(TIMEOUT)
-                .default_headers(headers)
-                .build()
-                .expect("unable to build reqwest client"),
+            inner: Self::build_inner(Some(headers.clone()), self.tls.as_deref().cloned()),
+            headers: Some(headers),
             ..self
         }
     }
+
+    /// Use custom TLS config, see `tls::TlsConfig`.
+    pub fn with_tls(self, tls: ClientConfig) -> Self {
+        Self {
+            inner: Self::build_inner(self.headers.clone(), Some(tls.clone())),
+            tls: Some(Arc::new(tls)),
+            ..self
+        }
+    }
+
+    fn build_inner(headers: Option<HeaderMap>, tls: Option<ClientConfig>) -> reqwest::Client {
+        let mut builder = reqwest::Client::builder().timeout(TIMEOUT);
+        if let Some(headers) = headers {
+            builder = builder.default_headers(headers);
+        }
+        if let Some(tls) = tls {
+            builder = builder.use_preconfigured_tls(tls);
+        }
+        builder.build().expect("unable to build reqwest client")
+    }
+
+    /// Send request, and retry it on another endpoint if possible.
+    /// Idempotent requests are retried on failure, and other requests are only
+    /// retried when the connection is not established.
+    pub async fn send(&self, builder: reqwest::RequestBuilder) -> reqwest::Result<Response> {
+        let pool = match &self.proxy {
+            Some(p) => p,
+            None => return builder.send().await,
+        };
+        let request = builder.build()?;
+        let current = match pool.position(&request) {
+            Some(idx) => idx,
+            // direct request
+            None => return self.inner.execute(request).await,
+        };
+        let backup = request.try_clone();
+        let idempotent = matches!(
+            *request.method(),
+            Method::GET | Method::HEAD | Method::PUT | Method::DELETE | Method::OPTIONS
+        );
+
+        let result = self.inner.execute(request).await;
+        let (failed, retryable) = match &result {
+            Ok(resp) => {
+                let failed = resp.status().as_u16() == 429 || resp.status().is_server_error();
+                (failed, failed && idempotent)
+            }
+            Err(e) => (true, idempotent || e.is_connect()),
+        };
+        pool.proxies[current].report(!failed);
+
+        let mut request = match backup {
+            Some(r) if retryable => r,
+            _ => return result,
+        };
+        match pool.select(Some(current)) {
+            Some(idx) if idx == current => result,
+            Some(idx) => {
+                let proxy = &pool.proxies[idx];
+                tracing::warn!("[proxy] retry request with endpoint {}", proxy.endpoint);
+                *request.url_mut() = proxy.endpoint.clone();
+                request
+                    .headers_mut()
+                    .insert(AUTH_HEADER, proxy.authorization.clone());
+                let result = self.inner.execute(request).await;
+                let failed = match &result {
+                    Ok(resp) => resp.status().as_u16() == 429 || resp.status().is_server_error(),
+                    Err(_) => true,
+                };
+                proxy.report(!failed);
+                result
+            }
+            None => {
+                let url = match request
+                    .headers_mut()
+                    .remove(URL_HEADER)
+                    .and_then(|v| v.to_str().ok().and_then(|v| v.parse().ok()))
+                {
+                    Some(u) => u,
+                    None => return result,
+                };
+                tracing::warn!("[proxy] all endpoints are unhealthy, fallback to direct");
+                request.headers_mut().remove(AUTH_HEADER);
+                *request.url_mut() = url;
+                self.inner.execute(request).await
+            }
+        }
+    }
 }
 
 macro_rules! impl_method {
-    ($method: ident) => {
+    ($method: ident, $name: ident) => {
         pub fn $method(&self, url: &str) -> reqwest::RequestBuilder {
-            match &self.proxy {
-                Some(p) => self
-                    .inner
-                    .$method(p.endpoint.clone())
-                    .header("X-Forwarded-For", url)
-                    .header("X-Authorization", p.authorization.clone()),
-                None => self.inner.$method(url),
-            }
+            self.request(Method::$name, url)
         }
     };
 }
 
 impl ProxiedClient {
-    impl_method!(get);
-    impl_method!(post);
-    impl_method!(head);
-    impl_method!(put);
-    impl_method!(delete);
-    impl_method!(patch);
+    impl_method!(get, GET);
+    impl_method!(post, POST);
+    impl_method!(head, HEAD);
+    impl_method!(put, PUT);
+    impl_method!(delete, DELETE);
+    impl_method!(patch, PATCH);
 
     pub fn request(&self, method: reqwest::Method, url: &str) -> reqwest::RequestBuilder {
-        match &self.proxy {
+        let proxy = self
+            .proxy
+            .as_ref()
+            .and_then(|pool| pool.select(None).map(|idx| &pool.proxies[idx]));
+        match proxy {
             Some(p) => self
                 .inner
                 .request(method, p.endpoint.clone())
-                .header("X-Forwarded-For", url)
-                .header("X-Authorization", p.authorization.clone()),
+                .header(URL_HEADER, url)
+                .header(AUTH_HEADER, p.authorization.clone()),
             None => self.inner.request(method, url),
         }
     }
 }
+
+impl HttpRequestBuilder for ProxiedClient {
+    #[inline]
+    fn get_builder(&self, url: &str) -> reqwest::RequestBuilder {
+        self.request(Method::GET, url)
+            .header(reqwest::header::USER_AGENT, rand_ua())
+    }
+
+    #[inline]
+    fn post_builder(&self, url: &str) -> reqwest::RequestBuilder {
+        self.request(Method::POST, url)
+            .header(reqwest::header::USER_AGENT, rand_ua())
+    }
+
+    #[inline]
+    async fn send_raw(&self, builder: reqwest::RequestBuilder) -> reqwest::Result<Response> {
+        self.send(builder).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Proxy, ProxyPool, FAIL_THRESHOLD};
+
+    fn pool(fallback_direct: bool) -> ProxyPool {
+        ProxyPool::new(
+            vec![
+                (Proxy::new("https://a.workers.dev/", "a"), 2),
+                (Proxy::new("https://b.workers.dev/", "b"), 1),
+            ],
+            fallback_direct,
+        )
+    }
+
+    #[test]
+    fn weighted_failover() {
+        let pool = pool(true);
+        let selected = (0..3).map(|_| pool.select(None)).collect::<Vec<_>>();
+        assert_eq!(selected, vec![Some(0), Some(0), Some(1)]);
+        assert_eq!(pool.select(Some(0)), Some(1));
+
+        for _ in 0..FAIL_THRESHOLD {
+            pool.proxies[0].report(false);
+        }
+        assert!((0..3).all(|_| pool.select(None) == Some(1)));
+
+        for _ in 0..FAIL_THRESHOLD {
+            pool.proxies[1].report(false);
+        }
+        assert_eq!(pool.select(None), None);
+        let pool_no_fallback = self::pool(false);
+        for p in pool_no_fallback.proxies.iter() {
+            for _ in 0..FAIL_THRESHOLD {
+                p.report(false);
+            }
+        }
+        assert!(pool_no_fallback.select(None).is_some());
+    }
+}
@@ -0,0 +1,344 @@
+//! Record and replay http exchanges for offline tests.
+//!
+//! Tests open a `Fixture` and build their clients with it. By default the
+//! clients are `ReplayClient`s serving `fixtures/{name}.json` without network.
+//! Run with `EH2TELEGRAPH_RECORD=1` to send to the live sites instead, and
+//! the exchanges are saved to the fixture file.
+//! Requests are matched by method and original url(the proxy worker url is
+//! ignored). Repeated requests are served in the recorded order, and the last
+//! one is repeated. Unknown requests get status 599.
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+};
+
+use base64::Engine;
+use parking_lot::Mutex;
+use reqwest::{Method, Request, RequestBuilder, Response};
+
+use crate::{
+    http_client::{rand_ua, HttpRequestBuilder},
+    http_proxy::target_url,
+    outbound::OutboundClient,
+};
+
+const NOT_FOUND_STATUS: u16 = 599;
+/// Set it to record fixtures from the live sites.
+pub const RECORD_ENV: &str = "EH2TELEGRAPH_RECORD";
+
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone)]
+pub struct Exchange {
+    pub method: String,
+    pub url: String,
+    pub status: u16,
+    #[serde(default)]
+    pub headers: Vec<(String, String)>,
+    /// Text body, or base64 encoded if `base64` is true.
+    #[serde(default)]
+    pub body: String,
+    #[serde(default)]
+    pub base64: bool,
+}
+
+impl Exchange {
+    pub fn new(method: &str, url: &str, status: u16, body: &[u8]) -> Self {
+        let (body, base64) = match std::str::from_utf8(body) {
+            Ok(text) => (text.to_string(), false),
+            Err(_) => (base64::engine::general_purpose::STANDARD.encode(body), true),
+        };
+        Self {
+            method: method.to_string(),
+            url: url.to_string(),
+            status,
+            headers: Vec::new(),
+            body,
+            base64,
+        }
+    }
+
+    fn body_bytes(&self) -> Vec<u8> {
+        if self.base64 {
+            base64::engine::general_purpose::STANDARD
+                .decode(&self.body)
+                .unwrap_or_default()
+        } else {
+            self.body.clone().into_bytes()
+        }
+    }
+
+    fn to_response(&self) -> Response {
+        let mut builder = http::Response::builder().status(self.status);
+        for (name, value) in self.headers.iter() {
+            builder = builder.header(name, value);
+        }
+        builder
+            .body(self.body_bytes())
+            .expect("unable to build replay response")
+            .into()
+    }
+}
+
+fn request_key(request: &Request) -> (String, String) {
+    (
+        request.method().to_string(),
+        target_url(request).to_string(),
+    )
+}
+
+/// Client wrapper that records exchanges sent with `send_raw`.
+/// Clones share the recorded exchanges.
+#[derive(Debug, Clone)]
+pub struct Recorder<C> {
+    inner: C,
+    exchanges: Arc<Mutex<Vec<Exchange>>>,
+}
+
+impl<C> Recorder<C> {
+    pub fn new(inner: C) -> Self {
+        Self {
+            inner,
+            exchanges: Default::default(),
+        }
+    }
+
+    pub fn inner(&self) -> &C {
+        &self.inner
+    }
+
+    pub fn exchanges(&self) -> Vec<Exchange> {
+        self.exchanges.lock().clone()
+    }
+}
+
+impl<C: HttpRequestBuilder + Send> HttpRequestBuilder for Recorder<C> {
+    #[inline]
+    fn get_builder(&self, url: &str) -> RequestBuilder {
+        self.inner.get_builder(url)
+    }
+
+    #[inline]
+    fn post_builder(&self, url: &str) -> RequestBuilder {
+        self.inner.post_builder(url)
+    }
+
+    async fn send_raw(&self, builder: RequestBuilder) -> reqwest::Result<Response> {
+        self.send_with(builder, |b| self.inner.send_raw(b)).await
+    }
+}
+
+impl<C> Recorder<C> {
+    /// Send request with `send` and record the exchange.
+    pub async fn send_with<F, Fut>(
+        &self,
+        builder: RequestBuilder,
+        send: F,
+    ) -> reqwest::Result<Response>
+    where
+        F: FnOnce(RequestBuilder) -> Fut,
+        Fut: std::future::Future<Output = reqwest::Result<Response>>,
+    {
+        let (client, request) = builder.build_split();
+        let request = request?;
+        let (method, url) = request_key(&request);
+        let resp = send(RequestBuilder::from_parts(client, request)).await?;
+
+        let status = resp.status().as_u16();
+        let headers = resp
+            .headers()
+            .iter()
+            .filter_map(|(k, v)| Some((k.to_string(), v.to_str().ok()?.to_string())))
+            .collect();
+        let body = resp.bytes().await?;
+        let mut exchange = Exchange::new(&method, &url, status, &body);
+        exchange.headers = headers;
+        let resp = exchange.to_response();
+        self.exchanges.lock().push(exchange);
+        Ok(resp)
+    }
+}
+
+/// Client that serves recorded exchanges without network.
+#[derive(Debug, Clone, Default)]
+pub struct ReplayClient {
+    client: reqwest::Client,
+    exchanges: Arc<HashMap<(String, String), Vec<Exchange>>>,
+    served: Arc<Mutex<HashMap<(String, String), usize>>>,
+    missed: Arc<AtomicUsize>,
+}
+
+impl ReplayClient {
+    pub fn new(exchanges: Vec<Exchange>) -> Self {
+        let mut map: HashMap<_, Vec<_>> = HashMap::new();
+        for e in exchanges {
+            let key = (e.method.to_uppercase(), e.url.clone());
+            map.entry(key).or_default().push(e);
+        }
+        Self {
+            exchanges: Arc::new(map),
+            ..Default::default()
+        }
+    }
+
+    /// Load exchanges from a json fixture.
+    pub fn load<P: AsRef<Path>>(path: P) -> anyhow::Result<Self> {
+        let data = std::fs::read(path)?;
+        Ok(Self::new(serde_json::from_slice(&data)?))
+    }
+
+    /// Number of requests without a recorded exchange.
+    pub fn missed(&self) -> usize {
+        self.missed.load(Ordering::Relaxed)
+    }
+
+    fn serve(&self, request: &Request) -> Response {
+        let key = request_key(request);
+        let exchanges = match self.exchanges.get(&key) {
+            Some(e) => e,
+            None => {
+                tracing::warn!("[replay] no exchange for {} {}", key.0, key.1);
+                self.missed.fetch_add(1, Ordering::Relaxed);
+                return Exchange::new(&key.0, &key.1, NOT_FOUND_STATUS, b"").to_response();
+            }
+        };
+        let mut served = self.served.lock();
+        let n = served.entry(key).or_default();
+        let exchange = &exchanges[(*n).min(exchanges.len() - 1)];
+        *n += 1;
+        exchange.to_response()
+    }
+}
+
+impl HttpRequestBuilder for ReplayClient {
+    #[inline]
+    fn get_builder(&self, url: &str) -> RequestBuilder {
+        self.client
+            .request(Method::GET, url)
+            .header(reqwest::header::USER_AGENT, rand_ua())
+    }
+
+    #[inline]
+    fn post_builder(&self, url: &str) -> RequestBuilder {
+        self.client
+            .request(Method::POST, url)
+            .header(reqwest::header::USER_AGENT, rand_ua())
+    }
+
+    async fn send_raw(&self, builder: RequestBuilder) -> reqwest::Result<Response> {
+        let request = builder.build()?;
+        Ok(self.serve(&request))
+    }
+}
+
+/// Http fixture `fixtures/{name}.json` of a test.
+/// Recording overwrites the whole file, so tests should not share a fixture.
+pub struct Fixture {
+    path: PathBuf,
+    mode: FixtureMode,
+}
+
+enum FixtureMode {
+    Replay(ReplayClient),
+    Record(Mutex<Vec<Recorder<OutboundClient>>>),
+}
+
+impl Fixture {
+    /// Replay the fixture, or record it if `EH2TELEGRAPH_RECORD` is set.
+    pub fn open(name: &str) -> Self {
+        let path = Path::new(env!("CARGO_MANIFEST_DIR"))
+            .join("fixtures")
+            .join(format!("{name}.json"));
+        let recording = std::env::var_os(RECORD_ENV).is_some_and(|v| !v.is_empty());
+        let mode = if recording {
+            FixtureMode::Record(Default::default())
+        } else {
+            let replay = ReplayClient::load(&path)
+                .unwrap_or_else(|e| panic!("unable to load fixture {}: {e}", path.display()));
+            FixtureMode::Replay(replay)
+        };
+        Self { path, mode }
+    }
+
+    pub fn is_recording(&self) -> bool {
+        matches!(self.mode, FixtureMode::Record(_))
+    }
+
+    /// Client serving the fixture. In record mode, the client built by `live`
+    /// is used and recorded.
+    pub fn client<F: FnOnce() -> OutboundClient>(&self, live: F) -> OutboundClient {
+        match &self.mode {
+            FixtureMode::Replay(replay) => replay.clone().into(),
+            FixtureMode::Record(recorders) => {
+                let (client, recorder) = live().record();
+                recorders.lock().push(recorder);
+                client
+            }
+        }
+    }
+
+    /// Number of requests without a recorded exchange.
+    pub fn missed(&self) -> usize {
+        match &self.mode {
+            FixtureMode::Replay(replay) => replay.missed(),
+            FixtureMode::Record(_) => 0,
+        }
+    }
+}
+
+impl Drop for Fixture {
+    fn drop(&mut self) {
+        let FixtureMode::Record(recorders) = &self.mode else {
+            return;
+        };
+        if std::thread::panicking() {
+            return;
+        }
+        let exchanges: Vec<_> = recorders
+            .lock()
+            .iter()
+            .flat_map(Recorder::exchanges)
+            .collect();
+        let data = serde_json::to_vec_pretty(&exchanges).expect("unable to encode fixture");
+        std::fs::write(&self.path, data).expect("unable to save fixture");
+        tracing::info!("[replay] fixture saved to {}", self.path.display());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Exchange, Recorder, ReplayClient};
+    use crate::{http_client::HttpRequestBuilder, util::get_string};
+
+    #[tokio::test]
+    async fn record_and_replay() {
+        let replay = ReplayClient::new(vec![
+            Exchange::new("GET", "https://e-hentai.org/g/1/a", 200, b"first"),
+            Exchange::new("GET", "https://e-hentai.org/g/1/a", 200, b"second"),
+            Exchange::new("GET", "https://e-hentai.org/img.jpg", 200, &[0xff, 0xd8]),
+        ]);
+        // record the replay client itself
+        let recorder = Recorder::new(replay.clone());
+        let url = "https://e-hentai.org/g/1/a";
+        assert_eq!(get_string(&recorder, url).await.unwrap(), "first");
+        assert_eq!(get_string(&recorder, url).await.unwrap(), "second");
+        assert_eq!(get_string(&recorder, url).await.unwrap(), "second");
+        let resp = recorder
+            .execute(recorder.get_builder("https://e-hentai.org/img.jpg"))
+            .await
+            .unwrap();
+        assert_eq!(resp.bytes().await.unwrap().as_ref(), &[0xff, 0xd8]);
+        assert!(get_string(&recorder, "https://e-hentai.org/404")
+            .await
+            .is_err());
+        assert_eq!(replay.missed(), 1);
+
+        let exchanges = recorder.exchanges();
+        assert_eq!(exchanges.len(), 5);
+        assert!(exchanges[3].base64);
+        let replay = ReplayClient::new(exchanges);
+        assert_eq!(get_string(&replay, url).await.unwrap(), "first");
+    }
+}
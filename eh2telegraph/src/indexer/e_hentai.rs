@@ -0,0 +1,159 @@
+/// e-hentai indexer.
+/// Search galleries with the site search page.
+use reqwest::{header, Url};
+
+use crate::{
+    html::{Css, Document},
+    http_client::GhostClientBuilder,
+    outbound::{OutboundClient, OutboundPolicy},
+    util::get_string,
+};
+
+use super::{Entry, Filter, Indexer};
+
+lazy_static::lazy_static! {
+    static ref RESULTS: Css = Css::new(".itg");
+    static ref RESULT_LINKS: Css = Css::new(r#".itg a[href*="e-hentai.org/g/"]"#);
+    static ref RESULT_TITLE: Css = Css::new(".glink");
+}
+const SITE: &str = "e-hentai";
+const PAGE_NAME: &str = "e-hentai search page";
+const ALL_CATEGORIES: u16 = 1023;
+
+#[derive(Debug, Clone, Default)]
+pub struct EHIndexer {
+    client: OutboundClient,
+}
+
+impl EHIndexer {
+    pub fn new_from_config() -> anyhow::Result<Self> {
+        let mut request_headers = header::HeaderMap::new();
+        request_headers.insert(
+            header::COOKIE,
+            header::HeaderValue::from_str("nw=1").unwrap(),
+        );
+
+        let policy = OutboundPolicy::from_config(SITE, OutboundPolicy::Ghost)?;
+        let client = policy.build(
+            GhostClientBuilder::default()
+                .with_default_headers(request_headers)
+                .with_cf_resolve(&["e-hentai.org"]),
+        )?;
+        Ok(Self { client })
+    }
+}
+
+impl Indexer for EHIndexer {
+    type IndexError = anyhow::Error;
+
+    #[inline]
+    fn name() -> &'static str {
+        "e-hentai"
+    }
+
+    async fn index(&self, filters: &[Filter]) -> Result<Vec<Entry>, Self::IndexError> {
+        let url = search_url(filters)?;
+        tracing::info!("[e-hentai] search {url}");
+        let content = get_string(&self.client, url.as_str()).await?;
+        parse_results(&content)
+    }
+}
+
+// bit of each category in f_cats, a set bit excludes the category
+fn category_bit(category: &str) -> Option<u16> {
+    let bit = match category.to_ascii_lowercase().as_str() {
+        "misc" => 1,
+        "doujinshi" => 2,
+        "manga" => 4,
+        "artist cg" => 8,
+        "game cg" => 16,
+        "image set" => 32,
+        "cosplay" => 64,
+        "asian porn" => 128,
+        "non-h" => 256,
+        "western" => 512,
+        _ => return None,
+    };
+    Some(bit)
+}
+
+fn search_url(filters: &[Filter]) -> anyhow::Result<Url> {
+    let mut terms = Vec::with_capacity(filters.len());
+    let mut categories = 0;
+    for filter in filters {
+        match filter {
+            Filter::Name(name) => terms.push(name.clone()),
+            // exact match, like female:"big breasts$"
+            Filter::Tag(tag) => terms.push(match tag.split_once(':') {
+                Some((namespace, tag)) => format!("{namespace}:\"{tag}$\""),
+                None => format!("\"{tag}$\""),
+            }),
+            Filter::Category(category) => {
+                categories |= category_bit(category)
+                    .ok_or_else(|| anyhow::anyhow!("unknown e-hentai category {category}"))?;
+            }
+        }
+    }
+
+    let mut params = vec![("f_search", terms.join(" "))];
+    if categories != 0 {
+        params.push(("f_cats", (ALL_CATEGORIES ^ categories).to_string()));
+    }
+    Ok(Url::parse_with_params("https://e-hentai.org/", params)?)
+}
+
+fn parse_results(content: &str) -> anyhow::Result<Vec<Entry>> {
+    let doc = Document::parse(PAGE_NAME, content);
+    if !doc.exists(&RESULTS) {
+        if content.contains("No hits found") {
+            return Ok(Vec::new());
+        }
+        return Err(doc.layout_changed(&RESULTS).into());
+    }
+
+    let mut entries: Vec<Entry> = Vec::new();
+    // thumbnail layouts link a gallery twice, only the one with title is used
+    for node in doc.select(&RESULT_LINKS) {
+        let (Some(link), Some(title)) = (node.attr("href"), node.text_of(&RESULT_TITLE)) else {
+            continue;
+        };
+        if entries.iter().all(|e| e.link != link) {
+            entries.push(Entry {
+                title,
+                link: link.to_string(),
+            });
+        }
+    }
+    Ok(entries)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{parse_results, search_url, Filter};
+
+    #[test]
+    fn search() {
+        let url = search_url(&[
+            Filter::Tag("female:big breasts".to_string()),
+            Filter::Category("Manga".to_string()),
+        ])
+        .unwrap();
+        assert_eq!(
+            url.as_str(),
+            "https://e-hentai.org/?f_search=female%3A%22big+breasts%24%22&f_cats=1019"
+        );
+
+        let h = r#"<table class="itg gltc"><tr><td class="gl1c glcat"><div class="cn ct2">Doujinshi</div></td><td class="gl3c glname"><a href="https://e-hentai.org/g/2122174/fd2525031e/"><div class="glink">A &amp; B</div></a></td></tr><tr><td class="gl3c glname"><a href="https://e-hentai.org/g/2127986/da1deffea5/"><div class="glink">C</div></a></td></tr></table>"#;
+        let entries = parse_results(h).unwrap();
+        assert_eq!(entries.len(), 2);
+        assert_eq!(entries[0].title, "A & B");
+        assert_eq!(
+            entries[1].link,
+            "https://e-hentai.org/g/2127986/da1deffea5/"
+        );
+
+        assert!(parse_results("<p>No hits found</p>").unwrap().is_empty());
+        let err = parse_results("<html></html>").unwrap_err();
+        assert!(err.is::<crate::html::LayoutChanged>());
+    }
+}
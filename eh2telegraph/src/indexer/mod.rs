@@ -1,9 +1,15 @@
-// Indexer + Filters(FilterType+Value) -> EntryStream
+// Indexer + Filters(FilterType+Value) -> Entries
+
+use std::future::Future;
+
+pub mod e_hentai;
 
 #[derive(Debug, Clone)]
 pub enum Filter {
     Name(String),
     Category(String),
+    /// Namespaced tag like `female:glasses`.
+    Tag(String),
 }
 
 #[derive(Debug, Clone)]
@@ -12,4 +18,20 @@ pub enum OrderBy {
     ClickDesc,
 }
 
-pub trait Indexer {}
+/// An album found by indexer.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Entry {
+    pub title: String,
+    pub link: String,
+}
+
+pub trait Indexer {
+    type IndexError;
+
+    fn name() -> &'static str;
+    /// Search albums matching all filters, returns entries of the first result page.
+    fn index(
+        &self,
+        filters: &[Filter],
+    ) -> impl Future<Output = Result<Vec<Entry>, Self::IndexError>>;
+}
@@ -1,15 +1,22 @@
-#![feature(type_alias_impl_trait)]
 #![feature(impl_trait_in_assoc_type)]
 
 #[macro_use]
 pub mod telegraph;
 
 pub mod buffer;
+pub mod circuit_breaker;
 pub mod collector;
 pub mod config;
+pub mod dns;
+pub mod html;
 pub mod http_client;
 pub mod http_proxy;
+#[cfg(test)]
+mod http_replay;
 pub mod indexer;
+pub mod outbound;
+pub mod queue;
+pub mod rate_limit;
 pub mod searcher;
 pub mod storage;
 pub mod stream;
@@ -0,0 +1,293 @@
+//! Per-site outbound policy.
+//!
+//! Config example:
+//! ```yaml
+//! outbound:
+//!   e-hentai: ghost
+//!   exhentai: socks5://127.0.0.1:1080
+//!   nhentai: worker
+//!   saucenao: direct
+//! ```
+use std::{collections::HashMap, time::Duration};
+
+use reqwest::{header::HeaderMap, Response};
+
+#[cfg(test)]
+use crate::http_replay::{Recorder, ReplayClient};
+use crate::{
+    config,
+    dns::DnsConfig,
+    http_client::{GhostClient, GhostClientBuilder, HttpRequestBuilder},
+    http_proxy::ProxiedClient,
+};
+
+const CONFIG_KEY: &str = "outbound";
+const TIMEOUT: Duration = Duration::from_secs(30);
+
+/// How requests to a site are sent.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum OutboundPolicy {
+    /// Connect directly.
+    Direct,
+    /// Connect with random ipv6 from `http.ipv6_prefix`.
+    Ghost,
+    /// Send with the `proxy` worker.
+    Worker,
+    /// Send with http/https/socks5 proxy url.
+    Proxy(String),
+}
+
+impl<'de> serde::Deserialize<'de> for OutboundPolicy {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: serde::Deserializer<'de>,
+    {
+        let data = String::deserialize(deserializer)?;
+        match data.as_str() {
+            "direct" => Ok(Self::Direct),
+            "ghost" => Ok(Self::Ghost),
+            "worker" => Ok(Self::Worker),
+            url if url.contains("://") => Ok(Self::Proxy(url.to_string())),
+            _ => Err(serde::de::Error::custom(format!(
+                "invalid outbound policy {data}, expect direct, ghost, worker or a proxy url"
+            ))),
+        }
+    }
+}
+
+impl OutboundPolicy {
+    /// Read policy of the site from config, returns default if not set.
+    pub fn from_config(site: &str, default: Self) -> anyhow::Result<Self> {
+        let mut policies: HashMap<String, Self> = config::parse(CONFIG_KEY)?.unwrap_or_default();
+        Ok(policies.remove(site).unwrap_or(default))
+    }
+
+    /// Build client for site pages. Headers in builder are kept. Resolve rules in
+    /// builder are only for ghost, direct and proxy clients use DNS config instead,
+    /// and worker resolves domains itself.
+    pub fn build(&self, builder: GhostClientBuilder) -> anyhow::Result<OutboundClient> {
+        let client = match self {
+            Self::Direct => OutboundClient::Raw(
+                Self::raw_builder(builder.headers(), &DnsConfig::from_config()?).build()?,
+            ),
+            Self::Ghost => OutboundClient::Ghost(builder.build_from_config()?),
+            Self::Worker => {
+                let mut client = ProxiedClient::new_from_config();
+                if let Some(headers) = builder.headers() {
+                    client = client.with_default_headers(headers.clone());
+                }
+                OutboundClient::Worker(client)
+            }
+            Self::Proxy(url) => OutboundClient::Raw(
+                Self::raw_builder(builder.headers(), &DnsConfig::from_config()?)
+                    .proxy(reqwest::Proxy::all(url)?)
+                    .build()?,
+            ),
+        };
+        Ok(client)
+    }
+
+    /// Build client for images. Image CDNs are not behind the site, so ghost
+    /// policy connects them directly.
+    pub fn build_image(&self, page_client: &OutboundClient) -> anyhow::Result<OutboundClient> {
+        match self {
+            Self::Ghost => Ok(OutboundClient::Raw(
+                Self::raw_builder(None, &DnsConfig::from_config()?).build()?,
+            )),
+            _ => Ok(page_client.clone()),
+        }
+    }
+
+    // The host may have no ipv6 route, so only overrides of the preferred family
+    // are used. Proxy clients resolve locally for socks5, so they are the same.
+    fn raw_builder(headers: Option<&HeaderMap>, dns: &DnsConfig) -> reqwest::ClientBuilder {
+        let mut builder = reqwest::Client::builder().timeout(TIMEOUT);
+        if let Some(headers) = headers {
+            builder = builder.default_headers(headers.clone());
+        }
+        for (domain, addrs) in dns.overrides_of(dns.prefer) {
+            builder = builder.resolve_to_addrs(domain, &addrs);
+        }
+        if let Some(resolver) = dns.resolver() {
+            builder = builder.dns_resolver(resolver);
+        }
+        builder
+    }
+}
+
+/// Client built by OutboundPolicy.
+/// Cloning a ghost client changes its ip, and others are cheap to clone.
+/// `Record` and `Replay` are for tests, see `http_replay`.
+#[derive(Debug, Clone)]
+pub enum OutboundClient {
+    Raw(reqwest::Client),
+    Ghost(GhostClient),
+    Worker(ProxiedClient),
+    #[cfg(test)]
+    Record(Box<Recorder<OutboundClient>>),
+    #[cfg(test)]
+    Replay(ReplayClient),
+}
+
+impl OutboundClient {
+    /// Record exchanges of this client.
+    #[cfg(test)]
+    pub fn record(self) -> (Self, Recorder<OutboundClient>) {
+        let inner = match self {
+            Self::Record(r) => r.inner().clone(),
+            c => c,
+        };
+        let recorder = Recorder::new(inner);
+        (Self::Record(Box::new(recorder.clone())), recorder)
+    }
+
+    // Send without recording. `record` unwraps the inner client, so a recorder
+    // never wraps another one.
+    async fn send_leaf(&self, builder: reqwest::RequestBuilder) -> reqwest::Result<Response> {
+        match self {
+            Self::Raw(c) => c.send_raw(builder).await,
+            Self::Ghost(c) => c.send_raw(builder).await,
+            Self::Worker(c) => c.send(builder).await,
+            #[cfg(test)]
+            Self::Replay(c) => c.send_raw(builder).await,
+            #[cfg(test)]
+            Self::Record(_) => unreachable!("recorders are never nested"),
+        }
+    }
+}
+
+impl Default for OutboundClient {
+    fn default() -> Self {
+        Self::Ghost(GhostClient::default())
+    }
+}
+
+impl From<reqwest::Client> for OutboundClient {
+    fn from(c: reqwest::Client) -> Self {
+        Self::Raw(c)
+    }
+}
+
+impl From<GhostClient> for OutboundClient {
+    fn from(c: GhostClient) -> Self {
+        Self::Ghost(c)
+    }
+}
+
+impl From<ProxiedClient> for OutboundClient {
+    fn from(c: ProxiedClient) -> Self {
+        Self::Worker(c)
+    }
+}
+
+#[cfg(test)]
+impl From<ReplayClient> for OutboundClient {
+    fn from(c: ReplayClient) -> Self {
+        Self::Replay(c)
+    }
+}
+
+impl HttpRequestBuilder for OutboundClient {
+    #[inline]
+    fn get_builder(&self, url: &str) -> reqwest::RequestBuilder {
+        match self {
+            Self::Raw(c) => c.get_builder(url),
+            Self::Ghost(c) => c.get_builder(url),
+            Self::Worker(c) => c.get_builder(url),
+            #[cfg(test)]
+            Self::Record(c) => c.get_builder(url),
+            #[cfg(test)]
+            Self::Replay(c) => c.get_builder(url),
+        }
+    }
+
+    #[inline]
+    fn post_builder(&self, url: &str) -> reqwest::RequestBuilder {
+        match self {
+            Self::Raw(c) => c.post_builder(url),
+            Self::Ghost(c) => c.post_builder(url),
+            Self::Worker(c) => c.post_builder(url),
+            #[cfg(test)]
+            Self::Record(c) => c.post_builder(url),
+            #[cfg(test)]
+            Self::Replay(c) => c.post_builder(url),
+        }
+    }
+
+    #[inline]
+    async fn send_raw(&self, builder: reqwest::RequestBuilder) -> reqwest::Result<Response> {
+        match self {
+            #[cfg(test)]
+            Self::Record(c) => c.send_with(builder, |b| c.inner().send_leaf(b)).await,
+            c => c.send_leaf(builder).await,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        io::{Read, Write},
+        net::TcpListener,
+    };
+
+    use super::{OutboundClient, OutboundPolicy};
+    use crate::{dns::DnsConfig, http_client::HttpRequestBuilder};
+
+    #[test]
+    fn parse_policy() {
+        let parse = |s: &str| serde_yaml::from_str::<OutboundPolicy>(s);
+        assert_eq!(parse("direct").unwrap(), OutboundPolicy::Direct);
+        assert_eq!(parse("ghost").unwrap(), OutboundPolicy::Ghost);
+        assert_eq!(parse("worker").unwrap(), OutboundPolicy::Worker);
+        assert_eq!(
+            parse("socks5://127.0.0.1:1080").unwrap(),
+            OutboundPolicy::Proxy("socks5://127.0.0.1:1080".to_string())
+        );
+        assert!(parse("unknown").is_err());
+    }
+
+    #[tokio::test]
+    async fn direct_uses_dns_overrides() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        std::thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let _ = stream.read(&mut [0; 1024]).unwrap();
+            stream
+                .write_all(b"HTTP/1.1 200 OK\r\ncontent-length: 2\r\n\r\nok")
+                .unwrap();
+        });
+
+        let dns = DnsConfig {
+            overrides: [("eh2telegraph.invalid".to_string(), vec![addr])].into(),
+            ..Default::default()
+        };
+        let client: OutboundClient = OutboundPolicy::raw_builder(None, &dns)
+            .build()
+            .unwrap()
+            .into();
+        let url = format!("http://eh2telegraph.invalid:{}/", addr.port());
+        let body = client
+            .send_raw(client.get_builder(&url))
+            .await
+            .unwrap()
+            .text()
+            .await
+            .unwrap();
+        assert_eq!(body, "ok");
+    }
+
+    #[test]
+    fn ipv4_direct_has_no_ipv6_override() {
+        let dns: DnsConfig = serde_yaml::from_str(
+            "prefer: ipv4\noverrides:\n  e-hentai.org: [\"[2606:4700:4700::1111]:443\"]\n  exhentai.org: [\"[2606:4700:4700::1111]:443\", \"1.1.1.1:443\"]",
+        )
+        .unwrap();
+        let overrides = dns.overrides_of(dns.prefer);
+        assert_eq!(
+            overrides,
+            [("exhentai.org", vec!["1.1.1.1:443".parse().unwrap()])]
+        );
+    }
+}
@@ -0,0 +1,383 @@
+//! Job queue with per-owner fairness and a priority lane.
+//!
+//! Priority jobs are served first. Other jobs are served round-robin by owner,
+//! so one owner can not block others by sending many jobs.
+//! Jobs can be persisted with `JobStore` and restored after restart.
+use std::collections::{HashMap, VecDeque};
+
+use parking_lot::Mutex;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use tokio::sync::Notify;
+
+use crate::{config, storage::local::LocalStorage};
+
+const CONFIG_KEY: &str = "queue";
+const DEFAULT_WORKERS: usize = 4;
+const DEFAULT_MAX_LEN: usize = 100;
+const JOB_PREFIX: &str = "job:";
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct QueueConfig {
+    /// Jobs run concurrently.
+    #[serde(default = "default_workers")]
+    pub workers: usize,
+    /// Max queued jobs, priority jobs are not limited.
+    #[serde(default = "default_max_len")]
+    pub max_len: usize,
+    /// Path of the database to persist queued jobs. Jobs are lost on restart
+    /// if not set.
+    #[serde(default)]
+    pub path: Option<String>,
+}
+
+fn default_workers() -> usize {
+    DEFAULT_WORKERS
+}
+
+fn default_max_len() -> usize {
+    DEFAULT_MAX_LEN
+}
+
+impl Default for QueueConfig {
+    fn default() -> Self {
+        Self {
+            workers: DEFAULT_WORKERS,
+            max_len: DEFAULT_MAX_LEN,
+            path: None,
+        }
+    }
+}
+
+impl QueueConfig {
+    pub fn from_config() -> anyhow::Result<Self> {
+        Ok(config::parse(CONFIG_KEY)?.unwrap_or_default())
+    }
+}
+
+#[derive(thiserror::Error, Debug)]
+#[error("queue is full, at most {0} jobs can be queued")]
+pub struct QueueFull(pub usize);
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Job<T> {
+    pub id: u64,
+    pub owner: i64,
+    pub priority: bool,
+    pub data: T,
+}
+
+#[derive(Debug)]
+struct State<T> {
+    priority: VecDeque<Job<T>>,
+    // owners with queued jobs in serving order
+    owners: VecDeque<i64>,
+    jobs: HashMap<i64, VecDeque<Job<T>>>,
+    next_id: u64,
+    // workers waiting for jobs
+    idle: usize,
+}
+
+impl<T> State<T> {
+    fn normal_len(&self) -> usize {
+        self.jobs.values().map(VecDeque::len).sum()
+    }
+
+    fn insert(&mut self, job: Job<T>) {
+        if job.priority {
+            self.priority.push_back(job);
+        } else {
+            let owner = job.owner;
+            let jobs = self.jobs.entry(owner).or_default();
+            jobs.push_back(job);
+            if jobs.len() == 1 {
+                self.owners.push_back(owner);
+            }
+        }
+    }
+
+    fn pop(&mut self) -> Option<Job<T>> {
+        if let Some(job) = self.priority.pop_front() {
+            return Some(job);
+        }
+        let owner = self.owners.pop_front()?;
+        let jobs = self.jobs.get_mut(&owner)?;
+        let job = jobs.pop_front();
+        if jobs.is_empty() {
+            self.jobs.remove(&owner);
+        } else {
+            self.owners.push_back(owner);
+        }
+        job
+    }
+
+    fn remove(&mut self, id: u64) -> Option<Job<T>> {
+        if let Some(idx) = self.priority.iter().position(|j| j.id == id) {
+            return self.priority.remove(idx);
+        }
+        let (owner, jobs) = self
+            .jobs
+            .iter_mut()
+            .find(|(_, jobs)| jobs.iter().any(|j| j.id == id))?;
+        let owner = *owner;
+        let idx = jobs.iter().position(|j| j.id == id)?;
+        let job = jobs.remove(idx);
+        if jobs.is_empty() {
+            self.jobs.remove(&owner);
+            self.owners.retain(|o| *o != owner);
+        }
+        job
+    }
+
+    fn position(&self, id: u64) -> Option<usize> {
+        if let Some(idx) = self.priority.iter().position(|j| j.id == id) {
+            return Some(idx + 1);
+        }
+        let (owner_idx, job_idx) = self.owners.iter().enumerate().find_map(|(i, owner)| {
+            let idx = self.jobs.get(owner)?.iter().position(|j| j.id == id)?;
+            Some((i, idx))
+        })?;
+        // jobs served in earlier rounds, and in the same round before this owner
+        let mut ahead = self.priority.len();
+        for (i, owner) in self.owners.iter().enumerate() {
+            let len = self.jobs.get(owner).map(VecDeque::len).unwrap_or_default();
+            ahead += len.min(job_idx);
+            if i < owner_idx && len > job_idx {
+                ahead += 1;
+            }
+        }
+        Some(ahead + 1)
+    }
+}
+
+#[derive(Debug)]
+pub struct JobQueue<T> {
+    state: Mutex<State<T>>,
+    notify: Notify,
+    max_len: usize,
+}
+
+impl<T> JobQueue<T> {
+    pub fn new(max_len: usize) -> Self {
+        Self {
+            state: Mutex::new(State {
+                priority: VecDeque::new(),
+                owners: VecDeque::new(),
+                jobs: HashMap::new(),
+                next_id: 0,
+                idle: 0,
+            }),
+            notify: Notify::new(),
+            max_len,
+        }
+    }
+
+    /// Queue a job, returns its id and 1-based position.
+    pub fn push(&self, owner: i64, priority: bool, data: T) -> Result<(u64, usize), QueueFull> {
+        let id = self.reserve_id();
+        let position = self.push_job(Job {
+            id,
+            owner,
+            priority,
+            data,
+        })?;
+        Ok((id, position))
+    }
+
+    /// Take an id for a job which is queued later with `push_job`, so the job
+    /// can be saved before workers see it.
+    pub fn reserve_id(&self) -> u64 {
+        let mut state = self.state.lock();
+        let id = state.next_id;
+        state.next_id += 1;
+        id
+    }
+
+    /// Queue a job with a reserved id, returns its 1-based position.
+    pub fn push_job(&self, job: Job<T>) -> Result<usize, QueueFull> {
+        let mut state = self.state.lock();
+        if !job.priority && state.normal_len() >= self.max_len {
+            return Err(QueueFull(self.max_len));
+        }
+        let id = job.id;
+        state.insert(job);
+        let position = state.position(id).expect("job is just pushed");
+        drop(state);
+        self.notify.notify_one();
+        Ok(position)
+    }
+
+    /// Queue a job restored from `JobStore`, it is not limited by max_len.
+    /// Later pushed jobs get larger ids than restored ones.
+    pub fn restore(&self, job: Job<T>) {
+        let mut state = self.state.lock();
+        state.next_id = state.next_id.max(job.id + 1);
+        state.insert(job);
+        drop(state);
+        self.notify.notify_one();
+    }
+
+    pub fn pop(&self) -> Option<Job<T>> {
+        self.state.lock().pop()
+    }
+
+    /// Wait for the next job.
+    pub async fn next(&self) -> Job<T> {
+        loop {
+            {
+                let mut state = self.state.lock();
+                if let Some(job) = state.pop() {
+                    return job;
+                }
+                state.idle += 1;
+            }
+            self.notify.notified().await;
+            self.state.lock().idle -= 1;
+        }
+    }
+
+    /// Remove a queued job, returns None if it is not queued.
+    pub fn remove(&self, id: u64) -> Option<Job<T>> {
+        self.state.lock().remove(id)
+    }
+
+    /// 1-based position of a queued job.
+    pub fn position(&self, id: u64) -> Option<usize> {
+        self.state.lock().position(id)
+    }
+
+    /// Number of workers waiting for jobs.
+    pub fn idle(&self) -> usize {
+        self.state.lock().idle
+    }
+
+    pub fn len(&self) -> usize {
+        let state = self.state.lock();
+        state.priority.len() + state.normal_len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+/// Persisted jobs, which are saved when queued and removed when finished.
+#[derive(Clone, Debug)]
+pub struct JobStore(LocalStorage);
+
+impl JobStore {
+    pub fn new(storage: LocalStorage) -> Self {
+        Self(storage)
+    }
+
+    /// Returns None if `queue.path` is not set.
+    pub fn new_from_config() -> anyhow::Result<Option<Self>> {
+        QueueConfig::from_config()?
+            .path
+            .map(|path| Ok(Self(LocalStorage::new(path)?)))
+            .transpose()
+    }
+
+    pub async fn save<T: Serialize>(&self, job: &Job<T>) -> anyhow::Result<()> {
+        let data = serde_json::to_vec(job)?;
+        self.0.set_raw(job_key(job.id), data, None).await
+    }
+
+    pub async fn remove(&self, id: u64) -> anyhow::Result<()> {
+        self.0.delete_raw(&job_key(id)).await
+    }
+
+    /// Load all saved jobs in queued order.
+    pub async fn load<T: DeserializeOwned>(&self) -> anyhow::Result<Vec<Job<T>>> {
+        let mut jobs = Vec::new();
+        for key in self.0.keys(JOB_PREFIX).await? {
+            let Some(data) = self.0.get_raw(&key).await? else {
+                continue;
+            };
+            match serde_json::from_slice(&data) {
+                Ok(job) => jobs.push(job),
+                Err(e) => tracing::error!("[queue] unable to load job {key}: {e:?}"),
+            }
+        }
+        // keys are zero padded, so they are already sorted by id
+        Ok(jobs)
+    }
+}
+
+#[inline]
+fn job_key(id: u64) -> String {
+    format!("{JOB_PREFIX}{id:020}")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Job, JobQueue, JobStore};
+    use crate::storage::local::LocalStorage;
+
+    #[test]
+    fn fairness_and_priority() {
+        let queue = JobQueue::new(4);
+        let a1 = queue.push(1, false, "a1").unwrap();
+        let a2 = queue.push(1, false, "a2").unwrap();
+        let a3 = queue.push(1, false, "a3").unwrap();
+        let b1 = queue.push(2, false, "b1").unwrap();
+        assert_eq!((a1.1, a2.1, a3.1, b1.1), (1, 2, 3, 2));
+        assert!(queue.push(2, false, "b2").is_err());
+        let admin = queue.push(3, true, "admin").unwrap();
+        assert_eq!(admin.1, 1);
+        assert_eq!(queue.position(b1.0), Some(3));
+        assert_eq!(queue.position(a3.0), Some(5));
+
+        let order = std::iter::from_fn(|| queue.pop().map(|j| j.data)).collect::<Vec<_>>();
+        assert_eq!(order, ["admin", "a1", "b1", "a2", "a3"]);
+        assert!(queue.is_empty());
+
+        let c1 = queue.push(5, false, "c1").unwrap();
+        assert_eq!(queue.remove(c1.0).unwrap().data, "c1");
+        assert!(queue.remove(c1.0).is_none());
+        assert!(queue.pop().is_none());
+    }
+
+    #[test]
+    fn reserve_then_push() {
+        let queue = JobQueue::new(1);
+        let id = queue.reserve_id();
+        // reserved jobs are invisible until pushed
+        assert!(queue.pop().is_none());
+        let (other, _) = queue.push(1, false, "a").unwrap();
+        assert_ne!(id, other);
+        let job = Job {
+            id,
+            owner: 2,
+            priority: false,
+            data: "b",
+        };
+        assert!(queue.push_job(job.clone()).is_err());
+        assert_eq!(queue.pop().unwrap().data, "a");
+        assert_eq!(queue.push_job(job).unwrap(), 1);
+        assert_eq!(queue.pop().unwrap().id, id);
+    }
+
+    #[tokio::test]
+    async fn persist_and_restore() {
+        let dir = std::env::temp_dir().join(format!("eh2telegraph-queue-{}", std::process::id()));
+        let store = JobStore::new(LocalStorage::new(&dir).unwrap());
+        let queue = JobQueue::new(4);
+        for (owner, priority, data) in [(1, false, "a"), (2, false, "b"), (3, true, "admin")] {
+            let (id, _) = queue.push(owner, priority, data.to_string()).unwrap();
+            let job = queue.pop().unwrap();
+            assert_eq!(job.id, id);
+            store.save(&job).await.unwrap();
+        }
+        store.remove(0).await.unwrap();
+
+        let restored = JobQueue::new(0);
+        for job in store.load::<String>().await.unwrap() {
+            restored.restore(job);
+        }
+        assert_eq!(restored.pop().unwrap().data, "admin");
+        assert_eq!(restored.pop().unwrap().data, "b");
+        // restored jobs are not limited, and new ids do not conflict
+        assert!(restored.push(4, true, "c".to_string()).unwrap().0 > 2);
+        let _ = std::fs::remove_file(dir);
+    }
+}
@@ -0,0 +1,383 @@
+use std::{
+    collections::HashMap,
+    net::IpAddr,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use once_cell::sync::OnceCell;
+use parking_lot::Mutex;
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+
+use crate::config;
+
+const CONFIG_KEY: &str = "http";
+// idle limits are dropped when there are more than this
+const SWEEP_LEN: usize = 64;
+// waits are capped so tiny or zero rates do not overflow
+const MAX_WAIT: Duration = Duration::from_secs(3600);
+// second-level labels under country code TLDs, like `co.jp`
+const SECOND_LEVEL_LABELS: &[&str] = &["co", "com", "net", "org", "ac", "edu", "gov"];
+
+/// Token bucket rate limiter.
+/// Tokens are refilled at `rate` per second, and at most `burst` tokens can be saved.
+#[derive(Debug)]
+pub struct TokenBucket {
+    rate: f64,
+    burst: f64,
+    state: Mutex<BucketState>,
+}
+
+#[derive(Debug)]
+struct BucketState {
+    tokens: f64,
+    last: Instant,
+}
+
+impl TokenBucket {
+    pub fn new(rate: f64, burst: usize) -> Self {
+        let burst = (burst as f64).max(1.0);
+        Self {
+            rate,
+            burst,
+            state: Mutex::new(BucketState {
+                tokens: burst,
+                last: Instant::now(),
+            }),
+        }
+    }
+
+    /// Take a token if there is one, returns the duration to wait(at most an hour) if not.
+    pub fn try_acquire(&self) -> Result<(), Duration> {
+        let mut state = self.state.lock();
+        let now = Instant::now();
+        let elapsed = now.duration_since(state.last).as_secs_f64();
+        state.tokens = (state.tokens + elapsed * self.rate).min(self.burst);
+        state.last = now;
+
+        if state.tokens >= 1.0 {
+            state.tokens -= 1.0;
+            return Ok(());
+        }
+        let wait = (1.0 - state.tokens) / self.rate;
+        if !wait.is_finite() || wait < 0.0 || wait >= MAX_WAIT.as_secs_f64() {
+            return Err(MAX_WAIT);
+        }
+        Err(Duration::from_secs_f64(wait))
+    }
+
+    /// Whether the bucket has refilled to burst, then it is the same as a new one.
+    pub fn is_full(&self) -> bool {
+        let state = self.state.lock();
+        let elapsed = state.last.elapsed().as_secs_f64();
+        state.tokens + elapsed * self.rate >= self.burst
+    }
+
+    /// Wait until a token is taken.
+    pub async fn acquire(&self) {
+        while let Err(wait) = self.try_acquire() {
+            tokio::time::sleep(wait).await;
+        }
+    }
+}
+
+/// Budget class of a request, pages and images are limited separately.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum RequestKind {
+    Page,
+    Image,
+}
+
+#[derive(serde::Deserialize, Clone, Debug, Default)]
+pub struct HostLimitConfig {
+    /// Requests per second, no limit if not set.
+    pub rate_limit: Option<f64>,
+    /// Max burst requests, default to 1 second of rate_limit.
+    pub burst: Option<usize>,
+    /// Max requests in flight, no limit if not set.
+    pub max_inflight: Option<usize>,
+}
+
+/// Config example:
+/// ```yaml
+/// http:
+///   limit:
+///     page: { rate_limit: 2, max_inflight: 4 }
+///     image: { rate_limit: 20, burst: 40, max_inflight: 16 }
+///     hosts:
+///       exhentai.org: { rate_limit: 1, max_inflight: 2 }
+///       hath.network: { rate_limit: 10, max_inflight: 8 }
+/// ```
+/// Limits of `page` and `image` apply to each site, subdomains of a registrable
+/// domain like `hath.network` share one budget, while ip hosts are limited each.
+/// A rule in `hosts` matches the domain and its subdomains, and all of them share
+/// one budget. The longest matching rule wins when rules overlap.
+#[derive(serde::Deserialize, Clone, Debug, Default)]
+pub struct HostLimiterConfig {
+    #[serde(default)]
+    pub page: HostLimitConfig,
+    #[serde(default)]
+    pub image: HostLimitConfig,
+    #[serde(default)]
+    pub hosts: HashMap<String, HostLimitConfig>,
+}
+
+#[derive(serde::Deserialize, Default)]
+struct HTTPConfig {
+    #[serde(default)]
+    limit: HostLimiterConfig,
+}
+
+#[derive(Debug)]
+struct HostLimit {
+    bucket: Option<TokenBucket>,
+    inflight: Option<Arc<Semaphore>>,
+    max_inflight: usize,
+}
+
+impl HostLimit {
+    fn new(config: &HostLimitConfig) -> Self {
+        Self {
+            bucket: config.rate_limit.map(|rate| {
+                TokenBucket::new(rate, config.burst.unwrap_or_else(|| rate.ceil() as usize))
+            }),
+            inflight: config
+                .max_inflight
+                .map(|n| Arc::new(Semaphore::new(n.max(1)))),
+            max_inflight: config.max_inflight.unwrap_or_default().max(1),
+        }
+    }
+
+    // An idle limit can be dropped, since a new one behaves the same.
+    fn is_idle(&self) -> bool {
+        self.inflight
+            .as_ref()
+            .is_none_or(|s| s.available_permits() == self.max_inflight)
+            && self.bucket.as_ref().is_none_or(TokenBucket::is_full)
+    }
+}
+
+/// Registrable domain of the host, approximated by the last two labels, or three
+/// under second-level labels like `co.jp`. Ip hosts are returned as is.
+fn site_of(host: &str) -> &str {
+    if host.starts_with('[') || host.parse::<IpAddr>().is_ok() {
+        return host;
+    }
+    let labels = host.rsplit('.').collect::<Vec<_>>();
+    let count = match labels.as_slice() {
+        [tld, second, _, ..] if tld.len() == 2 && SECOND_LEVEL_LABELS.contains(second) => 3,
+        _ => 2,
+    };
+    if labels.len() <= count {
+        return host;
+    }
+    let len = labels[..count].iter().map(|l| l.len()).sum::<usize>() + count - 1;
+    &host[host.len() - len..]
+}
+
+static GLOBAL_LIMITER: OnceCell<HostLimiter> = OnceCell::new();
+
+/// Rate limiter keyed by matched rule or site, shared by all http clients.
+#[derive(Debug)]
+pub struct HostLimiter {
+    config: HostLimiterConfig,
+    limits: Mutex<HashMap<(RequestKind, String), Arc<HostLimit>>>,
+}
+
+impl HostLimiter {
+    pub fn new(config: HostLimiterConfig) -> Self {
+        Self {
+            config,
+            limits: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Initialize the global limiter with `http.limit`.
+    /// Requests are not limited if it is not initialized.
+    pub fn init_from_config() -> anyhow::Result<()> {
+        let config: HTTPConfig = config::parse(CONFIG_KEY)?.unwrap_or_default();
+        let _ = GLOBAL_LIMITER.set(Self::new(config.limit));
+        Ok(())
+    }
+
+    pub fn global() -> Option<&'static Self> {
+        GLOBAL_LIMITER.get()
+    }
+
+    fn get(&self, kind: RequestKind, host: &str) -> Arc<HostLimit> {
+        let rule = self
+            .config
+            .hosts
+            .iter()
+            .filter(|(domain, _)| {
+                host == domain.as_str()
+                    || host
+                        .strip_suffix(domain.as_str())
+                        .map(|h| h.ends_with('.'))
+                        .unwrap_or_default()
+            })
+            .max_by_key(|(domain, _)| domain.len());
+        let (key, config) = match rule {
+            Some((domain, config)) => (domain.as_str(), config),
+            None => match kind {
+                RequestKind::Page => (site_of(host), &self.config.page),
+                RequestKind::Image => (site_of(host), &self.config.image),
+            },
+        };
+
+        let mut limits = self.limits.lock();
+        if let Some(limit) = limits.get(&(kind, key.to_string())) {
+            return limit.clone();
+        }
+        if limits.len() >= SWEEP_LEN {
+            limits.retain(|_, limit| Arc::strong_count(limit) > 1 || !limit.is_idle());
+        }
+        let limit = Arc::new(HostLimit::new(config));
+        limits.insert((kind, key.to_string()), limit.clone());
+        limit
+    }
+
+    /// Wait until the request to host is allowed.
+    /// The returned permit should be held until the response is consumed.
+    pub async fn acquire(&self, kind: RequestKind, host: &str) -> Option<OwnedSemaphorePermit> {
+        let limit = self.get(kind, host);
+        let permit = match &limit.inflight {
+            Some(s) => Some(
+                s.clone()
+                    .acquire_owned()
+                    .await
+                    .expect("host limiter semaphore closed"),
+            ),
+            None => None,
+        };
+        if let Some(bucket) = &limit.bucket {
+            bucket.acquire().await;
+        }
+        permit
+    }
+}
+
+/// Wait for the global limiter of the request target.
+/// Requests through the proxy worker are limited by their original host.
+pub async fn limit(
+    kind: RequestKind,
+    builder: reqwest::RequestBuilder,
+) -> reqwest::Result<(Option<OwnedSemaphorePermit>, reqwest::RequestBuilder)> {
+    let limiter = match HostLimiter::global() {
+        Some(l) => l,
+        None => return Ok((None, builder)),
+    };
+    let (client, request) = builder.build_split();
+    let request = request?;
+    let host = crate::http_proxy::target_url(&request)
+        .host_str()
+        .unwrap_or_default()
+        .to_string();
+    let permit = limiter.acquire(kind, &host).await;
+    Ok((permit, reqwest::RequestBuilder::from_parts(client, request)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        site_of, HostLimiter, HostLimiterConfig, RequestKind, TokenBucket, MAX_WAIT, SWEEP_LEN,
+    };
+
+    #[test]
+    fn token_bucket() {
+        let bucket = TokenBucket::new(1.0, 2);
+        assert!(bucket.try_acquire().is_ok());
+        assert!(bucket.try_acquire().is_ok());
+        let wait = bucket.try_acquire().unwrap_err();
+        assert!(wait.as_secs_f64() > 0.5 && wait.as_secs_f64() <= 1.0);
+        assert!(!bucket.is_full());
+        assert!(TokenBucket::new(1.0, 2).is_full());
+
+        // waits are capped
+        for rate in [0.0, 1e-300] {
+            let bucket = TokenBucket::new(rate, 1);
+            assert!(bucket.try_acquire().is_ok());
+            assert_eq!(bucket.try_acquire().unwrap_err(), MAX_WAIT);
+        }
+    }
+
+    #[test]
+    fn site() {
+        assert_eq!(site_of("e-hentai.org"), "e-hentai.org");
+        assert_eq!(site_of("abc.def.hath.network"), "hath.network");
+        assert_eq!(site_of("a.example.co.jp"), "example.co.jp");
+        assert_eq!(site_of("127.0.0.1"), "127.0.0.1");
+        assert_eq!(site_of("[::1]"), "[::1]");
+        assert_eq!(site_of("localhost"), "localhost");
+    }
+
+    #[tokio::test]
+    async fn host_limiter() {
+        let config: HostLimiterConfig = serde_yaml::from_str(
+            "page: { max_inflight: 1 }\nhosts:\n  hath.network: { max_inflight: 2 }",
+        )
+        .unwrap();
+        let limiter = HostLimiter::new(config);
+
+        // pages of each host have its own budget
+        let p1 = limiter.acquire(RequestKind::Page, "e-hentai.org").await;
+        assert!(p1.is_some());
+        let p2 = limiter.acquire(RequestKind::Page, "exhentai.org").await;
+        assert!(p2.is_some());
+        let limit = limiter.get(RequestKind::Page, "e-hentai.org");
+        assert_eq!(limit.inflight.as_ref().unwrap().available_permits(), 0);
+
+        // images are not limited
+        assert!(limiter
+            .acquire(RequestKind::Image, "e-hentai.org")
+            .await
+            .is_none());
+
+        // subdomains share the budget of the rule
+        let _a = limiter.acquire(RequestKind::Image, "a.hath.network").await;
+        let _b = limiter.acquire(RequestKind::Image, "b.hath.network").await;
+        let limit = limiter.get(RequestKind::Image, "hath.network");
+        assert_eq!(limit.inflight.as_ref().unwrap().available_permits(), 0);
+        let limit = limiter.get(RequestKind::Image, "nothath.network");
+        assert!(limit.inflight.is_none());
+    }
+
+    #[tokio::test]
+    async fn longest_rule() {
+        let config: HostLimiterConfig = serde_yaml::from_str(
+            "hosts:\n  hath.network: { max_inflight: 2 }\n  a.hath.network: { max_inflight: 1 }",
+        )
+        .unwrap();
+        let limiter = HostLimiter::new(config);
+        for _ in 0..16 {
+            let limit = limiter.get(RequestKind::Image, "b.a.hath.network");
+            assert_eq!(limit.max_inflight, 1);
+            let limit = limiter.get(RequestKind::Image, "b.hath.network");
+            assert_eq!(limit.max_inflight, 2);
+        }
+    }
+
+    #[tokio::test]
+    async fn evict_idle() {
+        let config: HostLimiterConfig = serde_yaml::from_str("image: { max_inflight: 1 }").unwrap();
+        let limiter = HostLimiter::new(config);
+
+        // subdomains of a site share the budget
+        let busy = limiter.acquire(RequestKind::Image, "a.example.com").await;
+        let limit = limiter.get(RequestKind::Image, "b.example.com");
+        assert_eq!(limit.inflight.as_ref().unwrap().available_permits(), 0);
+        drop(limit);
+
+        for i in 0..SWEEP_LEN * 2 {
+            let _ = limiter
+                .acquire(RequestKind::Image, &format!("10.0.0.{i}"))
+                .await;
+        }
+        let limits = limiter.limits.lock();
+        assert!(limits.len() <= SWEEP_LEN);
+        // the busy one is kept
+        assert!(limits.contains_key(&(RequestKind::Image, "example.com".to_string())));
+        drop(limits);
+        drop(busy);
+    }
+}
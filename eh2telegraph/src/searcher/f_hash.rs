@@ -3,18 +3,41 @@ use regex::Regex;
 
 use crate::{
     collector::exhentai::EXCollector,
-    http_client::{GhostClient, GhostClientBuilder},
+    html::{Css, Document},
+    http_client::GhostClientBuilder,
+    outbound::{OutboundClient, OutboundPolicy},
     util::{get_string, match_first_group},
 };
 
+const PAGE_NAME: &str = "e-hentai search page";
+
 lazy_static::lazy_static! {
-    static ref EHENTAI_URL_RE: Regex = Regex::new(r#"<a href="(https://e(-|x)hentai\.org/g/\w+/[\w-]+)/">"#).unwrap();
+    static ref RESULTS: Css = Css::new(".itg");
+    static ref LINKS: Css = Css::new(".itg a[href]");
+    static ref EHENTAI_URL_RE: Regex = Regex::new(r#"^(https://e(-|x)hentai\.org/g/\w+/[\w-]+)/?$"#).unwrap();
+}
+
+/// Find the first gallery url in the search result page.
+fn first_gallery(content: &str) -> anyhow::Result<Option<String>> {
+    let doc = Document::parse(PAGE_NAME, content);
+    if !doc.exists(&RESULTS) {
+        if content.contains("No hits found") {
+            return Ok(None);
+        }
+        return Err(doc.layout_changed(&RESULTS).into());
+    }
+    let url = doc
+        .select(&LINKS)
+        .filter_map(|n| n.attr("href"))
+        .find_map(|href| match_first_group(&EHENTAI_URL_RE, href));
+    Ok(url.map(ToString::to_string))
 }
+
 /// FHashConverter can convert f-hash(usually comes from a search result) to the first gallery url.
 /// Works for both e-hentai and ex-hentai.
 pub struct FHashConvertor {
-    client: GhostClient,
-    raw_client: reqwest::Client,
+    client: OutboundClient,
+    raw_client: OutboundClient,
 }
 
 impl FHashConvertor {
@@ -22,7 +45,8 @@ impl FHashConvertor {
         Self {
             client: GhostClientBuilder::default()
                 .with_cf_resolve(&["e-hentai.org"])
-                .build(prefix),
+                .build(prefix)
+                .into(),
             raw_client: EXCollector::new_from_config()
                 .expect("unable to build ex-client")
                 .get_client(),
@@ -30,10 +54,12 @@ impl FHashConvertor {
     }
 
     pub fn new_from_config() -> Self {
+        // f-hash is searched on e-hentai, so we follow its policy.
+        let policy = OutboundPolicy::from_config("e-hentai", OutboundPolicy::Ghost)
+            .expect("unable to parse outbound config for e-hentai");
         Self {
-            client: GhostClientBuilder::default()
-                .with_cf_resolve(&["e-hentai.org"])
-                .build_from_config()
+            client: policy
+                .build(GhostClientBuilder::default().with_cf_resolve(&["e-hentai.org"]))
                 .expect("unable to build client for f-hash convertor"),
             raw_client: EXCollector::new_from_config()
                 .expect("unable to build ex-client")
@@ -41,6 +67,15 @@ impl FHashConvertor {
         }
     }
 
+    #[cfg(test)]
+    pub(crate) fn from_fixture(fixture: &crate::http_replay::Fixture) -> Self {
+        let live = || FHashConvertor::new_from_config();
+        Self {
+            client: fixture.client(|| live().client),
+            raw_client: fixture.client(|| live().raw_client),
+        }
+    }
+
     // TODO: impl a trait?
     pub async fn convert_to_gallery(&self, f_hash: &str) -> anyhow::Result<String> {
         tracing::info!("[f-hash] converting hash {f_hash}");
@@ -48,21 +83,54 @@ impl FHashConvertor {
         let url = format!("https://e-hentai.org/?f_shash={f_hash}&f_sh=on&f_sname=on&f_stags=on&f_sh=on&f_spf=&f_spt=&f_sfl=on&f_sfu=on&f_sft=on");
         let text = get_string(&self.client, &url).await?;
 
-        if let Some(url) = match_first_group(&EHENTAI_URL_RE, &text) {
+        if let Some(url) = first_gallery(&text)? {
             tracing::info!("[f-hash] hash {f_hash} -> {url}");
-            return Ok(url.to_string());
+            return Ok(url);
         }
 
         // find in exhentai
         let url = format!("https://exhentai.org/?f_shash={f_hash}&f_sh=on&f_sname=on&f_stags=on&f_sh=on&f_spf=&f_spt=&f_sfl=on&f_sfu=on&f_sft=on");
         let text = get_string(&self.raw_client, &url).await?;
 
-        if let Some(url) = match_first_group(&EHENTAI_URL_RE, &text) {
+        if let Some(url) = first_gallery(&text)? {
             tracing::info!("[f-hash] hash {f_hash} -> {url}");
-            return Ok(url.to_string());
+            return Ok(url);
         }
 
         tracing::info!("[f-hash] hash {f_hash} not found");
         Err(anyhow::anyhow!("not found in e-hentai or exhentai"))
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::FHashConvertor;
+    use crate::http_replay::Fixture;
+
+    #[tokio::test]
+    async fn replay_convert() {
+        let fixture = Fixture::open("f_hash");
+        let convertor = FHashConvertor::from_fixture(&fixture);
+        // not found in e-hentai, and found in exhentai
+        let url = convertor
+            .convert_to_gallery("0123456789abcdef0123456789abcdef01234567")
+            .await
+            .unwrap();
+        assert_eq!(url, "https://exhentai.org/g/2129939/01a6e086b9");
+        assert_eq!(fixture.missed(), 0);
+    }
+
+    #[test]
+    fn first_gallery() {
+        let html = r#"<a href="https://e-hentai.org/g/1/a/">header</a><table class="itg gltc"><tr><td><a href="https://e-hentai.org/tag/x">x</a></td><td class="gl3c glname"><a href="https://e-hentai.org/g/2122174/fd2525031e/"><div class="glink">A</div></a></td></tr></table>"#;
+        assert_eq!(
+            super::first_gallery(html).unwrap().unwrap(),
+            "https://e-hentai.org/g/2122174/fd2525031e"
+        );
+        assert!(super::first_gallery("<p>No hits found</p>")
+            .unwrap()
+            .is_none());
+        let err = super::first_gallery("<html></html>").unwrap_err();
+        assert!(err.is::<crate::html::LayoutChanged>());
+    }
+}
@@ -8,17 +8,3 @@ pub trait ImageSearcher<T> {
 
     fn search(&self, data: T) -> Self::FetchFuture;
 }
-
-#[cfg(test)]
-mod tests {
-    use super::*;
-
-    #[ignore]
-    #[tokio::test]
-    async fn demo() {
-        let data = std::fs::read("./image.png").unwrap();
-        let searcher = saucenao::SaucenaoSearcher::new(None);
-        let r = searcher.search(data).await;
-        println!("result: {r:?}");
-    }
-}
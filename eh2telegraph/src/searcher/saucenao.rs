@@ -8,45 +8,31 @@ use reqwest::{
     Response,
 };
 
-use crate::http_client::{GhostClient, HttpRequestBuilder};
+use crate::{
+    html::{Css, Document, Node},
+    http_client::{GhostClient, HttpRequestBuilder},
+    outbound::{OutboundClient, OutboundPolicy},
+};
 
 use super::ImageSearcher;
 
-lazy_static::lazy_static! {
-    static ref SEARCH_ELEMENT_RE: Regex = Regex::new(r#"<tr><td class="resulttableimage">(.*?)</tr>"#).unwrap();
-    static ref S_URL_RE: Regex = Regex::new(r#"src="(https://.*?)""#).unwrap();
-    static ref TITLE_RE: Regex = Regex::new(r#"<div class="resulttitle"><strong>(.*?)</strong>"#).unwrap();
-    static ref SIM_RE: Regex = Regex::new(r#"<div class="resultsimilarityinfo">(\d+)\.?\d*%</div>"#).unwrap();
-    static ref SITE_PARSE_RE: Regex = Regex::new(r#"saucenao\.com/(res/pixiv(_historical)?/\d+/manga/(?P<pixiv_id>\d+)_)|(ehentai/\w+/\w+/(?P<ehentai_fhash>\w+))|(res/nhentai/(?P<nhentai_id>\d+))"#).unwrap();
-}
+const SITE: &str = "saucenao";
 
-macro_rules! extract_first {
-    ($re: expr, $input: expr, $err_msg: expr) => {
-        $re.captures($input)
-            .ok_or_else(|| anyhow::anyhow!($err_msg))?
-            .get(1)
-            .expect("regexp is matched but no group 1 found")
-            .as_str()
-    };
-}
+const PAGE_NAME: &str = "saucenao result page";
 
-macro_rules! extract_first_opt {
-    ($re: expr, $input: expr, $default: expr) => {
-        match $re.captures($input) {
-            Some(t) => t
-                .get(1)
-                .expect("regexp is matched but no group 1 found")
-                .as_str(),
-            None => $default,
-        }
-    };
+lazy_static::lazy_static! {
+    static ref RESULT: Css = Css::new("table.resulttable");
+    static ref RESULT_IMAGE: Css = Css::new("td.resulttableimage img");
+    static ref TITLE: Css = Css::new("div.resulttitle strong");
+    static ref SIMILARITY: Css = Css::new("div.resultsimilarityinfo");
+    static ref SITE_PARSE_RE: Regex = Regex::new(r#"saucenao\.com/(res/pixiv(_historical)?/\d+/manga/(?P<pixiv_id>\d+)_)|(ehentai/\w+/\w+/(?P<ehentai_fhash>\w+))|(res/nhentai/(?P<nhentai_id>\d+))"#).unwrap();
 }
 
 /// Saucenao searcher.
 /// Note: even saucenao resolves to an ipv6 address, we still use force resolving.
 #[derive(Debug, Clone)]
 pub struct SaucenaoSearcher {
-    client: GhostClient,
+    client: OutboundClient,
 }
 
 impl SaucenaoSearcher {
@@ -54,27 +40,38 @@ impl SaucenaoSearcher {
         Self {
             client: GhostClient::builder()
                 .with_cf_resolve(&["saucenao.com", "e-hentai.org"])
-                .build(prefix),
+                .build(prefix)
+                .into(),
         }
     }
 
     pub fn new_from_config() -> Self {
+        let policy = OutboundPolicy::from_config(SITE, OutboundPolicy::Ghost)
+            .expect("unable to parse outbound config for saucenao");
         Self {
-            client: GhostClient::builder()
-                .with_cf_resolve(&["saucenao.com", "e-hentai.org"])
-                .build_from_config()
+            client: policy
+                .build(GhostClient::builder().with_cf_resolve(&["saucenao.com", "e-hentai.org"]))
                 .expect("unable to build client for saucenao"),
         }
     }
 
+    #[cfg(test)]
+    pub(crate) fn from_fixture(fixture: &crate::http_replay::Fixture) -> Self {
+        Self {
+            client: fixture.client(|| SaucenaoSearcher::new(None).client),
+        }
+    }
+
     async fn do_search<C: HttpRequestBuilder>(
         client: &C,
         file: Part,
     ) -> anyhow::Result<SaucenaoOutput> {
         let response = client
-            .post_builder("https://saucenao.com/search.php")
-            .multipart(multipart::Form::new().part("file", file))
-            .send()
+            .execute(
+                client
+                    .post_builder("https://saucenao.com/search.php")
+                    .multipart(multipart::Form::new().part("file", file)),
+            )
             .await
             .and_then(Response::error_for_status)?
             .text()
@@ -137,37 +134,46 @@ impl FromStr for SaucenaoOutput {
     type Err = anyhow::Error;
 
     fn from_str(s: &str) -> Result<Self, Self::Err> {
-        let mut data = Vec::new();
-
-        // match all
-        for cap in SEARCH_ELEMENT_RE.captures_iter(s) {
-            let s = cap
-                .get(1)
-                .expect("regexp is matched but no group 1 found")
-                .as_str();
-            let element = SaucenaoOuputElement::from_str(s)?;
-            data.push(element);
-        }
+        let doc = Document::parse(PAGE_NAME, s);
+        let mut data = doc
+            .select(&RESULT)
+            .map(|node| SaucenaoOuputElement::from_node(&doc, node))
+            .collect::<Result<Vec<_>, _>>()?;
         // sort
-        data.sort_unstable_by(|a, b| b.similarity.cmp(&a.similarity));
+        data.sort_unstable_by_key(|b| std::cmp::Reverse(b.similarity));
 
         Ok(Self { data })
     }
 }
 
-impl FromStr for SaucenaoOuputElement {
-    type Err = anyhow::Error;
-
-    fn from_str(s: &str) -> Result<Self, Self::Err> {
+impl SaucenaoOuputElement {
+    fn from_node(doc: &Document, node: Node<'_>) -> anyhow::Result<Self> {
         // raw_url examples:
         // https://img1.saucenao.com/res/pixiv/7594/manga/75943246_p1.jpg?auth=dKnHvUUPQ0wi8G6yv-HWZQ&exp=1645560000
         // https://img1.saucenao.com/res/seiga_illust/157/1574075.jpg?auth=KKGjLqCUyouLUKieJ5g4Rw&exp=1645560000
         // https://img3.saucenao.com/ehentai/c5/17/c517710f0654ea883df1e0fea7117c671fb03bc1.jpg?auth=Hu-H_4c3lTKdh_rtZJv50w&exp=1645560000
-        let raw_url =
-            extract_first!(S_URL_RE, s, "unable to parse saucenao result url").to_string();
-        let name = extract_first_opt!(TITLE_RE, s, "NO TITLE").to_string();
-        let similarity =
-            extract_first!(SIM_RE, s, "unable to parse saucenao result similarity").parse()?;
+        // hidden results are lazy loaded with data-src
+        let raw_url = node
+            .select(&RESULT_IMAGE)
+            .find_map(|img| {
+                [img.attr("src"), img.attr("data-src")]
+                    .into_iter()
+                    .flatten()
+                    .find(|src| src.starts_with("https://"))
+            })
+            .ok_or_else(|| doc.layout_changed(&RESULT_IMAGE))?
+            .to_string();
+        let name = node
+            .text_of(&TITLE)
+            .unwrap_or_else(|| "NO TITLE".to_string());
+        let similarity = node
+            .text_of(&SIMILARITY)
+            .and_then(|s| {
+                // like 93.52%
+                let integer = s.trim_end_matches('%').split('.').next()?;
+                integer.parse().ok()
+            })
+            .ok_or_else(|| doc.layout_changed(&SIMILARITY))?;
 
         let parsed = SITE_PARSE_RE
             .captures(&raw_url)
@@ -193,3 +199,48 @@ impl FromStr for SaucenaoOuputElement {
         })
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+
+    use super::{SaucenaoOutput, SaucenaoParsed, SaucenaoSearcher};
+    use crate::{http_replay::Fixture, searcher::ImageSearcher};
+
+    #[tokio::test]
+    async fn replay_search() {
+        let fixture = Fixture::open("saucenao");
+        let searcher = SaucenaoSearcher::from_fixture(&fixture);
+        // request bodies are not matched when replaying
+        let output = searcher.search(&[0xff, 0xd8, 0xff][..]).await.unwrap();
+        assert_eq!(output.data.len(), 1);
+        assert_eq!(output.data[0].similarity, 93);
+        assert_eq!(output.data[0].name, "EX Fixture Album");
+        assert!(matches!(
+            &output.data[0].parsed,
+            SaucenaoParsed::EHentai(h) if h == "c517710f0654ea883df1e0fea7117c671fb03bc1"
+        ));
+        assert_eq!(fixture.missed(), 0);
+    }
+
+    #[test]
+    fn parse_output() {
+        let html = r#"<div class="result"><table class="resulttable"><tr><td class="resulttableimage"><img src="https://img1.saucenao.com/res/nhentai/123/1.jpg?auth=a&amp;exp=1"></td><td class="resulttablecontent"><div class="resultmatchinfo"><div class="resultsimilarityinfo">61.2%</div></div><div class="resulttitle"><strong>Tom &amp; Jerry</strong></div></td></tr></table></div>
+        <div class="result hidden"><table class="resulttable"><tr><td class="resulttableimage"><img data-src="https://img3.saucenao.com/ehentai/c5/17/c517710f.jpg"></td><td class="resulttablecontent"><div class="resultsimilarityinfo">93.52%</div></td></tr></table></div>"#;
+        let output = SaucenaoOutput::from_str(html).unwrap();
+        assert_eq!(output.data.len(), 2);
+        assert_eq!(output.data[0].similarity, 93);
+        assert_eq!(output.data[0].name, "NO TITLE");
+        assert!(matches!(&output.data[0].parsed, SaucenaoParsed::EHentai(h) if h == "c517710f"));
+        assert_eq!(output.data[1].name, "Tom & Jerry");
+        assert_eq!(
+            output.data[1].raw_url,
+            "https://img1.saucenao.com/res/nhentai/123/1.jpg?auth=a&exp=1"
+        );
+
+        let broken =
+            r#"<table class="resulttable"><tr><td class="resulttableimage"></td></tr></table>"#;
+        let err = SaucenaoOutput::from_str(broken).unwrap_err();
+        assert!(err.is::<crate::html::LayoutChanged>());
+    }
+}
@@ -0,0 +1,184 @@
+use std::{
+    path::Path,
+    sync::Arc,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use redb::{Database, TableDefinition};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+
+use crate::config;
+
+use super::KVStorage;
+
+const CONFIG_KEY: &str = "local_kv";
+const TABLE: TableDefinition<&str, &[u8]> = TableDefinition::new("kv");
+// Value layout: 8 bytes big endian expire timestamp(0 means never) + payload.
+const EXPIRE_LEN: usize = 8;
+
+#[derive(Debug, Deserialize)]
+pub struct LocalConfig {
+    pub path: String,
+}
+
+/// Local persistent storage backed by redb.
+/// Values are encoded as json when used as KVStorage.
+#[derive(Clone, Debug)]
+pub struct LocalStorage(Arc<Database>);
+
+impl LocalStorage {
+    pub fn new<P: AsRef<Path>>(path: P) -> anyhow::Result<Self> {
+        let db = Database::create(path)?;
+        // make sure the table exists so read transactions will not fail.
+        let txn = db.begin_write()?;
+        txn.open_table(TABLE)?;
+        txn.commit()?;
+        Ok(Self(Arc::new(db)))
+    }
+
+    pub fn new_from_config() -> anyhow::Result<Self> {
+        let config: LocalConfig = config::parse(CONFIG_KEY)?
+            .ok_or_else(|| anyhow::anyhow!("local kv config(key: local_kv) not found"))?;
+        Self::new(config.path)
+    }
+
+    /// Get raw value. Expired value will be treated as not found.
+    pub async fn get_raw(&self, key: &str) -> anyhow::Result<Option<Vec<u8>>> {
+        let db = self.0.clone();
+        let key = key.to_string();
+        tokio::task::spawn_blocking(move || {
+            let txn = db.begin_read()?;
+            let table = txn.open_table(TABLE)?;
+            let value = match table.get(key.as_str())? {
+                Some(v) => v,
+                None => return Ok(None),
+            };
+            let value = value.value();
+            if is_expired(value, now()) {
+                return Ok(None);
+            }
+            Ok(Some(value[EXPIRE_LEN..].to_vec()))
+        })
+        .await?
+    }
+
+    /// Set raw value with optional ttl in seconds.
+    pub async fn set_raw(
+        &self,
+        key: String,
+        value: Vec<u8>,
+        expire_ttl: Option<usize>,
+    ) -> anyhow::Result<()> {
+        let db = self.0.clone();
+        let expire = expire_ttl.map(|ttl| now() + ttl as u64).unwrap_or(0);
+        tokio::task::spawn_blocking(move || {
+            let mut data = Vec::with_capacity(EXPIRE_LEN + value.len());
+            data.extend_from_slice(&expire.to_be_bytes());
+            data.extend_from_slice(&value);
+
+            let txn = db.begin_write()?;
+            txn.open_table(TABLE)?
+                .insert(key.as_str(), data.as_slice())?;
+            txn.commit()?;
+            Ok(())
+        })
+        .await?
+    }
+
+    pub async fn delete_raw(&self, key: &str) -> anyhow::Result<()> {
+        let db = self.0.clone();
+        let key = key.to_string();
+        tokio::task::spawn_blocking(move || {
+            let txn = db.begin_write()?;
+            txn.open_table(TABLE)?.remove(key.as_str())?;
+            txn.commit()?;
+            Ok(())
+        })
+        .await?
+    }
+
+    /// List keys with given prefix, expired ones are skipped.
+    pub async fn keys(&self, prefix: &str) -> anyhow::Result<Vec<String>> {
+        let db = self.0.clone();
+        let prefix = prefix.to_string();
+        tokio::task::spawn_blocking(move || {
+            let txn = db.begin_read()?;
+            let table = txn.open_table(TABLE)?;
+            let now = now();
+            let mut keys = Vec::new();
+            for item in table.range(prefix.as_str()..)? {
+                let (k, v) = item?;
+                if !k.value().starts_with(prefix.as_str()) {
+                    break;
+                }
+                if !is_expired(v.value(), now) {
+                    keys.push(k.value().to_string());
+                }
+            }
+            Ok(keys)
+        })
+        .await?
+    }
+
+    /// Remove all expired values, returns removed count.
+    pub async fn purge_expired(&self) -> anyhow::Result<usize> {
+        let db = self.0.clone();
+        tokio::task::spawn_blocking(move || {
+            let txn = db.begin_write()?;
+            let removed = {
+                let mut table = txn.open_table(TABLE)?;
+                let now = now();
+                let mut removed = 0;
+                for item in table.extract_if(|_, v| is_expired(v, now))? {
+                    item?;
+                    removed += 1;
+                }
+                removed
+            };
+            txn.commit()?;
+            Ok(removed)
+        })
+        .await?
+    }
+}
+
+#[inline]
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default()
+}
+
+#[inline]
+fn is_expired(value: &[u8], now: u64) -> bool {
+    match value.get(..EXPIRE_LEN) {
+        Some(expire) => {
+            let expire = u64::from_be_bytes(expire.try_into().expect("slice length checked"));
+            expire != 0 && expire <= now
+        }
+        None => true,
+    }
+}
+
+impl<T> KVStorage<T> for LocalStorage
+where
+    T: DeserializeOwned + Serialize + Send + Sync,
+{
+    async fn get(&self, key: &str) -> anyhow::Result<Option<T>> {
+        self.get_raw(key)
+            .await?
+            .map(|v| serde_json::from_slice(&v))
+            .transpose()
+            .map_err(Into::into)
+    }
+
+    async fn set(&self, key: String, value: T, expire_ttl: Option<usize>) -> anyhow::Result<()> {
+        let data = serde_json::to_vec(&value)?;
+        self.set_raw(key, data, expire_ttl).await
+    }
+
+    async fn delete(&self, key: &str) -> anyhow::Result<()> {
+        self.delete_raw(key).await
+    }
+}
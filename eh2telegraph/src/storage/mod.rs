@@ -3,6 +3,7 @@ use parking_lot::RwLock;
 use std::{collections::HashMap, sync::Arc};
 
 pub mod cloudflare_kv;
+pub mod local;
 pub mod lru;
 
 pub trait KVStorage<V> {
@@ -1,9 +1,15 @@
 use std::collections::VecDeque;
 use std::fmt;
 use std::future::Future;
+use std::pin::Pin;
+use std::sync::{
+    atomic::{AtomicBool, Ordering},
+    Arc,
+};
+use std::task::{Context, Poll, Waker};
 
-use futures::FutureExt;
-use tokio::sync::oneshot;
+use futures::{future::Either, stream::FuturesOrdered, FutureExt, Stream, StreamExt};
+use parking_lot::Mutex;
 
 /// We define a AsyncStream to replace futures::Stream since we don't want to implement
 /// poll_next nor using async_stream.
@@ -20,25 +26,394 @@ pub trait AsyncStream {
     fn size_hint(&self) -> (usize, Option<usize>) {
         (0, None)
     }
+
+    fn map<F, U>(self, f: F) -> Map<Self, F>
+    where
+        Self: Sized,
+        F: FnMut(Self::Item) -> U + Clone,
+    {
+        Map { stream: self, f }
+    }
+
+    /// Since the item count is decided before the futures are resolved,
+    /// filtered out items are yielded as None.
+    fn filter_map<F, U>(self, f: F) -> Map<Self, F>
+    where
+        Self: Sized,
+        F: FnMut(Self::Item) -> Option<U> + Clone,
+    {
+        Map { stream: self, f }
+    }
+
+    fn inspect<F>(self, f: F) -> Inspect<Self, F>
+    where
+        Self: Sized,
+        F: FnMut(&Self::Item) + Clone,
+    {
+        Inspect { stream: self, f }
+    }
+
+    /// Skip n items, their futures are dropped without polling.
+    fn skip(self, n: usize) -> Skip<Self>
+    where
+        Self: Sized,
+    {
+        Skip { stream: self, n }
+    }
+
+    fn take(self, n: usize) -> Take<Self>
+    where
+        Self: Sized,
+    {
+        Take { stream: self, n }
+    }
+
+    fn enumerate(self) -> Enumerate<Self>
+    where
+        Self: Sized,
+    {
+        Enumerate {
+            stream: self,
+            count: 0,
+        }
+    }
+
+    fn chain<St>(self, other: St) -> Chain<Self, St>
+    where
+        Self: Sized,
+        St: AsyncStream<Item = Self::Item>,
+    {
+        Chain {
+            first: Some(self),
+            second: other,
+        }
+    }
+
+    fn buffered(self, buffer_size: usize) -> Buffered<Self>
+    where
+        Self: Sized,
+    {
+        Buffered::new(self, buffer_size)
+    }
+
+    /// Like buffered, but stop loading ahead when the resolved items which are
+    /// not consumed take budget bytes. The size of item is measured by `size`.
+    fn buffered_bytes<F>(self, buffer_size: usize, budget: usize, size: F) -> Buffered<Self>
+    where
+        Self: Sized,
+        F: Fn(&Self::Item) -> usize + Send + Sync + 'static,
+    {
+        Buffered::with_budget(self, buffer_size, budget, size)
+    }
+
+    /// Like buffered, but only start a future when `gate` returns true. When
+    /// nothing is loading, one future is started anyway to make progress.
+    fn buffered_gated<G>(self, buffer_size: usize, gate: G) -> Buffered<Self>
+    where
+        Self: Sized,
+        G: FnMut() -> bool + Send + 'static,
+    {
+        Buffered::with_gate(self, buffer_size, gate)
+    }
+
+    /// Convert to futures::Stream, the futures are awaited one by one.
+    fn into_stream(self) -> impl Stream<Item = Self::Item>
+    where
+        Self: Sized,
+    {
+        futures::stream::unfold(self, |mut st| async move {
+            let fut = st.next()?;
+            Some((fut.await, st))
+        })
+    }
+}
+
+/// Create AsyncStream from futures.
+pub fn iter<I>(futures: I) -> Iter<I::IntoIter>
+where
+    I: IntoIterator,
+    I::Item: Future,
+{
+    Iter {
+        iter: futures.into_iter(),
+    }
+}
+
+/// Create AsyncStream from futures::Stream.
+/// The stream is polled when the futures are polled, so the futures should
+/// be awaited in order. The item count is unknown before the stream ends, so
+/// the end is yielded as None, and no more futures are returned after that.
+pub fn from_stream<S>(stream: S) -> FromStream<S>
+where
+    S: Stream + Unpin,
+{
+    FromStream {
+        stream: Arc::new(tokio::sync::Mutex::new(stream)),
+        ended: Arc::new(AtomicBool::new(false)),
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Iter<I> {
+    iter: I,
+}
+
+impl<I> AsyncStream for Iter<I>
+where
+    I: Iterator,
+    I::Item: Future,
+{
+    type Item = <I::Item as Future>::Output;
+    type Future = I::Item;
+
+    #[inline]
+    fn next(&mut self) -> Option<Self::Future> {
+        self.iter.next()
+    }
+
+    #[inline]
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.iter.size_hint()
+    }
+}
+
+#[derive(Debug)]
+pub struct FromStream<S> {
+    stream: Arc<tokio::sync::Mutex<S>>,
+    ended: Arc<AtomicBool>,
+}
+
+impl<S> AsyncStream for FromStream<S>
+where
+    S: Stream + Unpin,
+{
+    type Item = Option<S::Item>;
+    type Future = impl Future<Output = Self::Item>;
+
+    fn next(&mut self) -> Option<Self::Future> {
+        if self.ended.load(Ordering::Acquire) {
+            return None;
+        }
+        let stream = self.stream.clone();
+        let ended = self.ended.clone();
+        Some(async move {
+            let item = stream.lock().await.next().await;
+            if item.is_none() {
+                ended.store(true, Ordering::Release);
+            }
+            item
+        })
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Map<St, F> {
+    stream: St,
+    f: F,
+}
+
+impl<St, F, U> AsyncStream for Map<St, F>
+where
+    St: AsyncStream,
+    F: FnMut(St::Item) -> U + Clone,
+{
+    type Item = U;
+    type Future = futures::future::Map<St::Future, F>;
+
+    #[inline]
+    fn next(&mut self) -> Option<Self::Future> {
+        Some(self.stream.next()?.map(self.f.clone()))
+    }
+
+    #[inline]
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.stream.size_hint()
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Inspect<St, F> {
+    stream: St,
+    f: F,
+}
+
+impl<St, F> AsyncStream for Inspect<St, F>
+where
+    St: AsyncStream,
+    F: FnMut(&St::Item) + Clone,
+{
+    type Item = St::Item;
+    type Future = futures::future::Inspect<St::Future, F>;
+
+    #[inline]
+    fn next(&mut self) -> Option<Self::Future> {
+        Some(self.stream.next()?.inspect(self.f.clone()))
+    }
+
+    #[inline]
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.stream.size_hint()
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Skip<St> {
+    stream: St,
+    n: usize,
+}
+
+impl<St: AsyncStream> AsyncStream for Skip<St> {
+    type Item = St::Item;
+    type Future = St::Future;
+
+    fn next(&mut self) -> Option<Self::Future> {
+        while self.n > 0 {
+            self.n -= 1;
+            drop(self.stream.next()?);
+        }
+        self.stream.next()
+    }
+
+    #[inline]
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        let (lower, upper) = self.stream.size_hint();
+        (
+            lower.saturating_sub(self.n),
+            upper.map(|x| x.saturating_sub(self.n)),
+        )
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Take<St> {
+    stream: St,
+    n: usize,
+}
+
+impl<St: AsyncStream> AsyncStream for Take<St> {
+    type Item = St::Item;
+    type Future = St::Future;
+
+    fn next(&mut self) -> Option<Self::Future> {
+        if self.n == 0 {
+            return None;
+        }
+        self.n -= 1;
+        self.stream.next()
+    }
+
+    #[inline]
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        let (lower, upper) = self.stream.size_hint();
+        (
+            lower.min(self.n),
+            Some(upper.map_or(self.n, |x| x.min(self.n))),
+        )
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Enumerate<St> {
+    stream: St,
+    count: usize,
+}
+
+impl<St: AsyncStream> AsyncStream for Enumerate<St> {
+    type Item = (usize, St::Item);
+    type Future = impl Future<Output = Self::Item>;
+
+    fn next(&mut self) -> Option<Self::Future> {
+        let fut = self.stream.next()?;
+        let idx = self.count;
+        self.count += 1;
+        Some(fut.map(move |item| (idx, item)))
+    }
+
+    #[inline]
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.stream.size_hint()
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Chain<St1, St2> {
+    first: Option<St1>,
+    second: St2,
+}
+
+impl<St1, St2> AsyncStream for Chain<St1, St2>
+where
+    St1: AsyncStream,
+    St2: AsyncStream<Item = St1::Item>,
+{
+    type Item = St1::Item;
+    type Future = Either<St1::Future, St2::Future>;
+
+    fn next(&mut self) -> Option<Self::Future> {
+        if let Some(first) = self.first.as_mut() {
+            match first.next() {
+                Some(fut) => return Some(Either::Left(fut)),
+                None => self.first = None,
+            }
+        }
+        self.second.next().map(Either::Right)
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        let (lower, upper) = self.second.size_hint();
+        match self.first.as_ref().map(|st| st.size_hint()) {
+            Some((l, u)) => (
+                lower.saturating_add(l),
+                upper.zip(u).and_then(|(a, b)| a.checked_add(b)),
+            ),
+            None => (lower, upper),
+        }
+    }
 }
 
 /// Buffered Stream.
 /// By decorating Buffered, the output future of stream will be polled
 /// concurrently.
-/// Here I implement it by spawning tasks. It is indeed not efficient as
-/// `FuturesOrdered` which is used by `futures-util::stream::Buffered`.
-/// As a decorator of an async trait, it is hard to implement it in a poll
-/// way. We can do that, but it breaks the safety boundary which requires
-/// user to make sure that the AsyncStream exists when polling the future
-/// since in our trait definition, the future has no relation with self.
-/// And without poll, we can not drive multiple futures by one future.
+/// The inner futures are kept in a shared `FuturesOrdered`, and driven by
+/// whichever returned future is polled, so no task is spawned. Note that the
+/// inner futures only make progress when some returned future is polled.
+/// When the stream and all returned futures are dropped, the inner futures
+/// are dropped too, which cancels the in-flight requests.
 pub struct Buffered<St>
 where
     St: AsyncStream,
 {
     stream: Option<St>,
-    queue: VecDeque<oneshot::Receiver<St::Item>>,
+    shared: Arc<Mutex<Shared<St::Future>>>,
     max: usize,
+    // number of futures taken from stream
+    started: usize,
+    // number of futures returned by next
+    returned: usize,
+    gate: Option<Box<dyn FnMut() -> bool + Send>>,
+    // future taken from stream but not let in by the gate
+    parked: Option<St::Future>,
+}
+
+type SizeFn<T> = Arc<dyn Fn(&T) -> usize + Send + Sync>;
+
+struct Shared<Fut: Future> {
+    running: FuturesOrdered<Fut>,
+    // resolved items not taken yet, the first one is at seq `resolved - done.len()`
+    done: VecDeque<Option<Fut::Output>>,
+    resolved: usize,
+    // wakers of pending returned futures
+    waiting: Vec<(usize, Waker)>,
+    // seqs of returned futures dropped before resolved
+    abandoned: Vec<usize>,
+    budget: Option<Budget<Fut::Output>>,
+}
+
+struct Budget<T> {
+    limit: usize,
+    // bytes of resolved items which are not taken
+    held: usize,
+    size: SizeFn<T>,
 }
 
 impl<St> Buffered<St>
@@ -48,22 +423,55 @@ where
     pub fn new(stream: St, buffer_size: usize) -> Self {
         Self {
             stream: Some(stream),
-            queue: VecDeque::with_capacity(buffer_size),
+            shared: Arc::new(Mutex::new(Shared {
+                running: FuturesOrdered::new(),
+                done: VecDeque::with_capacity(buffer_size),
+                resolved: 0,
+                waiting: Vec::new(),
+                abandoned: Vec::new(),
+                budget: None,
+            })),
             max: buffer_size,
+            started: 0,
+            returned: 0,
+            gate: None,
+            parked: None,
         }
     }
+
+    pub fn with_budget<F>(stream: St, buffer_size: usize, budget: usize, size: F) -> Self
+    where
+        F: Fn(&St::Item) -> usize + Send + Sync + 'static,
+    {
+        let this = Self::new(stream, buffer_size);
+        this.shared.lock().budget = Some(Budget {
+            limit: budget,
+            held: 0,
+            size: Arc::new(size),
+        });
+        this
+    }
+
+    pub fn with_gate<G>(stream: St, buffer_size: usize, gate: G) -> Self
+    where
+        G: FnMut() -> bool + Send + 'static,
+    {
+        let mut this = Self::new(stream, buffer_size);
+        this.gate = Some(Box::new(gate));
+        this
+    }
 }
 
 impl<St> fmt::Debug for Buffered<St>
 where
     St: AsyncStream + fmt::Debug,
-    St::Item: fmt::Debug,
 {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         f.debug_struct("Buffered")
             .field("stream", &self.stream)
-            .field("queue", &self.queue)
             .field("max", &self.max)
+            .field("started", &self.started)
+            .field("returned", &self.returned)
             .finish()
     }
 }
@@ -71,38 +479,262 @@ where
 impl<St> AsyncStream for Buffered<St>
 where
     St: AsyncStream,
-    St::Item: Send + 'static,
-    St::Future: Send + 'static,
 {
     type Item = St::Item;
 
-    type Future = impl std::future::Future<Output = Self::Item>;
+    type Future = BufferedItem<St::Future>;
 
     fn next(&mut self) -> Option<Self::Future> {
-        while self.queue.len() < self.max {
-            let item = match self.stream.as_mut() {
-                Some(st) => match st.next() {
-                    Some(item) => Some(item),
-                    None => {
-                        self.stream = None;
-                        None
+        {
+            let mut shared = self.shared.lock();
+            while self.started - self.returned < self.max {
+                // always load one item to make progress
+                let idle = self.started == self.returned;
+                let over_budget = !idle
+                    && shared
+                        .budget
+                        .as_ref()
+                        .map(|b| b.held >= b.limit)
+                        .unwrap_or_default();
+                if over_budget {
+                    break;
+                }
+                let fut = match self.parked.take() {
+                    Some(fut) => fut,
+                    None => match self.stream.as_mut().and_then(|st| st.next()) {
+                        Some(fut) => fut,
+                        None => {
+                            self.stream = None;
+                            break;
+                        }
+                    },
+                };
+                let open = self.gate.as_mut().is_none_or(|gate| gate());
+                if !open && !idle {
+                    self.parked = Some(fut);
+                    break;
+                }
+                shared.running.push_back(fut);
+                self.started += 1;
+                if !open {
+                    break;
+                }
+            }
+        }
+        if self.returned == self.started {
+            return None;
+        }
+        let seq = self.returned;
+        self.returned += 1;
+        Some(BufferedItem {
+            shared: self.shared.clone(),
+            seq,
+            finished: false,
+        })
+    }
+
+    #[inline]
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        let (lower, upper) = match self.stream.as_ref() {
+            Some(st) => st.size_hint(),
+            None => (0, Some(0)),
+        };
+        let n = self.started - self.returned + self.parked.is_some() as usize;
+        (
+            lower.saturating_add(n),
+            upper.and_then(|x| x.checked_add(n)),
+        )
+    }
+}
+
+/// Future of a Buffered item.
+pub struct BufferedItem<Fut: Future> {
+    shared: Arc<Mutex<Shared<Fut>>>,
+    seq: usize,
+    finished: bool,
+}
+
+impl<Fut: Future> Shared<Fut> {
+    fn take(&mut self, seq: usize) -> Option<Fut::Output> {
+        let first = self.resolved - self.done.len();
+        let item = self.done.get_mut(seq.checked_sub(first)?)?.take()?;
+        while matches!(self.done.front(), Some(None)) {
+            self.done.pop_front();
+        }
+        if let Some(b) = self.budget.as_mut() {
+            b.held -= (b.size)(&item);
+        }
+        Some(item)
+    }
+
+    fn wake_all(&mut self) {
+        for (_, waker) in self.waiting.drain(..) {
+            waker.wake();
+        }
+    }
+}
+
+impl<Fut: Future> Future for BufferedItem<Fut> {
+    type Output = Fut::Output;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let seq = self.seq;
+        let mut shared = self.shared.lock();
+        loop {
+            if let Some(item) = shared.take(seq) {
+                // let another waiting future drive the rest
+                shared.wake_all();
+                drop(shared);
+                self.finished = true;
+                return Poll::Ready(item);
+            }
+            match shared.running.poll_next_unpin(cx) {
+                Poll::Ready(Some(item)) => {
+                    let resolved = shared.resolved;
+                    if let Some(idx) = shared.abandoned.iter().position(|s| *s == resolved) {
+                        shared.abandoned.swap_remove(idx);
+                        shared.done.push_back(None);
+                        shared.resolved += 1;
+                        while matches!(shared.done.front(), Some(None)) {
+                            shared.done.pop_front();
+                        }
+                        continue;
+                    }
+                    if let Some(b) = shared.budget.as_mut() {
+                        b.held += (b.size)(&item);
+                    }
+                    shared.done.push_back(Some(item));
+                    shared.resolved += 1;
+                    if let Some(idx) = shared.waiting.iter().position(|(s, _)| *s == resolved) {
+                        shared.waiting.swap_remove(idx).1.wake();
                     }
-                },
-                None => None,
-            };
-            match item {
-                Some(f) => {
-                    let (tx, rx) = oneshot::channel::<Self::Item>();
-                    tokio::spawn(async move {
-                        let _ = tx.send(f.await);
-                    });
-                    self.queue.push_back(rx);
                 }
-                None => break,
+                Poll::Ready(None) => panic!("buffered item polled after its output is taken"),
+                Poll::Pending => {
+                    match shared.waiting.iter_mut().find(|(s, _)| *s == seq) {
+                        Some((_, waker)) => waker.clone_from(cx.waker()),
+                        None => shared.waiting.push((seq, cx.waker().clone())),
+                    }
+                    return Poll::Pending;
+                }
             }
         }
-        self.queue
-            .pop_front()
-            .map(|x| x.map(|xx| xx.expect("oneshot tx dropped which is unexpected")))
+    }
+}
+
+impl<Fut: Future> Drop for BufferedItem<Fut> {
+    fn drop(&mut self) {
+        if self.finished {
+            return;
+        }
+        // this future may be the one driving others
+        let mut shared = self.shared.lock();
+        let seq = self.seq;
+        shared.waiting.retain(|(s, _)| *s != seq);
+        if shared.take(seq).is_none() {
+            shared.abandoned.push(seq);
+        }
+        shared.wake_all();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        sync::{
+            atomic::{AtomicUsize, Ordering},
+            Arc,
+        },
+        time::Duration,
+    };
+
+    use futures::StreamExt;
+
+    use super::{from_stream, iter, AsyncStream};
+
+    async fn collect<St: AsyncStream>(mut st: St) -> Vec<St::Item> {
+        let mut out = Vec::new();
+        while let Some(fut) = st.next() {
+            out.push(fut.await);
+        }
+        out
+    }
+
+    #[tokio::test]
+    async fn combinators() {
+        let numbers = || iter((0..10).map(futures::future::ready));
+        let seen = Arc::new(AtomicUsize::new(0));
+        let seen_ = seen.clone();
+        let st = numbers()
+            .skip(2)
+            .take(5)
+            .inspect(move |_| {
+                seen_.fetch_add(1, Ordering::Relaxed);
+            })
+            .map(|x| x * 10)
+            .chain(numbers().take(1))
+            .enumerate();
+        assert_eq!(st.size_hint(), (6, Some(6)));
+        assert_eq!(
+            collect(st).await,
+            [(0, 20), (1, 30), (2, 40), (3, 50), (4, 60), (5, 0)]
+        );
+        assert_eq!(seen.load(Ordering::Relaxed), 5);
+
+        let odd = numbers().filter_map(|x| (x % 2 == 1).then_some(x));
+        let odd = odd.into_stream().filter_map(futures::future::ready);
+        assert_eq!(odd.collect::<Vec<_>>().await, [1, 3, 5, 7, 9]);
+
+        let st = from_stream(futures::stream::iter(0..3));
+        assert_eq!(collect(st).await, [Some(0), Some(1), Some(2), None]);
+
+        let st = numbers().buffered_bytes(4, 2, |_| 1);
+        assert_eq!(collect(st).await, (0..10).collect::<Vec<_>>());
+    }
+
+    #[tokio::test]
+    async fn buffered() {
+        // later futures finish first, but items keep the order
+        let st = iter((0..5u64).map(|i| async move {
+            tokio::time::sleep(Duration::from_millis(20 - i * 4)).await;
+            i
+        }))
+        .buffered(3);
+        assert_eq!(collect(st).await, [0, 1, 2, 3, 4]);
+
+        // futures held back by the gate are started one by one when idle
+        let opened = Arc::new(AtomicUsize::new(0));
+        let counter = opened.clone();
+        let mut st = iter((0..4).map(futures::future::ready))
+            .buffered_gated(3, move || counter.fetch_add(1, Ordering::Relaxed) < 1);
+        let first = st.next().unwrap();
+        assert_eq!((st.started, st.size_hint()), (1, (3, Some(3))));
+        assert_eq!(first.await, 0);
+        assert_eq!(collect(st).await, [1, 2, 3]);
+        assert_eq!(opened.load(Ordering::Relaxed), 5);
+
+        // dropping the stream cancels the in-flight futures
+        struct Guard(Arc<AtomicUsize>);
+        impl Drop for Guard {
+            fn drop(&mut self) {
+                self.0.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+        let dropped = Arc::new(AtomicUsize::new(0));
+        let mut st = iter((0..4).map(|_| {
+            let guard = Guard(dropped.clone());
+            async move {
+                let _guard = guard;
+                futures::future::pending::<()>().await
+            }
+        }))
+        .buffered(2);
+        let fut = st.next().unwrap();
+        assert!(tokio::time::timeout(Duration::from_millis(10), fut)
+            .await
+            .is_err());
+        assert_eq!(dropped.load(Ordering::Relaxed), 0);
+        drop(st);
+        assert_eq!(dropped.load(Ordering::Relaxed), 2);
     }
 }
@@ -1,15 +1,29 @@
+use std::{
+    collections::VecDeque,
+    sync::Arc,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use parking_lot::Mutex;
+
+use serde::{Deserialize, Serialize};
+use tokio::sync::watch;
+use tokio_util::sync::CancellationToken;
+
 use crate::{
-    buffer::{DataSized, ImageBuffer},
+    buffer::{DataSized, ImageBuffer, MemoryBudget, MemoryPermit},
+    circuit_breaker::{self, BreakerStream},
     collector::{
         AlbumMeta, Collector, ImageData, ImageMeta, Param, Registry, URL_FROM_TEXT_RE,
         URL_FROM_URL_RE,
     },
+    http_client::HttpRequestBuilder,
     http_proxy::ProxiedClient,
     storage::{cloudflare_kv::CFStorage, KVStorage},
-    stream::{AsyncStream, Buffered},
+    stream::AsyncStream,
     telegraph::{
         types::{Node, NodeElement, NodeElementAttr, Page, PageCreate, Tag},
-        RandomAccessToken, Telegraph, TelegraphError, MAX_SINGLE_FILE_SIZE,
+        AccessToken, RandomAccessToken, Telegraph, TelegraphError, MAX_SINGLE_FILE_SIZE,
     },
     util::match_first_group,
 };
@@ -18,6 +32,9 @@ const ERR_THRESHOLD: usize = 10;
 const BATCH_LEN_THRESHOLD: usize = 20;
 const BATCH_SIZE_THRESHOLD: usize = 5 * 1024 * 1024;
 const DEFAULT_CONCURRENT: usize = 20;
+// memory budget reserved for an image before downloading, it is cut to the
+// real size once downloaded.
+const IMAGE_RESERVE: usize = MAX_SINGLE_FILE_SIZE;
 
 #[derive(thiserror::Error, Debug)]
 pub enum UploadError<SE> {
@@ -27,9 +44,143 @@ pub enum UploadError<SE> {
     Reqwest(#[from] TelegraphError),
 }
 
-pub struct Synchronizer<C = CFStorage> {
-    tg: Telegraph<RandomAccessToken, ProxiedClient>,
+/// Phase of a running sync.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum SyncProgress {
+    /// Fetching album info and image index.
+    #[default]
+    FetchingIndex,
+    /// Downloading images, total is known if the image stream reports it.
+    Downloading { done: usize, total: Option<usize> },
+    /// Uploading a batch, `done` images are uploaded before it.
+    Uploading { done: usize, total: Option<usize> },
+    /// Creating telegraph pages.
+    CreatingPages { done: usize, total: usize },
+}
+
+/// Receivers only see the latest progress, so they can read it at their own pace.
+pub type ProgressSender = watch::Sender<SyncProgress>;
+
+/// Options of a single sync.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct SyncOptions<'a> {
+    /// Report progress to the sender.
+    pub progress: Option<&'a ProgressSender>,
+    /// Abort the sync when cancelled, running downloads and uploads are dropped.
+    pub cancel: Option<&'a CancellationToken>,
+}
+
+#[derive(thiserror::Error, Debug)]
+#[error("sync cancelled")]
+pub struct SyncCancelled;
+
+#[inline]
+fn report(progress: Option<&ProgressSender>, p: SyncProgress) {
+    if let Some(tx) = progress {
+        tx.send_replace(p);
+    }
+}
+
+/// SyncRecord is what we store in cache for a synced album.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(from = "SyncRecordCompat")]
+pub struct SyncRecord {
+    /// Record format version, 0 means it is converted from a legacy plain url.
+    pub version: u32,
+    /// Url of the first telegraph page.
+    pub url: String,
+    /// Album title.
+    pub title: String,
+    /// Count of images uploaded.
+    pub image_count: usize,
+    /// All telegraph page urls in order, the first one equals to `url`.
+    pub pages: Vec<String>,
+    /// Telegraph access token used to create the pages.
+    pub token: Option<String>,
+    /// Unix timestamp(in seconds) when the sync finished.
+    pub synced_at: u64,
+    /// Source album version when it was synced.
+    pub source_version: Option<String>,
+    /// Telegraph url of the first image.
+    pub cover: Option<String>,
+    /// Album tags, namespaced like `female:glasses` if the site has namespaces.
+    pub tags: Vec<String>,
+    /// Album category like `Doujinshi`.
+    pub category: Option<String>,
+}
+
+impl SyncRecord {
+    pub const VERSION: u32 = 1;
+
+    /// Build a record with only url known.
+    pub fn from_url(url: String) -> Self {
+        Self {
+            version: 0,
+            title: String::new(),
+            image_count: 0,
+            pages: vec![url.clone()],
+            url,
+            token: None,
+            synced_at: 0,
+            source_version: None,
+            cover: None,
+            tags: Vec::new(),
+            category: None,
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct SyncRecordV1 {
+    version: u32,
+    url: String,
+    title: String,
+    image_count: usize,
+    pages: Vec<String>,
+    token: Option<String>,
+    synced_at: u64,
+    source_version: Option<String>,
+    #[serde(default)]
+    cover: Option<String>,
+    #[serde(default)]
+    tags: Vec<String>,
+    #[serde(default)]
+    category: Option<String>,
+}
+
+// Before SyncRecord, we store the url string directly.
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum SyncRecordCompat {
+    Record(SyncRecordV1),
+    Legacy(String),
+}
+
+impl From<SyncRecordCompat> for SyncRecord {
+    fn from(r: SyncRecordCompat) -> Self {
+        match r {
+            SyncRecordCompat::Record(r) => Self {
+                version: r.version,
+                url: r.url,
+                title: r.title,
+                image_count: r.image_count,
+                pages: r.pages,
+                token: r.token,
+                synced_at: r.synced_at,
+                source_version: r.source_version,
+                cover: r.cover,
+                tags: r.tags,
+                category: r.category,
+            },
+            SyncRecordCompat::Legacy(url) => Self::from_url(url),
+        }
+    }
+}
+
+pub struct Synchronizer<C = CFStorage, T = ProxiedClient> {
+    tg: Telegraph<RandomAccessToken, T>,
     limit: Option<usize>,
+    budget: Option<MemoryBudget>,
 
     author_name: Option<String>,
     author_url: Option<String>,
@@ -39,21 +190,19 @@ pub struct Synchronizer<C = CFStorage> {
     cache: C,
 }
 
-impl<CACHE> Synchronizer<CACHE>
+impl<CACHE, T> Synchronizer<CACHE, T>
 where
-    CACHE: KVStorage<String>,
+    CACHE: KVStorage<SyncRecord>,
+    T: HttpRequestBuilder + Clone,
 {
     // cache ttl is 45 days
     const DEFAULT_CACHE_TTL: usize = 3600 * 24 * 45;
 
-    pub fn new(
-        tg: Telegraph<RandomAccessToken, ProxiedClient>,
-        registry: Registry,
-        cache: CACHE,
-    ) -> Self {
+    pub fn new(tg: Telegraph<RandomAccessToken, T>, registry: Registry, cache: CACHE) -> Self {
         Self {
             tg,
             limit: None,
+            budget: MemoryBudget::global().cloned(),
             author_name: None,
             author_url: None,
             cache_ttl: None,
@@ -67,6 +216,13 @@ where
         self
     }
 
+    /// Bound the image data held by syncs sharing the budget. The global
+    /// budget is used by default.
+    pub fn with_memory_budget(mut self, budget: MemoryBudget) -> Self {
+        self.budget = Some(budget);
+        self
+    }
+
     pub fn with_author<S: Into<String>>(mut self, name: Option<S>, url: Option<S>) -> Self {
         self.author_name = name.map(Into::into);
         self.author_url = url.map(Into::into);
@@ -82,7 +238,34 @@ where
         self.cache.delete(key).await
     }
 
-    pub async fn sync<C: Collector>(&self, path: String) -> anyhow::Result<String>
+    /// Delete the cached record, so the album will be synced again.
+    pub async fn delete_cached<C: Collector>(&self, path: &str) -> anyhow::Result<()> {
+        self.cache.delete(&cache_key::<C>(path)).await
+    }
+
+    /// Look up the cache only, returns None if the album is not synced.
+    pub async fn cached<C: Collector>(&self, path: &str) -> anyhow::Result<Option<SyncRecord>> {
+        self.cache.get(&cache_key::<C>(path)).await
+    }
+
+    pub async fn sync<C: Collector>(&self, path: String) -> anyhow::Result<SyncRecord>
+    where
+        Registry: Param<C>,
+        C::FetchError: Into<anyhow::Error> + Send + 'static,
+        C::StreamError:
+            Into<anyhow::Error> + std::fmt::Debug + std::fmt::Display + Send + Sync + 'static,
+        C::ImageStream: Send + 'static,
+        <C::ImageStream as AsyncStream>::Future: Send + 'static,
+    {
+        self.sync_with::<C>(path, SyncOptions::default()).await
+    }
+
+    /// Sync with options. Nothing is reported if the record is cached.
+    pub async fn sync_with<C: Collector>(
+        &self,
+        path: String,
+        opts: SyncOptions<'_>,
+    ) -> anyhow::Result<SyncRecord>
     where
         Registry: Param<C>,
         C::FetchError: Into<anyhow::Error> + Send + 'static,
@@ -92,47 +275,86 @@ where
         <C::ImageStream as AsyncStream>::Future: Send + 'static,
     {
         // check cache
-        let cache_key = format!("{}|{}", C::name(), path);
+        let cache_key = cache_key::<C>(&path);
         if let Ok(Some(v)) = self.cache.get(&cache_key).await {
             tracing::info!("[cache] hit key {cache_key}");
             return Ok(v);
         }
         tracing::info!("[cache] miss key {cache_key}");
 
-        let collector: &C = self.registry.get();
-        let (meta, stream) = collector.fetch(path).await.map_err(Into::into)?;
-        let page = self
-            .sync_stream(meta, stream)
-            .await
-            .map_err(anyhow::Error::from)?;
+        report(opts.progress, SyncProgress::FetchingIndex);
+        let work = async {
+            // fail fast if the site keeps failing
+            let breaker = circuit_breaker::get(C::name());
+            let permit = breaker.acquire()?;
+            let collector: &C = self.registry.get();
+            let fetched = collector.fetch(path).await.map_err(Into::into);
+            permit.report_result(&fetched);
+            let (meta, stream) = fetched?;
+            let stream = BreakerStream::new(stream, breaker);
+            self.sync_stream(meta, stream, opts.progress)
+                .await
+                .map_err(anyhow::Error::from)
+        };
+        let record = match opts.cancel {
+            Some(cancel) => tokio::select! {
+                r = work => r?,
+                _ = cancel.cancelled() => {
+                    tracing::info!("[sync] sync cancelled for key {cache_key}");
+                    return Err(SyncCancelled.into());
+                }
+            },
+            None => work.await?,
+        };
 
         // set cache
         let _ = self
             .cache
             .set(
                 cache_key,
-                page.url.clone(),
+                record.clone(),
                 Some(self.cache_ttl.unwrap_or(Self::DEFAULT_CACHE_TTL)),
             )
             .await;
-        Ok(page.url)
+        Ok(record)
     }
 
     pub async fn sync_stream<S, SE>(
         &self,
         meta: AlbumMeta,
         stream: S,
-    ) -> Result<Page, UploadError<SE>>
+        progress: Option<&ProgressSender>,
+    ) -> Result<SyncRecord, UploadError<SE>>
     where
         SE: Send + std::fmt::Debug + 'static,
         S: AsyncStream<Item = Result<(ImageMeta, ImageData), SE>>,
         S::Future: Send + 'static,
     {
-        let buffered_stream = Buffered::new(stream, self.limit.unwrap_or(DEFAULT_CONCURRENT));
-        let r = self.inner_sync_stream(meta, buffered_stream).await;
+        let total = stream.size_hint().1;
+        let limit = self.limit.unwrap_or(DEFAULT_CONCURRENT);
+        // reserve memory budget before an image is downloaded, so images
+        // loaded ahead in the buffered stream are bounded too. Permits are
+        // queued in the order of started images.
+        let reserved = Arc::new(Mutex::new(VecDeque::new()));
+        let buffered_stream = match self.budget.clone() {
+            Some(budget) => {
+                let reserved = reserved.clone();
+                stream.buffered_gated(limit, move || match budget.try_acquire(IMAGE_RESERVE) {
+                    Some(permit) => {
+                        reserved.lock().push_back(permit);
+                        true
+                    }
+                    None => false,
+                })
+            }
+            None => stream.buffered(limit),
+        };
+        let r = self
+            .inner_sync_stream(meta, buffered_stream, total, &reserved, progress)
+            .await;
         match &r {
-            Ok(p) => {
-                tracing::info!("[sync] sync success with url {}", p.url);
+            Ok(r) => {
+                tracing::info!("[sync] sync success with url {}", r.url);
             }
             Err(e) => {
                 tracing::error!("[sync] sync fail! {e:?}");
@@ -145,25 +367,71 @@ where
         &self,
         meta: AlbumMeta,
         mut stream: S,
-    ) -> Result<Page, UploadError<SE>>
+        total: Option<usize>,
+        // memory budget reserved for the started images
+        reserved: &Mutex<VecDeque<MemoryPermit>>,
+        progress: Option<&ProgressSender>,
+    ) -> Result<SyncRecord, UploadError<SE>>
     where
         S: AsyncStream<Item = Result<(ImageMeta, ImageData), SE>>,
     {
+        // None if the image is started without budget, it must not be
+        // downloaded until the budget is taken.
+        let take_reserved = || match self.budget {
+            Some(_) => reserved.lock().pop_front(),
+            None => Some(MemoryPermit::default()),
+        };
         let mut err_count = 0;
+        let mut downloaded = 0;
         let mut uploaded = Vec::new();
 
         let mut buffer = ImageBuffer::new();
+        // item loaded while uploading the last batch
+        let mut prefetched = None;
+        // image started without budget, waiting for the batch to be uploaded
+        let mut pending = None;
 
         // in this big loop, we will download images, and upload them in batch.
         // then, all meta info will be saved in `uploaded`.
         loop {
-            // TODO: load images one by one is too slow!
-            // We can spawn a background task(FuturesUnordered) and use channel, but expose as AsyncStream,
-            // which does not require changes on consuming side.
-
             // 1. download images in batch
-            while let Some(fut) = stream.next() {
-                let data = match fut.await {
+            loop {
+                let (item, mut permit) = match prefetched.take() {
+                    Some(item) => item,
+                    None => {
+                        let (fut, permit) = match pending.take() {
+                            Some(fut) => (fut, None),
+                            None => match stream.next() {
+                                Some(fut) => (fut, take_reserved()),
+                                None => break,
+                            },
+                        };
+                        let permit = match (permit, &self.budget) {
+                            (Some(permit), _) => permit,
+                            // the budget is used up, upload the current batch
+                            // to release ours first.
+                            (None, _) if !buffer.is_empty() => {
+                                pending = Some(fut);
+                                break;
+                            }
+                            // we hold nothing now, so it's safe to wait for
+                            // other syncs.
+                            (None, Some(budget)) => budget.acquire(IMAGE_RESERVE).await,
+                            (None, None) => MemoryPermit::default(),
+                        };
+                        let item = fut.await;
+                        downloaded += 1;
+                        report(
+                            progress,
+                            SyncProgress::Downloading {
+                                done: downloaded,
+                                total,
+                            },
+                        );
+                        (item, permit)
+                    }
+                };
+                let data = match item {
                     Err(e) => {
                         err_count += 1;
                         if err_count > ERR_THRESHOLD {
@@ -176,14 +444,15 @@ where
                         d
                     }
                 };
-
                 // if the data size is too big to upload, we will discard it.
                 if data.1.len() >= MAX_SINGLE_FILE_SIZE {
                     tracing::error!("Too big file, discarded. Meta: {:?}", data.0);
                     continue;
                 }
 
-                buffer.push(data);
+                // hold memory budget for the buffered data only
+                permit.shrink(data.1.len());
+                buffer.push(data, permit);
                 if buffer.len() > BATCH_LEN_THRESHOLD || buffer.size() > BATCH_SIZE_THRESHOLD {
                     break;
                 }
@@ -195,15 +464,46 @@ where
             }
 
             // 2. upload the batch
-            let (full_data, size) = buffer.swap();
+            let (full_data, size, permit) = buffer.swap();
             let image_count = full_data.len();
+            report(
+                progress,
+                SyncProgress::Uploading {
+                    done: uploaded.len(),
+                    total,
+                },
+            );
             tracing::debug!("download {image_count} images with size {size}, will upload them",);
 
             let (meta, data) = full_data
                 .into_iter()
                 .map(|(a, b)| (a, b.as_ref().to_owned()))
                 .unzip::<_, _, Vec<_>, Vec<_>>();
-            let medium = self.tg.upload(data).await?;
+            // the buffered stream is only driven when polled, so keep loading
+            // the next image while uploading, if its budget is reserved.
+            let next = match (&prefetched, &pending) {
+                (None, None) => stream.next(),
+                _ => None,
+            };
+            let next = next.and_then(|fut| match take_reserved() {
+                Some(permit) => Some((fut, permit)),
+                None => {
+                    pending = Some(fut);
+                    None
+                }
+            });
+            let (medium, next) = futures::join!(self.tg.upload(data), async move {
+                match next {
+                    Some((fut, permit)) => Some((fut.await, permit)),
+                    None => None,
+                }
+            });
+            let medium = medium?;
+            drop(permit);
+            if next.is_some() {
+                downloaded += 1;
+                prefetched = next;
+            }
             err_count = 0;
 
             // 3. add to uploaded
@@ -215,6 +515,11 @@ where
             );
         }
 
+        let image_count = uploaded.len();
+        let cover = uploaded
+            .first()
+            .map(|i| format!("https://telegra.ph{}", i.src));
+
         // create telegraph page, or multi pages
         // Telegraph has 64K limit, since our estimate is not accurate, here we use 48K.
         const PAGE_SIZE_LIMIT: usize = 48 * 1024;
@@ -231,9 +536,21 @@ where
             chunks.last_mut().unwrap().push(item);
         }
 
+        // all pages of one album are created with the same token, so we can
+        // edit them later.
+        let tg = self.tg.pinned();
+        let page_count = chunks.len();
+        let mut pages = Vec::with_capacity(page_count);
         let mut last_page: Option<Page> = None;
         let title = meta.name.replace('|', "");
         while let Some(last_chunk) = chunks.pop() {
+            report(
+                progress,
+                SyncProgress::CreatingPages {
+                    done: pages.len(),
+                    total: page_count,
+                },
+            );
             let mut content = last_chunk;
             write_footer(
                 &mut content,
@@ -245,8 +562,7 @@ where
                 n => format!("{}-Page{}", title, n + 1),
             };
             tracing::debug!("create page with content: {content:?}");
-            let page = self
-                .tg
+            let page = tg
                 .create_page(&PageCreate {
                     title,
                     content,
@@ -259,12 +575,37 @@ where
                 .await
                 .map_err(UploadError::Reqwest)?;
 
+            pages.push(page.url.clone());
             last_page = Some(page);
         }
-        Ok(last_page.unwrap())
+        // pages are created from the last one
+        pages.reverse();
+
+        let synced_at = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or_default();
+        Ok(SyncRecord {
+            version: SyncRecord::VERSION,
+            url: last_page.unwrap().url,
+            title: meta.name,
+            image_count,
+            pages,
+            token: Some(tg.access_token().token().to_string()),
+            synced_at,
+            source_version: meta.version,
+            cover,
+            tags: meta.tags.unwrap_or_default(),
+            category: meta.class,
+        })
     }
 }
 
+#[inline]
+fn cache_key<C: Collector>(path: &str) -> String {
+    format!("{}|{}", C::name(), path)
+}
+
 fn write_footer(content: &mut Vec<Node>, original_link: &str, next_page: Option<&str>) {
     if let Some(page) = next_page {
         content.push(np!(na!(@page, nt!("Next Page"))));
@@ -284,6 +625,11 @@ impl Synchronizer {
         match_first_group(&URL_FROM_TEXT_RE, content)
     }
 
+    /// All supported urls in the text, in order of appearance.
+    pub fn match_urls_from_text(content: &str) -> impl Iterator<Item = &str> {
+        URL_FROM_TEXT_RE.find_iter(content).map(|m| m.as_str())
+    }
+
     pub fn match_url_from_url(content: &str) -> Option<&str> {
         match_first_group(&URL_FROM_URL_RE, content)
     }
@@ -308,3 +654,208 @@ impl From<UploadedImage> for Node {
         Node::new_image(format!("https://telegra.ph{}", i.src))
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    };
+
+    use reqwest::{header::CONTENT_LENGTH, RequestBuilder, Response};
+
+    use super::{SyncRecord, Synchronizer};
+    use crate::{
+        buffer::MemoryBudget,
+        collector::{
+            e_hentai::EHCollector,
+            exhentai::{EXCollector, ExConfig},
+            nhentai::NHCollector,
+            AlbumMeta, ImageMeta, Registry,
+        },
+        http_client::HttpRequestBuilder,
+        http_replay::Fixture,
+        storage::SimpleMemStorage,
+        stream,
+        telegraph::{RandomAccessToken, Telegraph},
+    };
+
+    fn registry(eh: EHCollector) -> Registry {
+        Registry::new(
+            eh,
+            NHCollector::new(),
+            EXCollector::new(
+                &ExConfig {
+                    ipb_pass_hash: String::new(),
+                    ipb_member_id: String::new(),
+                    igneous: String::new(),
+                },
+                None,
+            )
+            .unwrap(),
+        )
+    }
+
+    #[tokio::test]
+    async fn replay_sync() {
+        let eh = Fixture::open("sync_e_hentai");
+        let tg = Fixture::open("telegraph");
+        let token = match tg.is_recording() {
+            true => std::env::var("TELEGRAPH_TOKEN").expect("TELEGRAPH_TOKEN is required"),
+            false => "fixture-token".to_string(),
+        };
+        let telegraph = Telegraph::<RandomAccessToken>::new(token)
+            .with_proxy(tg.client(|| reqwest::Client::new().into()));
+        let registry = registry(EHCollector::from_fixture(&eh));
+        let synchronizer = Synchronizer::new(telegraph, registry, SimpleMemStorage::default());
+
+        let path = "/g/2122174/fd2525031e";
+        let record = synchronizer
+            .sync::<EHCollector>(path.to_string())
+            .await
+            .unwrap();
+        assert_eq!(record.url, "https://telegra.ph/Fixture-Album-10-19");
+        assert_eq!(record.pages, ["https://telegra.ph/Fixture-Album-10-19"]);
+        assert_eq!(record.title, "Fixture Album");
+        assert_eq!(record.image_count, 2);
+        assert_eq!(
+            record.cover.as_deref(),
+            Some("https://telegra.ph/file/fixture-1.jpg")
+        );
+        assert_eq!(eh.missed(), 0);
+        assert_eq!(tg.missed(), 0);
+
+        let cached = synchronizer.cached::<EHCollector>(path).await.unwrap();
+        assert_eq!(cached, Some(record));
+    }
+
+    const IMAGE_SIZE: usize = 1024 * 1024;
+
+    /// Telegraph accepting images of IMAGE_SIZE bytes. Uploaded bytes are
+    /// removed from `held`.
+    #[derive(Clone)]
+    struct FakeTelegraph {
+        held: Arc<AtomicUsize>,
+    }
+
+    impl HttpRequestBuilder for FakeTelegraph {
+        fn get_builder(&self, url: &str) -> RequestBuilder {
+            reqwest::Client::new().get(url)
+        }
+
+        fn post_builder(&self, url: &str) -> RequestBuilder {
+            reqwest::Client::new().post(url)
+        }
+
+        async fn send_raw(&self, builder: RequestBuilder) -> reqwest::Result<Response> {
+            let request = builder.build()?;
+            let body = match request.url().path() {
+                "/upload" => {
+                    // the multipart overhead is far less than an image
+                    let len: usize = request.headers()[CONTENT_LENGTH]
+                        .to_str()
+                        .unwrap()
+                        .parse()
+                        .unwrap();
+                    let count = len / IMAGE_SIZE;
+                    // images loaded ahead pile up while uploading
+                    tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+                    self.held.fetch_sub(count * IMAGE_SIZE, Ordering::Relaxed);
+                    serde_json::json!(vec![serde_json::json!({"src": "/file/1.jpg"}); count])
+                }
+                _ => serde_json::json!({"ok": true, "result": {
+                    "path": "Album-10-19", "url": "https://telegra.ph/Album-10-19",
+                    "title": "Album", "description": "", "views": 0, "can_edit": true,
+                }}),
+            };
+            Ok(http::Response::new(body.to_string()).into())
+        }
+    }
+
+    #[tokio::test]
+    async fn shared_memory_budget() {
+        const IMAGES: usize = 12;
+        let budget = MemoryBudget::new(16 * IMAGE_SIZE);
+        let held = Arc::new(AtomicUsize::new(0));
+        let peak = Arc::new(AtomicUsize::new(0));
+        let telegraph = Telegraph::<RandomAccessToken>::new("token".to_string())
+            .with_proxy(FakeTelegraph { held: held.clone() });
+        let synchronizer = Synchronizer::new(
+            telegraph,
+            registry(EHCollector::new(None)),
+            SimpleMemStorage::default(),
+        )
+        .with_memory_budget(budget.clone());
+
+        let sync = |name: &str| {
+            let meta = AlbumMeta {
+                link: format!("https://e-hentai.org/g/{name}/"),
+                name: name.to_string(),
+                class: None,
+                description: None,
+                authors: None,
+                tags: None,
+                version: None,
+            };
+            let (held, peak) = (held.clone(), peak.clone());
+            let images = stream::iter((0..IMAGES).map(move |i| {
+                let (held, peak) = (held.clone(), peak.clone());
+                async move {
+                    tokio::task::yield_now().await;
+                    let data = vec![0u8; IMAGE_SIZE];
+                    let now = held.fetch_add(IMAGE_SIZE, Ordering::Relaxed) + IMAGE_SIZE;
+                    peak.fetch_max(now, Ordering::Relaxed);
+                    let meta = ImageMeta {
+                        id: i.to_string(),
+                        url: format!("https://e-hentai.org/s/{i}"),
+                        description: None,
+                    };
+                    Ok::<_, ()>((meta, data.into()))
+                }
+            }));
+            synchronizer.sync_stream(meta, images, None)
+        };
+        let (a, b) = tokio::join!(sync("1"), sync("2"));
+        assert_eq!(a.unwrap().image_count, IMAGES);
+        assert_eq!(b.unwrap().image_count, IMAGES);
+
+        // images held by both syncs never exceed the budget
+        let peak = peak.load(Ordering::Relaxed);
+        assert!(peak > IMAGE_SIZE && peak <= budget.total(), "peak {peak}");
+        assert_eq!(held.load(Ordering::Relaxed), 0);
+        assert_eq!(budget.available(), budget.total());
+    }
+
+    #[test]
+    fn record_compat() {
+        let legacy: SyncRecord =
+            serde_json::from_str(r#""https://telegra.ph/test-01-01""#).unwrap();
+        assert_eq!(
+            legacy,
+            SyncRecord::from_url("https://telegra.ph/test-01-01".to_string())
+        );
+
+        let mut record = SyncRecord::from_url("https://telegra.ph/test-01-01".to_string());
+        record.version = SyncRecord::VERSION;
+        record.title = "test".to_string();
+        let encoded = serde_json::to_string(&record).unwrap();
+        assert_eq!(
+            serde_json::from_str::<SyncRecord>(&encoded).unwrap(),
+            record
+        );
+    }
+
+    #[test]
+    fn match_urls() {
+        let text = "1. https://e-hentai.org/g/2122174/fd2525031e/\n2. https://nhentai.net/g/123 (https://exhentai.org/g/2127986/da1deffea5)";
+        let urls = Synchronizer::match_urls_from_text(text).collect::<Vec<_>>();
+        assert_eq!(
+            urls,
+            [
+                "https://e-hentai.org/g/2122174/fd2525031e",
+                "https://nhentai.net/g/123",
+                "https://exhentai.org/g/2127986/da1deffea5"
+            ]
+        );
+    }
+}
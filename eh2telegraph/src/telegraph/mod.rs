@@ -79,9 +79,9 @@ impl From<Vec<String>> for RandomAccessToken {
 }
 
 macro_rules! execute {
-    ($send: expr) => {
-        $send
-            .send()
+    ($client: expr, $send: expr) => {
+        $client
+            .execute($send)
             .await
             .and_then(Response::error_for_status)?
             .json::<ApiResult<_>>()
@@ -112,6 +112,24 @@ impl<T, C> Telegraph<T, C> {
             access_token: self.access_token,
         }
     }
+
+    pub fn access_token(&self) -> &T {
+        &self.access_token
+    }
+}
+
+impl<T, C> Telegraph<T, C>
+where
+    T: AccessToken,
+    C: Clone,
+{
+    /// Pin a single token so all following calls are made with the same account.
+    pub fn pinned(&self) -> Telegraph<SingleAccessToken, C> {
+        Telegraph {
+            client: self.client.clone(),
+            access_token: SingleAccessToken::from(self.access_token.token().to_string()),
+        }
+    }
 }
 
 impl<T, C> Telegraph<T, C>
@@ -160,10 +178,12 @@ where
                 author_url: &page.author_url,
             },
         };
-        execute!(self
-            .client
-            .post_builder("https://api.telegra.ph/createPage")
-            .form(&to_post))
+        execute!(
+            self.client,
+            self.client
+                .post_builder("https://api.telegra.ph/createPage")
+                .form(&to_post)
+        )
     }
 
     /// Edit page.
@@ -208,10 +228,12 @@ where
                 author_url: &page.author_url,
             },
         };
-        execute!(self
-            .client
-            .post_builder("https://api.telegra.ph/editPage")
-            .form(&to_post))
+        execute!(
+            self.client,
+            self.client
+                .post_builder("https://api.telegra.ph/editPage")
+                .form(&to_post)
+        )
     }
 
     /// Get page.
@@ -229,10 +251,12 @@ where
             path,
             return_content: Some(true),
         };
-        execute!(self
-            .client
-            .post_builder("https://api.telegra.ph/getPage")
-            .form(&to_post))
+        execute!(
+            self.client,
+            self.client
+                .post_builder("https://api.telegra.ph/getPage")
+                .form(&to_post)
+        )
     }
 
     /// Upload file.
@@ -252,9 +276,11 @@ where
 
         let r: Result<Vec<MediaInfo>, TelegraphError> = self
             .client
-            .post_builder("https://telegra.ph/upload")
-            .multipart(form)
-            .send()
+            .execute(
+                self.client
+                    .post_builder("https://telegra.ph/upload")
+                    .multipart(form),
+            )
             .await
             .and_then(Response::error_for_status)?
             .json::<UploadResult>()
@@ -1,83 +1,305 @@
-use std::sync::Arc;
+//! TLS verification policy.
+//!
+//! Config example:
+//! ```yaml
+//! http:
+//!   tls:
+//!     hosts:
+//!       api.telegra.ph:
+//!         alt_names: [telegram.org]
+//!       proxy.example.com:
+//!         extra_roots: [/etc/eh2telegraph/ca.pem]
+//!         spki_pins: [base64 of sha256 of the certificate public key]
+//! ```
+use std::{collections::HashMap, sync::Arc};
 
+use base64::Engine;
 use rustls::{
-    client::{ServerCertVerifier, WebPkiVerifier},
-    Certificate, ClientConfig, RootCertStore, ServerName,
+    client::{
+        danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier},
+        WebPkiServerVerifier,
+    },
+    pki_types::{CertificateDer, ServerName, UnixTime},
+    ClientConfig, DigitallySignedStruct, RootCertStore, SignatureScheme,
 };
+use sha2::{Digest, Sha256};
 
-pub struct WhitelistVerifier<const N: usize> {
-    verifier: WebPkiVerifier,
-    dns_names: [&'static str; N],
+use crate::config;
+
+const CONFIG_KEY: &str = "http";
+
+#[derive(serde::Deserialize, Clone, Debug, Default)]
+pub struct HostTlsConfig {
+    /// Certificates of these names are also accepted.
+    #[serde(default)]
+    pub alt_names: Vec<String>,
+    /// Base64 encoded sha256 of the certificate SubjectPublicKeyInfo, one of
+    /// them must match if it is not empty.
+    #[serde(default)]
+    pub spki_pins: Vec<String>,
+    /// PEM files of extra root certificates.
+    #[serde(default)]
+    pub extra_roots: Vec<String>,
+}
+
+#[derive(serde::Deserialize, Clone, Debug, Default)]
+pub struct TlsConfig {
+    #[serde(default)]
+    pub hosts: HashMap<String, HostTlsConfig>,
 }
 
-/// Custom verifier that allow hostname difference with specified dns names.
-impl<const N: usize> WhitelistVerifier<N> {
-    pub fn new(dns_names: [&'static str; N]) -> Self {
-        use rustls::OwnedTrustAnchor;
-        let mut root_cert_store = RootCertStore::empty();
-        let trust_anchors = webpki_roots::TLS_SERVER_ROOTS.0.iter().map(|trust_anchor| {
-            OwnedTrustAnchor::from_subject_spki_name_constraints(
-                trust_anchor.subject,
-                trust_anchor.spki,
-                trust_anchor.name_constraints,
-            )
-        });
-        root_cert_store.add_server_trust_anchors(trust_anchors);
-        let verifier = WebPkiVerifier::new(root_cert_store, None);
+#[derive(serde::Deserialize, Default)]
+struct HTTPConfig {
+    #[serde(default)]
+    tls: TlsConfig,
+}
+
+impl TlsConfig {
+    /// Read `http.tls` config.
+    pub fn from_config() -> anyhow::Result<Self> {
+        let config: HTTPConfig = config::parse(CONFIG_KEY)?.unwrap_or_default();
+        Ok(config.tls)
+    }
+
+    /// Build rustls config, returns None if there is no host rule.
+    pub fn client_config(&self) -> anyhow::Result<Option<ClientConfig>> {
+        if self.hosts.is_empty() {
+            return Ok(None);
+        }
+        Ok(Some(WhitelistVerifier::from_config(self)?.into()))
+    }
+}
+
+fn webpki_roots() -> RootCertStore {
+    RootCertStore {
+        roots: webpki_roots::TLS_SERVER_ROOTS.to_vec(),
+    }
+}
+
+fn build_verifier(roots: RootCertStore) -> anyhow::Result<Arc<WebPkiServerVerifier>> {
+    Ok(WebPkiServerVerifier::builder(Arc::new(roots)).build()?)
+}
+
+#[derive(Debug)]
+struct HostRule {
+    verifier: Arc<WebPkiServerVerifier>,
+    alt_names: Vec<ServerName<'static>>,
+    pins: Vec<Vec<u8>>,
+}
+
+/// Custom verifier that allow hostname difference with specified dns names,
+/// and checks host rules of extra roots and SPKI pins.
+#[derive(Debug)]
+pub struct WhitelistVerifier {
+    verifier: Arc<WebPkiServerVerifier>,
+    // alt names for all hosts
+    dns_names: Vec<ServerName<'static>>,
+    hosts: HashMap<String, HostRule>,
+}
+
+impl WhitelistVerifier {
+    pub fn new<I, S>(dns_names: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: AsRef<str>,
+    {
         Self {
-            verifier,
-            dns_names,
+            verifier: build_verifier(webpki_roots()).expect("unable to build tls verifier"),
+            dns_names: dns_names
+                .into_iter()
+                .filter_map(|n| ServerName::try_from(n.as_ref().to_string()).ok())
+                .collect(),
+            hosts: HashMap::new(),
+        }
+    }
+
+    pub fn from_config(config: &TlsConfig) -> anyhow::Result<Self> {
+        let mut hosts = HashMap::with_capacity(config.hosts.len());
+        for (host, cfg) in config.hosts.iter() {
+            let mut roots = webpki_roots();
+            for path in cfg.extra_roots.iter() {
+                let pem = std::fs::read(path)
+                    .map_err(|e| anyhow::anyhow!("unable to read root certificate {path}: {e}"))?;
+                for cert in rustls_pemfile::certs(&mut pem.as_slice()) {
+                    roots.add(cert?)?;
+                }
+            }
+            let alt_names = cfg
+                .alt_names
+                .iter()
+                .map(|n| ServerName::try_from(n.clone()))
+                .collect::<Result<_, _>>()?;
+            let pins = cfg
+                .spki_pins
+                .iter()
+                .map(|p| base64::engine::general_purpose::STANDARD.decode(p))
+                .collect::<Result<_, _>>()?;
+            hosts.insert(
+                host.clone(),
+                HostRule {
+                    verifier: build_verifier(roots)?,
+                    alt_names,
+                    pins,
+                },
+            );
         }
+        Ok(Self {
+            verifier: build_verifier(webpki_roots())?,
+            dns_names: Vec::new(),
+            hosts,
+        })
     }
 }
 
-impl<const N: usize> From<WhitelistVerifier<N>> for ClientConfig {
-    fn from(v: WhitelistVerifier<N>) -> Self {
-        let mut cfg = ClientConfig::builder()
-            .with_safe_defaults()
-            .with_root_certificates(RootCertStore::empty())
-            .with_no_client_auth();
-        cfg.dangerous().set_certificate_verifier(Arc::new(v));
-        cfg
+impl From<WhitelistVerifier> for ClientConfig {
+    fn from(v: WhitelistVerifier) -> Self {
+        ClientConfig::builder()
+            .dangerous()
+            .with_custom_certificate_verifier(Arc::new(v))
+            .with_no_client_auth()
     }
 }
 
-impl<const N: usize> ServerCertVerifier for WhitelistVerifier<N> {
+/// Sha256 of the certificate SubjectPublicKeyInfo.
+fn spki_sha256(cert: &CertificateDer<'_>) -> Result<Vec<u8>, rustls::Error> {
+    let (_, cert) = x509_parser::parse_x509_certificate(cert.as_ref())
+        .map_err(|_| rustls::Error::InvalidCertificate(rustls::CertificateError::BadEncoding))?;
+    Ok(Sha256::digest(cert.tbs_certificate.subject_pki.raw).to_vec())
+}
+
+impl ServerCertVerifier for WhitelistVerifier {
     fn verify_server_cert(
         &self,
-        end_entity: &Certificate,
-        intermediates: &[Certificate],
-        server_name: &rustls::ServerName,
-        scts: &mut dyn Iterator<Item = &[u8]>,
+        end_entity: &CertificateDer<'_>,
+        intermediates: &[CertificateDer<'_>],
+        server_name: &ServerName<'_>,
         ocsp_response: &[u8],
-        now: std::time::SystemTime,
-    ) -> Result<rustls::client::ServerCertVerified, rustls::Error> {
-        let original_validate_result = self.verifier.verify_server_cert(
-            end_entity,
-            intermediates,
-            server_name,
-            scts,
-            ocsp_response,
-            now,
-        );
-        if original_validate_result.is_ok() {
-            return original_validate_result;
+        now: UnixTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        let rule = match server_name {
+            ServerName::DnsName(name) => self.hosts.get(name.as_ref()),
+            _ => None,
+        };
+        let verifier = rule.map(|r| &r.verifier).unwrap_or(&self.verifier);
+        let alt_names = rule.map(|r| r.alt_names.as_slice()).unwrap_or_default();
+
+        let mut result =
+            verifier.verify_server_cert(end_entity, intermediates, server_name, ocsp_response, now);
+        for dns_name in alt_names.iter().chain(self.dns_names.iter()) {
+            if result.is_ok() {
+                break;
+            }
+            result = verifier.verify_server_cert(
+                end_entity,
+                intermediates,
+                dns_name,
+                ocsp_response,
+                now,
+            );
         }
-        for dns_name in self.dns_names.iter() {
-            if let Ok(dns_name) = ServerName::try_from(*dns_name) {
-                let whitelist_validate_result = self.verifier.verify_server_cert(
-                    end_entity,
-                    intermediates,
-                    &dns_name,
-                    scts,
-                    ocsp_response,
-                    now,
-                );
-                if whitelist_validate_result.is_ok() {
-                    return whitelist_validate_result;
-                }
+        let verified = result?;
+
+        if let Some(rule) = rule.filter(|r| !r.pins.is_empty()) {
+            let spki = spki_sha256(end_entity)?;
+            if !rule.pins.contains(&spki) {
+                return Err(rustls::Error::InvalidCertificate(
+                    rustls::CertificateError::ApplicationVerificationFailure,
+                ));
             }
         }
-        original_validate_result
+        Ok(verified)
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        self.verifier.verify_tls12_signature(message, cert, dss)
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        self.verifier.verify_tls13_signature(message, cert, dss)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.verifier.supported_verify_schemes()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use base64::Engine;
+    use rcgen::{BasicConstraints, Certificate, CertificateParams, IsCa};
+    use rustls::{
+        client::danger::ServerCertVerifier,
+        pki_types::{CertificateDer, ServerName, UnixTime},
+    };
+    use sha2::{Digest, Sha256};
+
+    use super::{TlsConfig, WhitelistVerifier};
+
+    #[test]
+    fn parse_config() {
+        let config: TlsConfig = serde_yaml::from_str(
+            "hosts:\n  api.telegra.ph:\n    alt_names: [telegram.org]\n    spki_pins: [AAAA]",
+        )
+        .unwrap();
+        assert!(config.client_config().unwrap().is_some());
+        assert!(TlsConfig::default().client_config().unwrap().is_none());
+
+        let invalid: TlsConfig =
+            serde_yaml::from_str("hosts:\n  a.com:\n    spki_pins: [\"!\"]").unwrap();
+        assert!(invalid.client_config().is_err());
+    }
+
+    #[test]
+    fn verify() {
+        let mut ca_params = CertificateParams::new(Vec::new());
+        ca_params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+        let ca = Certificate::from_params(ca_params).unwrap();
+        let leaf = Certificate::from_params(CertificateParams::new(vec![
+            "telegram.org".to_string(),
+            "pinned.example".to_string(),
+        ]))
+        .unwrap();
+        let leaf_der = CertificateDer::from(leaf.serialize_der_with_signer(&ca).unwrap());
+        let pin = base64::engine::general_purpose::STANDARD
+            .encode(Sha256::digest(leaf.get_key_pair().public_key_der()));
+
+        let ca_path =
+            std::env::temp_dir().join(format!("eh2telegraph-ca-{}.pem", std::process::id()));
+        std::fs::write(&ca_path, ca.serialize_pem().unwrap()).unwrap();
+        let ca_path = ca_path.display();
+        let config: TlsConfig = serde_yaml::from_str(&format!(
+            "hosts:
+  api.telegra.ph: {{ alt_names: [telegram.org], extra_roots: [{ca_path}] }}
+  other.example: {{ extra_roots: [{ca_path}] }}
+  pinned.example: {{ extra_roots: [{ca_path}], spki_pins: [{pin}] }}
+  wrong-pin.example: {{ alt_names: [pinned.example], extra_roots: [{ca_path}], spki_pins: [AAAA] }}"
+        ))
+        .unwrap();
+        let verifier = WhitelistVerifier::from_config(&config).unwrap();
+        let verify = |name: &str| {
+            let name = ServerName::try_from(name.to_string()).unwrap();
+            verifier
+                .verify_server_cert(&leaf_der, &[], &name, &[], UnixTime::now())
+                .is_ok()
+        };
+
+        // alt names are accepted with extra roots of the host
+        assert!(verify("api.telegra.ph"));
+        assert!(!verify("other.example"));
+        // extra roots are not trusted by other hosts
+        assert!(!verify("telegram.org"));
+        // certificate must match one of the pins
+        assert!(verify("pinned.example"));
+        assert!(!verify("wrong-pin.example"));
     }
 }
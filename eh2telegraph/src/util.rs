@@ -2,7 +2,10 @@ use bytes::Bytes;
 use regex::Regex;
 use reqwest::Response;
 
-use crate::http_client::HttpRequestBuilder;
+use crate::{
+    http_client::HttpRequestBuilder,
+    rate_limit::{self, RequestKind},
+};
 
 #[inline]
 pub fn match_first_group<'a>(regexp: &'a Regex, content: &'a str) -> Option<&'a str> {
@@ -15,9 +18,11 @@ pub fn match_first_group<'a>(regexp: &'a Regex, content: &'a str) -> Option<&'a
 
 #[inline]
 pub async fn get_bytes<C: HttpRequestBuilder>(client: &C, link: &str) -> reqwest::Result<Bytes> {
+    // hold the permit until the body is read
+    let (_permit, builder) =
+        rate_limit::limit(RequestKind::Image, client.get_builder(link)).await?;
     client
-        .get_builder(link)
-        .send()
+        .send_raw(builder)
         .await
         .and_then(Response::error_for_status)?
         .bytes()
@@ -26,9 +31,9 @@ pub async fn get_bytes<C: HttpRequestBuilder>(client: &C, link: &str) -> reqwest
 
 #[inline]
 pub async fn get_string<C: HttpRequestBuilder>(client: &C, link: &str) -> reqwest::Result<String> {
+    let (_permit, builder) = rate_limit::limit(RequestKind::Page, client.get_builder(link)).await?;
     client
-        .get_builder(link)
-        .send()
+        .send_raw(builder)
         .await
         .and_then(Response::error_for_status)?
         .text()
@@ -0,0 +1,65 @@
+use std::time::Duration;
+
+use clap::Parser;
+use eh2telegraph::{config, storage::local::LocalStorage};
+use tracing::level_filters::LevelFilter;
+use tracing_subscriber::{fmt, prelude::*, EnvFilter};
+
+mod server;
+
+const CONFIG_KEY: &str = "kv_proxy";
+const PURGE_INTERVAL: Duration = Duration::from_secs(3600);
+
+#[derive(Debug, serde::Deserialize)]
+pub struct KVProxyConfig {
+    pub listen: String,
+    pub token: String,
+}
+
+#[derive(Parser, Debug)]
+#[clap(
+    author,
+    version,
+    about,
+    long_about = "Self-hosted server speaking the cloudflare-kv-proxy protocol"
+)]
+struct Args {
+    #[clap(short, long, help = "Config file path")]
+    config: Option<String>,
+}
+
+#[tokio::main]
+async fn main() -> anyhow::Result<()> {
+    let args = Args::parse();
+    tracing_subscriber::registry()
+        .with(fmt::layer())
+        .with(
+            EnvFilter::builder()
+                .with_default_directive(LevelFilter::INFO.into())
+                .from_env_lossy(),
+        )
+        .init();
+
+    config::init(args.config);
+    let kv_config: KVProxyConfig = config::parse(CONFIG_KEY)?
+        .ok_or_else(|| anyhow::anyhow!("kv proxy config(key: {CONFIG_KEY}) not found"))?;
+    let storage = LocalStorage::new_from_config()?;
+
+    // expired values are not returned, but we still need to clean them.
+    let purge_storage = storage.clone();
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(PURGE_INTERVAL);
+        loop {
+            interval.tick().await;
+            match purge_storage.purge_expired().await {
+                Ok(n) => tracing::info!("[kv] purged {n} expired keys"),
+                Err(e) => tracing::error!("[kv] purge expired keys failed: {e:?}"),
+            }
+        }
+    });
+
+    let listener = tokio::net::TcpListener::bind(&kv_config.listen).await?;
+    tracing::info!("kv proxy is listening on {}", kv_config.listen);
+    axum::serve(listener, server::router(storage, &kv_config.token)).await?;
+    Ok(())
+}
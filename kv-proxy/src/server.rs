@@ -0,0 +1,175 @@
+/// KV server compatible with cloudflare-kv-proxy worker.
+/// Protocol:
+/// 1. `Authorization` header must equal to the token, or 401 is returned.
+/// 2. Key is the percent-decoded request path without the leading `/`, empty key returns 400.
+/// 3. GET returns `{"result": value}` or 404, PUT stores the body(with optional
+///    `ttl` header in seconds) and DELETE removes the key. Both return `{"result": "null"}`.
+use std::sync::Arc;
+
+use axum::{
+    body::Body,
+    extract::State,
+    http::{header, HeaderMap, Method, StatusCode, Uri},
+    response::Response,
+    Router,
+};
+use eh2telegraph::storage::local::LocalStorage;
+use percent_encoding::percent_decode_str;
+use subtle::ConstantTimeEq;
+
+const SERVER_NAME: &str = "kv-proxy";
+
+#[derive(Clone)]
+struct ServerState {
+    storage: LocalStorage,
+    token: Arc<str>,
+}
+
+pub fn router(storage: LocalStorage, token: &str) -> Router {
+    Router::new().fallback(handle).with_state(ServerState {
+        storage,
+        token: token.into(),
+    })
+}
+
+async fn handle(
+    State(state): State<ServerState>,
+    method: Method,
+    uri: Uri,
+    headers: HeaderMap,
+    body: String,
+) -> Response {
+    // validate token, compared in constant time
+    let authorized = headers
+        .get(header::AUTHORIZATION)
+        .map(|v| bool::from(v.as_bytes().ct_eq(state.token.as_bytes())))
+        .unwrap_or_default();
+    if !authorized {
+        return response(StatusCode::UNAUTHORIZED, None);
+    }
+
+    // the decoded path is used as key, so it matches records written by LocalStorage directly.
+    let key = match percent_decode_str(uri.path()).decode_utf8() {
+        Ok(key) => key,
+        Err(_) => return response(StatusCode::BAD_REQUEST, None),
+    };
+    let key = key.trim().trim_start_matches('/');
+    if key.is_empty() {
+        return response(StatusCode::BAD_REQUEST, None);
+    }
+
+    let r = match method {
+        Method::GET => match state.storage.get_raw(key).await {
+            Ok(Some(v)) => {
+                let value = String::from_utf8_lossy(&v);
+                return response(StatusCode::OK, Some(&value));
+            }
+            Ok(None) => return response(StatusCode::NOT_FOUND, None),
+            Err(e) => Err(e),
+        },
+        Method::PUT => {
+            let ttl = headers
+                .get("ttl")
+                .and_then(|v| v.to_str().ok())
+                .and_then(|v| v.parse::<usize>().ok())
+                .filter(|ttl| *ttl > 0);
+            state
+                .storage
+                .set_raw(key.to_string(), body.into_bytes(), ttl)
+                .await
+        }
+        Method::DELETE => state.storage.delete_raw(key).await,
+        _ => return response(StatusCode::METHOD_NOT_ALLOWED, None),
+    };
+    match r {
+        Ok(_) => response(StatusCode::OK, Some("null")),
+        Err(e) => {
+            tracing::error!("[kv] {method} {key} failed: {e:?}");
+            response(StatusCode::INTERNAL_SERVER_ERROR, None)
+        }
+    }
+}
+
+fn response(status: StatusCode, result: Option<&str>) -> Response {
+    let body = match result {
+        Some(r) => Body::from(serde_json::json!({ "result": r }).to_string()),
+        None => Body::empty(),
+    };
+    Response::builder()
+        .status(status)
+        .header(header::CONTENT_TYPE, "application/json")
+        .header(header::SERVER, SERVER_NAME)
+        .body(body)
+        .expect("unable to build response")
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use eh2telegraph::storage::{cloudflare_kv::CFStorage, local::LocalStorage, KVStorage};
+
+    const TOKEN: &str = "test-token";
+
+    use tempfile::TempDir;
+
+    /// The database is removed when the returned dir is dropped.
+    async fn serve() -> (String, LocalStorage, TempDir) {
+        let dir = tempfile::tempdir().unwrap();
+        let storage = LocalStorage::new(dir.path().join("kv-proxy.redb")).unwrap();
+        let local = storage.clone();
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            axum::serve(listener, super::router(storage, TOKEN))
+                .await
+                .unwrap();
+        });
+        (format!("http://{addr}/"), local, dir)
+    }
+
+    #[tokio::test]
+    async fn cf_storage() {
+        let (endpoint, local, _dir) = serve().await;
+        let storage = CFStorage::new(&endpoint, TOKEN, 16, Duration::from_secs(60)).unwrap();
+
+        let key = "e-hentai|/g/2122174/fd2525031e";
+        assert_eq!(KVStorage::<String>::get(&storage, key).await.unwrap(), None);
+        storage
+            .set(key.to_string(), "https://telegra.ph/test".to_string(), None)
+            .await
+            .unwrap();
+        let v: Option<String> = storage.get(key).await.unwrap();
+        assert_eq!(v.as_deref(), Some("https://telegra.ph/test"));
+        // the key is stored decoded
+        let v: Option<String> = local.get(key).await.unwrap();
+        assert_eq!(v.as_deref(), Some("https://telegra.ph/test"));
+        KVStorage::<String>::delete(&storage, key).await.unwrap();
+
+        // read through a new client to skip its local cache
+        let storage = CFStorage::new(&endpoint, TOKEN, 16, Duration::from_secs(60)).unwrap();
+        assert_eq!(KVStorage::<String>::get(&storage, key).await.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn unauthorized() {
+        let (endpoint, _, _dir) = serve().await;
+        let client = reqwest::Client::new();
+        let status = client
+            .get(format!("{endpoint}key"))
+            .header("Authorization", "wrong")
+            .send()
+            .await
+            .unwrap()
+            .status();
+        assert_eq!(status, 401);
+        let status = client
+            .get(&endpoint)
+            .header("Authorization", TOKEN)
+            .send()
+            .await
+            .unwrap()
+            .status();
+        assert_eq!(status, 400);
+    }
+}
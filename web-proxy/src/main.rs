@@ -0,0 +1,48 @@
+use clap::Parser;
+use eh2telegraph::{config, http_client::GhostClient};
+use tracing::level_filters::LevelFilter;
+use tracing_subscriber::{fmt, prelude::*, EnvFilter};
+
+use server::WebProxyConfig;
+
+mod server;
+
+const CONFIG_KEY: &str = "web_proxy";
+
+#[derive(Parser, Debug)]
+#[clap(
+    author,
+    version,
+    about,
+    long_about = "Self-hosted replacement of worker/web_proxy.js"
+)]
+struct Args {
+    #[clap(short, long, help = "Config file path")]
+    config: Option<String>,
+}
+
+#[tokio::main]
+async fn main() -> anyhow::Result<()> {
+    let args = Args::parse();
+    tracing_subscriber::registry()
+        .with(fmt::layer())
+        .with(
+            EnvFilter::builder()
+                .with_default_directive(LevelFilter::INFO.into())
+                .from_env_lossy(),
+        )
+        .init();
+
+    config::init(args.config);
+    let proxy_config: WebProxyConfig = config::parse(CONFIG_KEY)?
+        .ok_or_else(|| anyhow::anyhow!("web proxy config(key: {CONFIG_KEY}) not found"))?;
+    // outgoing requests use random ip of http.ipv6_prefix if it is set.
+    let client = GhostClient::builder().build_from_config()?;
+
+    let router = server::router(&proxy_config, client)?;
+
+    let listener = tokio::net::TcpListener::bind(&proxy_config.listen).await?;
+    tracing::info!("web proxy is listening on {}", proxy_config.listen);
+    axum::serve(listener, router).await?;
+    Ok(())
+}
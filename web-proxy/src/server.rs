@@ -0,0 +1,296 @@
+/// Web proxy compatible with worker/web_proxy.js.
+/// Protocol:
+/// 1. `X-Authorization` header must be one of the configured keys, or 401 is returned.
+/// 2. `X-Forwarded-For` header is the target url, 400 is returned if it is missing
+///    or invalid, and 403 is returned if the host is not allowed.
+/// 3. Other headers, method and body are forwarded to the target url, and the
+///    response is streamed back.
+use std::{
+    collections::{HashMap, HashSet},
+    sync::Arc,
+};
+
+use axum::{
+    body::Body,
+    extract::{Request, State},
+    http::{header, HeaderMap, HeaderName, Method, StatusCode},
+    response::Response,
+    Router,
+};
+use eh2telegraph::{
+    http_client::{GhostClient, HttpRequestBuilder},
+    rate_limit::TokenBucket,
+};
+use serde::Deserialize;
+use sync_wrapper::SyncStream;
+
+const SERVER_NAME: &str = "web-proxy";
+const KEY_HEADER: &str = "x-authorization";
+const URL_HEADER: &str = "x-forwarded-for";
+
+// Headers that should not be forwarded in either direction.
+const SCRUB_HEADERS: [&str; 17] = [
+    KEY_HEADER,
+    URL_HEADER,
+    "cf-connecting-ip",
+    "cf-worker",
+    "cf-ew-via",
+    "x-real-ip",
+    "forwarded",
+    "host",
+    "connection",
+    "keep-alive",
+    "proxy-connection",
+    "proxy-authorization",
+    "proxy-authenticate",
+    "te",
+    "trailer",
+    "transfer-encoding",
+    "upgrade",
+];
+
+#[derive(Debug, Deserialize)]
+pub struct KeyConfig {
+    pub key: String,
+    /// Requests per second, no limit if not set.
+    pub rate_limit: Option<f64>,
+    /// Max burst requests, default to 1 second of rate_limit.
+    pub burst: Option<usize>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct WebProxyConfig {
+    pub listen: String,
+    pub keys: Vec<KeyConfig>,
+    /// Allowed target hosts, all hosts are allowed if it is empty.
+    #[serde(default)]
+    pub allowed_hosts: Vec<String>,
+}
+
+struct ProxyState {
+    client: GhostClient,
+    keys: HashMap<String, Option<TokenBucket>>,
+    allowed_hosts: HashSet<String>,
+}
+
+pub fn router(config: &WebProxyConfig, client: GhostClient) -> anyhow::Result<Router> {
+    let keys = config
+        .keys
+        .iter()
+        .map(|k| {
+            let bucket = match k.rate_limit {
+                Some(rate) if !rate.is_finite() || rate <= 0.0 => {
+                    anyhow::bail!("invalid rate_limit {rate} for key, it must be positive")
+                }
+                Some(rate) => Some(TokenBucket::new(
+                    rate,
+                    k.burst.unwrap_or_else(|| rate.ceil() as usize),
+                )),
+                None => None,
+            };
+            Ok((k.key.clone(), bucket))
+        })
+        .collect::<anyhow::Result<_>>()?;
+    if config.allowed_hosts.is_empty() {
+        tracing::warn!("[proxy] allowed_hosts is empty, all hosts can be proxied");
+    }
+    Ok(Router::new()
+        .fallback(handle)
+        .with_state(Arc::new(ProxyState {
+            client,
+            keys,
+            allowed_hosts: config.allowed_hosts.iter().cloned().collect(),
+        })))
+}
+
+async fn handle(State(state): State<Arc<ProxyState>>, request: Request) -> Response {
+    let (parts, body) = request.into_parts();
+
+    // validate request key
+    let bucket = match parts
+        .headers
+        .get(KEY_HEADER)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|k| state.keys.get(k))
+    {
+        Some(b) => b,
+        None => return empty_response(StatusCode::UNAUTHORIZED),
+    };
+    if let Some(Err(wait)) = bucket.as_ref().map(TokenBucket::try_acquire) {
+        let mut resp = empty_response(StatusCode::TOO_MANY_REQUESTS);
+        if let Ok(v) = wait.as_secs().saturating_add(1).to_string().parse() {
+            resp.headers_mut().insert(header::RETRY_AFTER, v);
+        }
+        return resp;
+    }
+
+    // read original url
+    let url = match parts
+        .headers
+        .get(URL_HEADER)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| reqwest::Url::parse(v).ok())
+    {
+        Some(u) if matches!(u.scheme(), "http" | "https") => u,
+        _ => return empty_response(StatusCode::BAD_REQUEST),
+    };
+    let allowed = state.allowed_hosts.is_empty()
+        || url
+            .host_str()
+            .map(|h| state.allowed_hosts.contains(h))
+            .unwrap_or_default();
+    if !allowed {
+        return empty_response(StatusCode::FORBIDDEN);
+    }
+
+    // construct new request
+    // clone client to change ip, errors are reported to rotate the ip
+    let client = state.client.clone();
+    let mut builder = client
+        .request(parts.method.clone(), url.as_str())
+        .headers(scrub(&parts.headers));
+    if parts.method != Method::GET && parts.method != Method::HEAD {
+        // reqwest requires the body stream to be Sync.
+        let stream = SyncStream::new(body.into_data_stream());
+        builder = builder.body(reqwest::Body::wrap_stream(stream));
+    }
+
+    // send request
+    let upstream = match client.send_raw(builder).await {
+        Ok(r) => r,
+        Err(e) => {
+            tracing::error!("[proxy] {} {url} failed: {e}", parts.method);
+            return empty_response(StatusCode::BAD_GATEWAY);
+        }
+    };
+    let mut resp = Response::builder().status(upstream.status());
+    if let Some(headers) = resp.headers_mut() {
+        headers.extend(scrub(upstream.headers()));
+        headers.insert(header::SERVER, SERVER_NAME.parse().unwrap());
+    }
+    resp.body(Body::from_stream(upstream.bytes_stream()))
+        .expect("unable to build response")
+}
+
+fn scrub(headers: &HeaderMap) -> HeaderMap {
+    let mut headers = headers.clone();
+    for name in SCRUB_HEADERS {
+        headers.remove(HeaderName::from_static(name));
+    }
+    headers
+}
+
+fn empty_response(status: StatusCode) -> Response {
+    Response::builder()
+        .status(status)
+        .header(header::SERVER, SERVER_NAME)
+        .body(Body::empty())
+        .expect("unable to build response")
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::{http::HeaderMap, routing::post, Router};
+    use eh2telegraph::http_proxy::ProxiedClient;
+
+    use super::{KeyConfig, WebProxyConfig};
+
+    const KEY: &str = "test-key";
+
+    async fn serve(router: Router) -> String {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, router).await.unwrap() });
+        format!("http://{addr}")
+    }
+
+    async fn echo(headers: HeaderMap, body: String) -> String {
+        let mut names = headers.keys().map(|k| k.as_str()).collect::<Vec<_>>();
+        names.sort_unstable();
+        format!("{body}|{}", names.join(","))
+    }
+
+    fn config(rate_limit: Option<f64>) -> WebProxyConfig {
+        WebProxyConfig {
+            listen: String::new(),
+            keys: vec![KeyConfig {
+                key: KEY.to_string(),
+                rate_limit,
+                burst: Some(1),
+            }],
+            allowed_hosts: vec!["127.0.0.1".to_string()],
+        }
+    }
+
+    async fn setup(rate_limit: Option<f64>) -> (String, String) {
+        let upstream = serve(Router::new().route("/echo", post(echo))).await;
+        let router = super::router(&config(rate_limit), Default::default()).unwrap();
+        let proxy = serve(router).await;
+        (upstream, proxy)
+    }
+
+    #[tokio::test]
+    async fn forward() {
+        let (upstream, proxy) = setup(None).await;
+        let client = ProxiedClient::new(&proxy, KEY);
+        let resp = client
+            .post(&format!("{upstream}/echo"))
+            .header("X-Custom", "1")
+            .body("hello")
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), 200);
+        let text = resp.text().await.unwrap();
+        let (body, headers) = text.split_once('|').unwrap();
+        assert_eq!(body, "hello");
+        assert!(headers.contains("x-custom"));
+        assert!(!headers.contains("x-authorization"));
+        assert!(!headers.contains("x-forwarded-for"));
+
+        // host not in allow list
+        let resp = client.get("http://localhost/").send().await.unwrap();
+        assert_eq!(resp.status(), 403);
+
+        // wrong key
+        let client = ProxiedClient::new(&proxy, "wrong");
+        let resp = client.get(&upstream).send().await.unwrap();
+        assert_eq!(resp.status(), 401);
+    }
+
+    #[tokio::test]
+    async fn rate_limit() {
+        let (upstream, proxy) = setup(Some(0.01)).await;
+        let client = ProxiedClient::new(&proxy, KEY);
+        let url = format!("{upstream}/echo");
+        let resp = client.post(&url).send().await.unwrap();
+        assert_eq!(resp.status(), 200);
+        let resp = client.post(&url).send().await.unwrap();
+        assert_eq!(resp.status(), 429);
+        assert_eq!(resp.headers()["retry-after"], "100");
+    }
+
+    #[test]
+    fn invalid_rate_limit() {
+        for rate in [0.0, -1.0, f64::NAN, f64::INFINITY] {
+            assert!(super::router(&config(Some(rate)), Default::default()).is_err());
+        }
+    }
+
+    #[tokio::test]
+    async fn client_failover() {
+        let (upstream, proxy) = setup(None).await;
+        // nothing is listening on the first endpoint
+        let dead = {
+            let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+            format!("http://{}", listener.local_addr().unwrap())
+        };
+        let client = ProxiedClient::new_multi(vec![(&dead, KEY, 1), (&proxy, KEY, 1)], false);
+        for _ in 0..4 {
+            let builder = client.get(&format!("{upstream}/echo"));
+            let resp = client.send(builder).await.unwrap();
+            // proxy is reached and the upstream only accepts POST
+            assert_eq!(resp.status(), 405);
+        }
+    }
+}